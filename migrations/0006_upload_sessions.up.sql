@@ -0,0 +1,15 @@
+-- Add up migration script here
+CREATE TABLE upload_sessions (
+    id              UUID NOT NULL,
+    user_id         UUID NOT NULL REFERENCES users(id),
+    object_id       UUID NOT NULL,
+    multipart_id    TEXT NOT NULL,
+    upload_length   BIGINT NOT NULL,
+    upload_offset   BIGINT NOT NULL DEFAULT 0,
+    parts           JSONB NOT NULL DEFAULT '[]',
+    content_type    VARCHAR(64) NOT NULL,
+    file_name       VARCHAR(128) NOT NULL,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (id)
+);
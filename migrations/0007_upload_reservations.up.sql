@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE upload_reservations (
+    id              UUID NOT NULL,
+    user_id         UUID NOT NULL REFERENCES users(id),
+    content_type    VARCHAR(64) NOT NULL,
+    content_size    BIGINT NOT NULL,
+    file_name       VARCHAR(128) NOT NULL,
+    sha256_hash     CHAR(64) NOT NULL,
+    multipart_id    TEXT,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (id)
+);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+DROP COLUMN blob_hash;
+
+DROP TABLE blobs;
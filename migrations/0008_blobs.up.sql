@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE blobs (
+    sha256_hash     CHAR(64) NOT NULL,
+    content_size    BIGINT NOT NULL,
+    ref_count       BIGINT NOT NULL DEFAULT 0,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (sha256_hash)
+);
+
+-- objects uploaded before this migration keep their content in the vault
+ALTER TABLE cdn_objects
+ADD COLUMN blob_hash CHAR(64) REFERENCES blobs(sha256_hash);
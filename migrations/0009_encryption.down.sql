@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE vault_keys;
+
+ALTER TABLE blobs
+DROP COLUMN master_key_id,
+DROP COLUMN wrapped_key;
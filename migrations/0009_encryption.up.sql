@@ -0,0 +1,14 @@
+-- Add up migration script here
+-- blobs stored before encryption was enabled have no key and stay plaintext
+ALTER TABLE blobs
+ADD COLUMN master_key_id TEXT,
+ADD COLUMN wrapped_key BYTEA;
+
+CREATE TABLE vault_keys (
+    user_id         UUID NOT NULL REFERENCES users(id),
+    master_key_id   TEXT NOT NULL,
+    wrapped_key     BYTEA NOT NULL,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (user_id)
+);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE deletion_outbox;
+
+ALTER TABLE cdn_objects
+DROP COLUMN deleted_at;
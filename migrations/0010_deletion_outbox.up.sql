@@ -0,0 +1,22 @@
+-- Add up migration script here
+-- objects stay around, hidden, until everything they own has been deleted
+ALTER TABLE cdn_objects
+ADD COLUMN deleted_at TIMESTAMP;
+
+CREATE TABLE deletion_outbox (
+    id              BIGSERIAL NOT NULL,
+    -- 'blob', 'object', 'thumbnail' or 'search_document'
+    kind            VARCHAR(16) NOT NULL,
+    object_id       UUID,
+    -- storage key, blob hash or document id depending on the kind
+    target          TEXT NOT NULL,
+    attempts        INT NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    last_error      TEXT,
+    created_at      TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (id)
+);
+
+CREATE INDEX deletion_outbox_next_attempt_at_idx ON deletion_outbox (next_attempt_at);
+CREATE INDEX deletion_outbox_object_id_idx ON deletion_outbox (object_id);
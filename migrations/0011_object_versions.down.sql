@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+DROP COLUMN current_version;
+
+DROP TABLE object_versions;
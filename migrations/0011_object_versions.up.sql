@@ -0,0 +1,22 @@
+-- Add up migration script here
+-- every version holds a reference on its blob, the object row mirrors the
+-- current version so serving it doesn't need a join
+CREATE TABLE object_versions (
+    object_id       UUID NOT NULL REFERENCES cdn_objects(id) ON DELETE CASCADE,
+    version         INT NOT NULL,
+    content_type    VARCHAR(64) NOT NULL,
+    content_size    BIGINT NOT NULL,
+    file_name       VARCHAR(128) NOT NULL,
+    sha256_hash     CHAR(64) NOT NULL,
+    -- NULL for content uploaded before deduplication, kept in the vault
+    blob_hash       CHAR(64) REFERENCES blobs(sha256_hash),
+    uploaded_at     TIMESTAMP NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (object_id, version)
+);
+
+ALTER TABLE cdn_objects
+ADD COLUMN current_version INT NOT NULL DEFAULT 1;
+
+INSERT INTO object_versions (object_id, version, content_type, content_size, file_name, sha256_hash, blob_hash, uploaded_at)
+SELECT id, 1, content_type, content_size, file_name, sha256_hash, blob_hash, uploaded_at FROM cdn_objects;
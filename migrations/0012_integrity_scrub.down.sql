@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX cdn_objects_verified_at_idx;
+
+ALTER TABLE cdn_objects
+DROP COLUMN verified_at,
+DROP COLUMN integrity_error;
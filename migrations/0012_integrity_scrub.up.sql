@@ -0,0 +1,7 @@
+-- Add up migration script here
+ALTER TABLE cdn_objects
+ADD COLUMN verified_at TIMESTAMP,
+-- why the last scrub failed, NULL when the content matched its hash
+ADD COLUMN integrity_error TEXT;
+
+CREATE INDEX cdn_objects_verified_at_idx ON cdn_objects (verified_at NULLS FIRST);
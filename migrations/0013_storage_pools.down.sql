@@ -0,0 +1,21 @@
+-- Add down migration script here
+DROP INDEX object_versions_storage_pool_idx;
+DROP INDEX blobs_storage_pool_idx;
+
+ALTER TABLE deletion_outbox
+DROP COLUMN storage_pool;
+
+ALTER TABLE upload_reservations
+DROP COLUMN storage_pool;
+
+ALTER TABLE upload_sessions
+DROP COLUMN storage_pool;
+
+ALTER TABLE object_versions
+DROP COLUMN storage_pool;
+
+ALTER TABLE cdn_objects
+DROP COLUMN storage_pool;
+
+ALTER TABLE blobs
+DROP COLUMN storage_pool;
@@ -0,0 +1,27 @@
+-- Add up migration script here
+-- the storage pool holding the content, everything stored so far is in the default one.
+-- objects and versions mirror the pool of their blob, a blob only lives in one pool
+ALTER TABLE blobs
+ADD COLUMN storage_pool VARCHAR(32) NOT NULL DEFAULT 'default';
+
+ALTER TABLE cdn_objects
+ADD COLUMN storage_pool VARCHAR(32) NOT NULL DEFAULT 'default';
+
+ALTER TABLE object_versions
+ADD COLUMN storage_pool VARCHAR(32) NOT NULL DEFAULT 'default';
+
+-- uploads are staged in the pool that was the default when they started
+ALTER TABLE upload_sessions
+ADD COLUMN storage_pool VARCHAR(32) NOT NULL DEFAULT 'default';
+
+ALTER TABLE upload_reservations
+ADD COLUMN storage_pool VARCHAR(32) NOT NULL DEFAULT 'default';
+
+-- NULL for entries that aren't about storage
+ALTER TABLE deletion_outbox
+ADD COLUMN storage_pool VARCHAR(32);
+
+UPDATE deletion_outbox SET storage_pool = 'default' WHERE kind IN ('object', 'thumbnail');
+
+CREATE INDEX blobs_storage_pool_idx ON blobs (storage_pool);
+CREATE INDEX object_versions_storage_pool_idx ON object_versions (storage_pool);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+-- clears COF_THUMBNAILABLE and COF_THUMBNAILED
+UPDATE cdn_objects SET flags = flags & ~12;
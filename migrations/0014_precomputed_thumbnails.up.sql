@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- images uploaded before thumbnails were rendered ahead of time, 4 is COF_THUMBNAILABLE
+UPDATE cdn_objects SET flags = flags | 4
+WHERE content_type IN ('image/jpeg', 'image/jpg', 'image/png', 'image/webp', 'image/gif')
+    AND deleted_at IS NULL;
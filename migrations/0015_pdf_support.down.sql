@@ -0,0 +1,4 @@
+-- Add down migration script here
+-- clears every processing flag of pdfs
+UPDATE cdn_objects SET flags = flags & ~15
+WHERE content_type = 'application/pdf';
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- pdfs uploaded before they were indexed, 2 is COF_SEARCHABLE and 4 is COF_THUMBNAILABLE
+UPDATE cdn_objects SET flags = flags | 6
+WHERE content_type = 'application/pdf' AND deleted_at IS NULL;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+-- clears COF_INDEXED and COF_SEARCHABLE of everything but images and pdfs
+UPDATE cdn_objects SET flags = flags & ~3
+WHERE content_type NOT IN ('image/jpeg', 'image/jpg', 'image/png', 'image/webp', 'application/pdf');
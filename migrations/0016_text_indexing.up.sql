@@ -0,0 +1,38 @@
+-- Add up migration script here
+-- text uploaded before it was indexed, 2 is COF_SEARCHABLE. Mirrors `text::is_text`:
+-- the type is compared without parameters and case, file names by what follows
+-- their last dot, or the whole name when there is none (Dockerfile, Makefile)
+UPDATE cdn_objects SET flags = flags | 2
+FROM (
+    SELECT
+        id,
+        lower(trim(split_part(content_type, ';', 1))) AS essence,
+        regexp_replace(lower(file_name), '^.*\.', '') AS extension
+    FROM cdn_objects
+    WHERE deleted_at IS NULL
+) AS t
+WHERE cdn_objects.id = t.id
+    AND (
+        t.essence LIKE 'text/%'
+        OR t.essence LIKE '%+json'
+        OR t.essence LIKE '%+xml'
+        OR t.essence IN (
+            'application/json', 'application/x-ndjson', 'application/xml', 'application/yaml',
+            'application/x-yaml', 'application/toml', 'application/javascript',
+            'application/x-javascript', 'application/typescript', 'application/x-sh',
+            'application/x-shellscript', 'application/sql', 'application/graphql',
+            'application/x-httpd-php', 'application/x-python', 'application/x-subrip'
+        )
+        OR (
+            t.essence IN ('', 'application/octet-stream', 'binary/octet-stream')
+            AND t.extension IN (
+                'txt', 'log', 'out', 'md', 'markdown', 'rst', 'adoc', 'csv', 'tsv', 'json',
+                'jsonl', 'ndjson', 'yaml', 'yml', 'toml', 'ini', 'cfg', 'conf', 'env',
+                'properties', 'xml', 'html', 'htm', 'css', 'scss', 'js', 'mjs', 'cjs', 'ts',
+                'tsx', 'jsx', 'vue', 'svelte', 'rs', 'go', 'py', 'rb', 'php', 'java', 'kt',
+                'kts', 'scala', 'swift', 'c', 'h', 'cc', 'cpp', 'hpp', 'cs', 'fs', 'dart',
+                'zig', 'ex', 'exs', 'erl', 'hs', 'lua', 'pl', 'r', 'sh', 'bash', 'zsh', 'fish',
+                'ps1', 'bat', 'sql', 'graphql', 'proto', 'tf', 'nix', 'dockerfile', 'makefile'
+            )
+        )
+    );
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX cdn_objects_slug_key;
+
+-- slugs longer than 8 characters are cut and may collide again
+ALTER TABLE cdn_objects
+ALTER COLUMN slug TYPE VARCHAR(8) USING left(slug, 8);
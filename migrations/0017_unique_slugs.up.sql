@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- vanity slugs are longer than the 8 hex digits slugs used to have
+ALTER TABLE cdn_objects
+ALTER COLUMN slug TYPE VARCHAR(64);
+
+-- objects sharing a slug with an older one get a new slug, the link was
+-- ambiguous before and the older object is the one it was handed out for
+UPDATE cdn_objects SET slug = left(replace(id::text, '-', ''), 16)
+WHERE id IN (
+    SELECT id FROM (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY slug ORDER BY uploaded_at, id) AS n
+        FROM cdn_objects
+        WHERE slug IS NOT NULL
+    ) AS numbered
+    WHERE n > 1
+);
+
+CREATE UNIQUE INDEX cdn_objects_slug_key ON cdn_objects (slug);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE link_audit_log;
+
+DROP TABLE retired_slugs;
@@ -0,0 +1,23 @@
+-- Add up migration script here
+-- slugs of links that were taken down or rotated, they answer 410 and are never reused
+CREATE TABLE
+    retired_slugs (
+        slug VARCHAR(64) NOT NULL,
+        object_id UUID NOT NULL,
+        retired_at TIMESTAMP NOT NULL DEFAULT NOW (),
+        PRIMARY KEY (slug)
+    );
+
+CREATE TABLE
+    link_audit_log (
+        id BIGSERIAL NOT NULL,
+        user_id UUID NOT NULL,
+        object_id UUID NOT NULL,
+        action VARCHAR(16) NOT NULL,
+        old_slug VARCHAR(64),
+        new_slug VARCHAR(64),
+        created_at TIMESTAMP NOT NULL DEFAULT NOW (),
+        PRIMARY KEY (id)
+    );
+
+CREATE INDEX link_audit_log_object_id_idx ON link_audit_log (object_id);
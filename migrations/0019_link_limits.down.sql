@@ -0,0 +1,18 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+DROP COLUMN delete_when_spent;
+
+ALTER TABLE cdn_objects
+DROP COLUMN link_spent_at;
+
+ALTER TABLE cdn_objects
+DROP COLUMN link_remaining_downloads;
+
+ALTER TABLE cdn_objects
+DROP COLUMN link_downloads;
+
+ALTER TABLE cdn_objects
+DROP COLUMN link_max_downloads;
+
+ALTER TABLE cdn_objects
+DROP COLUMN link_expires_at;
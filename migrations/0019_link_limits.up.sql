@@ -0,0 +1,20 @@
+-- Add up migration script here
+ALTER TABLE cdn_objects
+ADD COLUMN link_expires_at TIMESTAMP;
+
+ALTER TABLE cdn_objects
+ADD COLUMN link_max_downloads INT;
+
+ALTER TABLE cdn_objects
+ADD COLUMN link_downloads INT NOT NULL DEFAULT 0;
+
+ALTER TABLE cdn_objects
+ADD COLUMN link_remaining_downloads INT GENERATED ALWAYS AS (link_max_downloads - link_downloads) STORED;
+
+-- when the last download was handed out
+ALTER TABLE cdn_objects
+ADD COLUMN link_spent_at TIMESTAMP;
+
+-- the object is deleted once its link expired or ran out of downloads
+ALTER TABLE cdn_objects
+ADD COLUMN delete_when_spent BOOLEAN NOT NULL DEFAULT false;
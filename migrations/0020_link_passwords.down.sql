@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+DROP COLUMN link_is_protected;
+
+ALTER TABLE cdn_objects
+DROP COLUMN link_password_hash;
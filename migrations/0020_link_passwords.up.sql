@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE cdn_objects
+ADD COLUMN link_password_hash TEXT;
+
+ALTER TABLE cdn_objects
+ADD COLUMN link_is_protected BOOLEAN GENERATED ALWAYS AS (link_password_hash IS NOT NULL) STORED;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+-- retired slugs can't be told apart from the ones retired since, so they stay
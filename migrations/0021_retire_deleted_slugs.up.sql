@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- links of objects deleted before slugs were retired on deletion
+INSERT INTO
+    retired_slugs (slug, object_id)
+SELECT
+    slug,
+    id
+FROM
+    cdn_objects
+WHERE
+    deleted_at IS NOT NULL
+    AND slug IS NOT NULL
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+DROP COLUMN content_modified_at;
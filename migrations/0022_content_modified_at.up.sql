@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- bumped whenever the object serves different content, uploaded_at goes back on a restore
+ALTER TABLE cdn_objects
+ADD COLUMN content_modified_at TIMESTAMP NOT NULL DEFAULT NOW ();
+
+UPDATE cdn_objects
+SET
+    content_modified_at = uploaded_at;
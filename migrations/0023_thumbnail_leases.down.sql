@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+DROP COLUMN thumbnail_leased_until;
+
+ALTER TABLE cdn_objects
+DROP COLUMN thumbnail_attempts;
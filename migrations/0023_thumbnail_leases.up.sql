@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- the thumbnail worker leases objects, failed ones come up again once the lease ran out
+ALTER TABLE cdn_objects
+ADD COLUMN thumbnail_attempts INT NOT NULL DEFAULT 0;
+
+ALTER TABLE cdn_objects
+ADD COLUMN thumbnail_leased_until TIMESTAMP;
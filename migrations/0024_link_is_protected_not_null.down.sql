@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE cdn_objects
+ALTER COLUMN link_is_protected DROP NOT NULL;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- the column is always computed, declaring it so makes it a plain bool for query_as!
+ALTER TABLE cdn_objects
+ALTER COLUMN link_is_protected SET NOT NULL;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE blobs
+DROP COLUMN claimed_until;
+
+ALTER TABLE blobs
+DROP COLUMN claim_id;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- a blob being written is claimed by one upload, the claim runs out if the upload dies
+ALTER TABLE blobs
+ADD COLUMN claim_id UUID;
+
+ALTER TABLE blobs
+ADD COLUMN claimed_until TIMESTAMP;
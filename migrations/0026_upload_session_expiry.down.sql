@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE upload_sessions
+DROP COLUMN expires_at;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- sessions nobody appended to for a while are dropped by the uploads worker
+ALTER TABLE upload_sessions
+ADD COLUMN expires_at TIMESTAMP;
+
+UPDATE upload_sessions SET expires_at = created_at + INTERVAL '1 day';
+
+ALTER TABLE upload_sessions
+ALTER COLUMN expires_at SET NOT NULL;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE upload_sessions
+DROP COLUMN sha256_hash;
+
+ALTER TABLE upload_sessions
+DROP COLUMN completing;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- a final PATCH that failed after the content was assembled picks up from there
+ALTER TABLE upload_sessions
+ADD COLUMN completing BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE upload_sessions
+ADD COLUMN sha256_hash CHAR(64);
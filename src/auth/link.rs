@@ -0,0 +1,255 @@
+//! Passwords of public links and the rate limit on guessing them
+
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use http::HeaderMap;
+use tokio::sync::Semaphore;
+
+/// Failed unlocks allowed per client and slug within `FAILURE_WINDOW`
+const MAX_FAILURES: u32 = 5;
+
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Clients tracked at once, expired and then the oldest entries are dropped
+/// beyond this
+const MAX_TRACKED: usize = 10_000;
+
+/// Passwords verified at once, each verification takes a core for a while
+const MAX_VERIFICATIONS: usize = 4;
+
+/// Hashes a link password with argon2, slow on purpose so run it off the runtime
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|error| anyhow!("failed to hash a link password: {error}"))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(error) => {
+            tracing::error!(error = ?error, "stored link password hash is invalid");
+            false
+        }
+    }
+}
+
+/// Counts unlock attempts per client IP and slug, and bounds how many
+/// passwords are verified at once
+pub struct UnlockLimiter {
+    failures: Mutex<HashMap<(IpAddr, String), (u32, Instant)>>,
+    verifications: Semaphore,
+    /// Whether `X-Forwarded-For` is set by a reverse proxy we run behind
+    trust_forwarded_for: bool,
+}
+
+impl UnlockLimiter {
+    pub fn from_env() -> UnlockLimiter {
+        let trust_forwarded_for = env::var("TRUST_FORWARDED_FOR")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(false);
+
+        UnlockLimiter {
+            failures: Mutex::new(HashMap::new()),
+            verifications: Semaphore::new(MAX_VERIFICATIONS),
+            trust_forwarded_for,
+        }
+    }
+
+    /// The address of the client, the one our proxy saw when we trust it
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trust_forwarded_for {
+            return peer.ip();
+        }
+
+        // the proxy appends the address it saw, anything before it is the client's claim
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(peer.ip())
+    }
+
+    /// Counts an attempt as failed before the password is checked, so
+    /// concurrent guesses can't slip past the limit. Returns how long the
+    /// client has to wait once it used up its attempts, `clear` forgets them
+    /// when the password turns out to be right.
+    pub fn record_attempt(&self, ip: IpAddr, slug: &str) -> Option<Duration> {
+        let key = (client_key(ip), slug.to_owned());
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&key) {
+            failures.retain(|_, (_, since)| since.elapsed() < FAILURE_WINDOW);
+        }
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&key) {
+            let oldest = failures
+                .iter()
+                .min_by_key(|(_, (_, since))| *since)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+
+        let entry = failures.entry(key).or_insert((0, Instant::now()));
+        let elapsed = entry.1.elapsed();
+        if elapsed >= FAILURE_WINDOW {
+            *entry = (0, Instant::now());
+        } else if entry.0 >= MAX_FAILURES {
+            return Some(FAILURE_WINDOW - elapsed);
+        }
+        entry.0 += 1;
+        None
+    }
+
+    /// Verifies a password off the runtime, waiting while too many are being
+    /// verified already
+    pub async fn verify(&self, hash: String, password: String) -> anyhow::Result<bool> {
+        let _permit = self.verifications.acquire().await?;
+        let valid = tokio::task::spawn_blocking(move || verify_password(&hash, &password)).await?;
+        Ok(valid)
+    }
+
+    pub fn clear(&self, ip: IpAddr, slug: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&(client_key(ip), slug.to_owned()));
+    }
+}
+
+/// Clients get a whole /64 for IPv6, so count them by that instead of the
+/// address they can rotate through freely
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = u128::from(v6) & !(u128::MAX >> 64);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(trust_forwarded_for: bool) -> UnlockLimiter {
+        UnlockLimiter {
+            failures: Mutex::new(HashMap::new()),
+            verifications: Semaphore::new(MAX_VERIFICATIONS),
+            trust_forwarded_for,
+        }
+    }
+
+    #[test]
+    fn passwords_verify_against_their_hash() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(verify_password(&hash, "hunter2"));
+        assert!(!verify_password(&hash, "hunter3"));
+        assert!(!verify_password("not a hash", "hunter2"));
+    }
+
+    #[tokio::test]
+    async fn verifies_passwords_off_the_runtime() {
+        let hash = hash_password("hunter2").unwrap();
+        let limiter = limiter(false);
+        assert!(limiter
+            .verify(hash.clone(), "hunter2".to_owned())
+            .await
+            .unwrap());
+        assert!(!limiter.verify(hash, "hunter3".to_owned()).await.unwrap());
+    }
+
+    #[test]
+    fn locks_a_client_out_after_too_many_attempts() {
+        let limiter = limiter(false);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(limiter.record_attempt(ip, "cats").is_none());
+        }
+        let wait = limiter.record_attempt(ip, "cats").unwrap();
+        assert!(wait <= FAILURE_WINDOW);
+
+        // other links and other clients aren't affected
+        assert!(limiter.record_attempt(ip, "dogs").is_none());
+        assert!(limiter
+            .record_attempt("203.0.113.8".parse().unwrap(), "cats")
+            .is_none());
+
+        limiter.clear(ip, "cats");
+        assert!(limiter.record_attempt(ip, "cats").is_none());
+    }
+
+    #[test]
+    fn counts_ipv6_clients_by_their_prefix() {
+        let limiter = limiter(false);
+        for i in 0..MAX_FAILURES {
+            let ip: IpAddr = format!("2001:db8:1:2::{i:x}").parse().unwrap();
+            assert!(limiter.record_attempt(ip, "cats").is_none());
+        }
+        let ip: IpAddr = "2001:db8:1:2:ffff::1".parse().unwrap();
+        assert!(limiter.record_attempt(ip, "cats").is_some());
+        let ip: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert!(limiter.record_attempt(ip, "cats").is_none());
+
+        // mapped addresses count as the IPv4 client they are
+        let v4: IpAddr = "203.0.113.7".parse().unwrap();
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        for _ in 0..MAX_FAILURES {
+            assert!(limiter.record_attempt(mapped, "cats").is_none());
+        }
+        assert!(limiter.record_attempt(v4, "cats").is_some());
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_clients() {
+        let limiter = limiter(false);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        for i in 0..MAX_TRACKED + 10 {
+            limiter.record_attempt(ip, &format!("slug{i}"));
+        }
+        let failures = limiter.failures.lock().unwrap();
+        assert_eq!(failures.len(), MAX_TRACKED);
+        // older entries made room for the newest
+        assert!(failures.contains_key(&(ip, format!("slug{}", MAX_TRACKED + 9))));
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_when_configured() {
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.7".parse().unwrap(),
+        );
+
+        assert_eq!(limiter(false).client_ip(peer, &headers), peer.ip());
+        assert_eq!(
+            limiter(true).client_ip(peer, &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(limiter(true).client_ip(peer, &HeaderMap::new()), peer.ip());
+    }
+}
@@ -4,6 +4,7 @@ use anyhow::Ok;
 use reqwest::Client;
 use serde::Deserialize;
 
+pub mod link;
 pub mod user;
 
 #[derive(Deserialize, Debug)]
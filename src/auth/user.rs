@@ -26,6 +26,16 @@ pub struct TokenClaims {
     pub exp: i64,
 }
 
+/// Proof that a client knows the password of a public link
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LinkUnlockClaims {
+    pub slug: String,
+    pub exp: i64,
+}
+
+/// How long an unlocked link stays unlocked, in seconds
+pub const LINK_UNLOCK_TTL: i64 = 60 * 60;
+
 pub struct TokenHandler {
     enc_key: EncodingKey,
     dec_key: DecodingKey,
@@ -76,4 +86,21 @@ impl TokenHandler {
             jsonwebtoken::decode(token, &self.dec_key, &self.validation)?;
         Ok(token_data.claims)
     }
+
+    pub fn sign_link_unlock(&self, slug: &str) -> anyhow::Result<String> {
+        let claims = LinkUnlockClaims {
+            slug: slug.to_owned(),
+            exp: Utc::now().timestamp() + LINK_UNLOCK_TTL,
+        };
+
+        let token = jsonwebtoken::encode(&self.header, &claims, &self.enc_key)?;
+        Ok(token)
+    }
+
+    /// Whether `token` unlocks `slug`, session tokens never do since they lack the slug
+    pub fn verify_link_unlock(&self, token: &str, slug: &str) -> bool {
+        let token_data: Result<TokenData<LinkUnlockClaims>, _> =
+            jsonwebtoken::decode(token, &self.dec_key, &self.validation);
+        token_data.is_ok_and(|data| data.claims.slug == slug)
+    }
 }
@@ -0,0 +1,236 @@
+//! Reconciles storage with postgres. Uploads and deletes that fail halfway
+//! leave content without a row or rows without content behind, this finds
+//! both and removes orphaned content unless it runs with `--dry-run`. Rows
+//! without content are only removed with `--purge-missing`, since a
+//! misconfigured storage backend would make every row look like one. Every
+//! configured pool is checked, content counts as orphaned in a pool its row
+//! doesn't point at.
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    state::ApiState,
+    storage::{blob_key, object_key, ObjectMeta},
+};
+
+/// Content younger than this may belong to an upload that hasn't created its row yet
+const GRACE_PERIOD_HOURS: i64 = 24;
+
+#[derive(Default, Serialize)]
+struct OrphanCount {
+    count: u64,
+    bytes: u64,
+}
+
+impl OrphanCount {
+    fn add(&mut self, meta: &ObjectMeta) {
+        self.count += 1;
+        self.bytes += meta.size;
+    }
+}
+
+#[derive(Default, Serialize)]
+struct GcReport {
+    dry_run: bool,
+    scanned_keys: u64,
+    scanned_rows: u64,
+    /// Vault objects with no row, or left behind after moving into a blob
+    orphan_objects: OrphanCount,
+    orphan_thumbnails: OrphanCount,
+    orphan_variants: OrphanCount,
+    /// Pending tails of resumable uploads that no longer exist
+    orphan_uploads: OrphanCount,
+    orphan_blobs: OrphanCount,
+    /// Rows whose content is gone from storage
+    missing_content: u64,
+    /// Keys that couldn't be removed, they are picked up by the next run
+    failed: u64,
+}
+
+/// The hash of a `/blobs/{prefix}/{hash}` key
+fn parse_blob_key(key: &str) -> Option<&str> {
+    let (_, hash) = key.strip_prefix("/blobs/")?.split_once('/')?;
+    Some(hash)
+}
+
+/// The object id of a `/vaults/{user_id}/variants/{id}/{name}` key
+fn parse_variant_key(key: &str) -> Option<Uuid> {
+    let mut parts = key.strip_prefix("/vaults/")?.split('/').skip(1);
+    if parts.next()? != "variants" {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Splits a `/vaults/{user_id}/{kind}/{id}` key
+fn parse_vault_key(key: &str) -> Option<(Uuid, &str, Uuid)> {
+    let mut parts = key.strip_prefix("/vaults/")?.split('/');
+    let user_id = parts.next()?.parse().ok()?;
+    let kind = parts.next()?;
+    let id = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some((user_id, kind, id))
+}
+
+pub async fn collect_garbage(state: &ApiState, args: &[String]) -> anyhow::Result<()> {
+    let mut dry_run = false;
+    let mut purge_missing = false;
+    for arg in args {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--purge-missing" => purge_missing = true,
+            _ => anyhow::bail!("usage: gc [--dry-run] [--purge-missing]"),
+        }
+    }
+
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    // rows are read before listing storage, so content uploaded in between
+    // looks orphaned and is only protected by the grace period
+    let objects = state.pg.list_object_refs().await?;
+    let blobs = state
+        .pg
+        .list_blob_hashes()
+        .await?
+        .into_iter()
+        .collect::<HashSet<(String, String)>>();
+    let pending = state.pg.list_pending_uploads().await?;
+    report.scanned_rows = objects.len() as u64;
+
+    let legacy_objects = objects
+        .iter()
+        .filter(|(_, _, hash, _)| hash.is_none())
+        .map(|(_, id, _, pool)| (pool.as_str(), *id))
+        .collect::<HashSet<_>>();
+    // thumbnails, variants and staged uploads count as referenced in every pool, copies
+    // left behind by moving an object are queued for deletion already
+    let object_ids = objects
+        .iter()
+        .map(|(_, id, _, _)| *id)
+        .collect::<HashSet<_>>();
+    let upload_ids = pending.iter().map(|(id, _, _)| *id).collect::<HashSet<_>>();
+    let staged_ids = pending
+        .iter()
+        .map(|(_, object_id, _)| *object_id)
+        .collect::<HashSet<_>>();
+
+    let mut stored = Vec::new();
+    for (name, pool) in state.storage.pools() {
+        for prefix in ["/vaults/", "/blobs/"] {
+            for meta in pool.backend.list(prefix).await? {
+                stored.push((name.as_str(), meta));
+            }
+        }
+    }
+    report.scanned_keys = stored.len() as u64;
+    let stored_keys = stored
+        .iter()
+        .map(|(pool, meta)| (*pool, meta.key.as_str()))
+        .collect::<HashSet<_>>();
+
+    let cutoff = Utc::now() - Duration::hours(GRACE_PERIOD_HOURS);
+    let mut orphans = Vec::new();
+    for (pool, meta) in &stored {
+        if meta.last_modified.is_some_and(|date| date > cutoff) {
+            continue;
+        }
+
+        let counter = if let Some(hash) = parse_blob_key(&meta.key) {
+            if blobs.contains(&(hash.to_owned(), pool.to_string())) {
+                continue;
+            }
+            &mut report.orphan_blobs
+        } else if let Some(id) = parse_variant_key(&meta.key) {
+            if object_ids.contains(&id) {
+                continue;
+            }
+            &mut report.orphan_variants
+        } else {
+            match parse_vault_key(&meta.key) {
+                Some((_, "objects", id))
+                    if legacy_objects.contains(&(*pool, id)) || staged_ids.contains(&id) =>
+                {
+                    continue
+                }
+                Some((_, "objects", _)) => &mut report.orphan_objects,
+                Some((_, "thumbnails", id)) if object_ids.contains(&id) => continue,
+                Some((_, "thumbnails", _)) => &mut report.orphan_thumbnails,
+                Some((_, "uploads", id)) if upload_ids.contains(&id) => continue,
+                Some((_, "uploads", _)) => &mut report.orphan_uploads,
+                _ => {
+                    tracing::warn!("gc: ignoring unknown key {}", meta.key);
+                    continue;
+                }
+            }
+        };
+
+        counter.add(meta);
+        orphans.push((*pool, meta));
+    }
+
+    for (pool, meta) in orphans {
+        tracing::info!(
+            "gc: orphaned {} in pool {pool} ({} bytes)",
+            meta.key,
+            meta.size
+        );
+        if dry_run {
+            continue;
+        }
+
+        // a blob may have been re-uploaded or moved here since the rows were read
+        if let Some(hash) = parse_blob_key(&meta.key) {
+            if state.pg.fetch_blob_pool(hash).await?.as_deref() == Some(pool) {
+                continue;
+            }
+        }
+
+        if let Err(error) = state.storage.backend(pool)?.delete(&meta.key).await {
+            tracing::error!(error = ?error, "gc: failed to delete {}", meta.key);
+            report.failed += 1;
+        }
+    }
+
+    let mut missing: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for (user_id, id, hash, pool) in &objects {
+        let key = match hash {
+            Some(hash) => blob_key(hash),
+            None => object_key(*user_id, *id),
+        };
+        if stored_keys.contains(&(pool.as_str(), key.as_str())) {
+            continue;
+        }
+        // the listing may be stale, so make sure before reporting it
+        if state.storage.backend(pool)?.head(&key).await?.is_some() {
+            continue;
+        }
+
+        tracing::info!("gc: object {id} of user {user_id} has no content at {key} in pool {pool}");
+        report.missing_content += 1;
+        missing.entry(*user_id).or_default().push(*id);
+    }
+
+    if !dry_run && purge_missing {
+        for (user_id, ids) in missing {
+            let mut trans = state.pg.inner.begin().await?;
+            state
+                .pg
+                .schedule_object_deletion(user_id, &ids, &mut trans)
+                .await?;
+            trans.commit().await?;
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,20 @@
+//! One-off maintenance commands, run with `linker <command>` instead of the server
+
+use std::sync::Arc;
+
+use crate::state::ApiState;
+
+mod gc;
+mod move_pool;
+mod replicate;
+mod rotate;
+
+pub async fn run(state: Arc<ApiState>, command: &str, args: &[String]) -> anyhow::Result<()> {
+    match command {
+        "gc" => gc::collect_garbage(&state, args).await,
+        "move-pool" => move_pool::move_objects(&state, args).await,
+        "replicate-backfill" => replicate::backfill_replica(&state, args).await,
+        "rotate-master-key" => rotate::rotate_master_key(&state).await,
+        other => anyhow::bail!("unknown command '{other}'"),
+    }
+}
@@ -0,0 +1,170 @@
+//! Moves objects from one storage pool to another, e.g. to retire a bucket.
+//! Content is copied first and the rows are switched afterwards, so objects
+//! stay readable throughout. The copies left in the old pool are removed by
+//! the deletion worker, running it again picks up whatever failed.
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    state::ApiState,
+    storage::{blob_key, object_key, transfer, StorageBackend},
+};
+
+#[derive(Default, Serialize)]
+struct MoveReport {
+    dry_run: bool,
+    from: String,
+    to: String,
+    moved_blobs: u64,
+    /// Objects whose content was uploaded before deduplication
+    moved_objects: u64,
+    moved_bytes: u64,
+    /// Content that was released or moved by someone else while copying, or
+    /// is still being deleted from the target pool after an earlier move
+    skipped: u64,
+    /// Rows whose content isn't in the pool they point at
+    missing: u64,
+    failed: u64,
+}
+
+enum Outcome {
+    Moved(u64),
+    Skipped,
+    Missing,
+}
+
+/// Copies `key` and then switches the rows with `switch`. If the rows changed
+/// in the meantime the copy is left for gc, an upload may have stored the
+/// same content in the target pool since.
+async fn move_key<F, Fut>(
+    from: &dyn StorageBackend,
+    to: &dyn StorageBackend,
+    key: &str,
+    switch: F,
+) -> anyhow::Result<Outcome>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<bool>>,
+{
+    let Some(size) = transfer(from, to, key).await? else {
+        return Ok(Outcome::Missing);
+    };
+
+    if !switch().await? {
+        return Ok(Outcome::Skipped);
+    }
+
+    Ok(Outcome::Moved(size))
+}
+
+fn record(report: &mut MoveReport, key: &str, outcome: anyhow::Result<Outcome>) -> bool {
+    match outcome {
+        Ok(Outcome::Moved(size)) => {
+            report.moved_bytes += size;
+            return true;
+        }
+        Ok(Outcome::Skipped) => report.skipped += 1,
+        Ok(Outcome::Missing) => {
+            tracing::warn!("move-pool: {key} is missing from pool {}", report.from);
+            report.missing += 1;
+        }
+        Err(error) => {
+            tracing::error!(error = ?error, "move-pool: failed to move {key}");
+            report.failed += 1;
+        }
+    }
+    false
+}
+
+pub async fn move_objects(state: &ApiState, args: &[String]) -> anyhow::Result<()> {
+    let usage = "usage: move-pool <from> <to> [--user <id>] [--dry-run]";
+    let (Some(from), Some(to)) = (args.first(), args.get(1)) else {
+        anyhow::bail!(usage);
+    };
+
+    let mut dry_run = false;
+    let mut user_id: Option<Uuid> = None;
+    let mut args = args[2..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--user" => match args.next() {
+                Some(id) => user_id = Some(id.parse()?),
+                None => anyhow::bail!(usage),
+            },
+            _ => anyhow::bail!(usage),
+        }
+    }
+
+    if from == to {
+        anyhow::bail!("objects are already in pool '{to}'");
+    }
+    let from_backend = state.storage.backend(from)?;
+    let to_backend = state.storage.backend(to)?;
+
+    let mut report = MoveReport {
+        dry_run,
+        from: from.clone(),
+        to: to.clone(),
+        ..Default::default()
+    };
+
+    let blobs = state.pg.list_pool_blobs(from, user_id).await?;
+    let objects = state.pg.list_pool_legacy_objects(from, user_id).await?;
+    tracing::info!(
+        "move-pool: {} blobs and {} objects to move from {from} to {to}",
+        blobs.len(),
+        objects.len()
+    );
+    if dry_run {
+        report.moved_blobs = blobs.len() as u64;
+        report.moved_objects = objects.len() as u64;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    for hash in &blobs {
+        let key = blob_key(hash);
+        if !state.pg.cancel_pool_deletions(&key, to).await? {
+            report.skipped += 1;
+            continue;
+        }
+        let outcome = move_key(from_backend, to_backend, &key, || async {
+            let mut trans = state.pg.inner.begin().await?;
+            let moved = state.pg.move_blob(hash, from, to, &mut trans).await?;
+            trans.commit().await?;
+            Ok(moved)
+        })
+        .await;
+
+        if record(&mut report, &key, outcome) {
+            report.moved_blobs += 1;
+        }
+    }
+
+    for (owner, id) in &objects {
+        let key = object_key(*owner, *id);
+        if !state.pg.cancel_pool_deletions(&key, to).await? {
+            report.skipped += 1;
+            continue;
+        }
+        let outcome = move_key(from_backend, to_backend, &key, || async {
+            let mut trans = state.pg.inner.begin().await?;
+            let moved = state
+                .pg
+                .move_legacy_object(*owner, *id, from, to, &mut trans)
+                .await?;
+            trans.commit().await?;
+            Ok(moved)
+        })
+        .await;
+
+        if record(&mut report, &key, outcome) {
+            report.moved_objects += 1;
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
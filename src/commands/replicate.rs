@@ -0,0 +1,140 @@
+//! Copies content the replica is missing, e.g. everything stored before the
+//! replica was configured or writes whose replication gave up. With `--prune`
+//! it also removes content that only exists on the replica. Every replicated
+//! pool is backfilled unless `--pool` names one.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{state::ApiState, storage::Replication};
+
+#[derive(Default, Serialize)]
+struct BackfillReport {
+    dry_run: bool,
+    pools: Vec<String>,
+    scanned_keys: u64,
+    up_to_date: u64,
+    copied: u64,
+    copied_bytes: u64,
+    pruned: u64,
+    /// Keys that couldn't be copied or removed, they are picked up by the next run
+    failed: u64,
+}
+
+pub async fn backfill_replica(state: &ApiState, args: &[String]) -> anyhow::Result<()> {
+    let usage = "usage: replicate-backfill [--pool <name>] [--dry-run] [--prune]";
+    let mut dry_run = false;
+    let mut prune = false;
+    let mut only_pool = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--prune" => prune = true,
+            "--pool" => match args.next() {
+                Some(name) => only_pool = Some(name.as_str()),
+                None => anyhow::bail!(usage),
+            },
+            _ => anyhow::bail!(usage),
+        }
+    }
+
+    let mut report = BackfillReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    for (name, pool) in state.storage.pools() {
+        if only_pool.is_some_and(|only| only != name) {
+            continue;
+        }
+        let Some(replication) = &pool.replication else {
+            continue;
+        };
+
+        report.pools.push(name.clone());
+        backfill_pool(replication, prune, &mut report).await?;
+    }
+
+    if report.pools.is_empty() {
+        anyhow::bail!("no matching storage pool has a replica configured");
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn backfill_pool(
+    replication: &Replication,
+    prune: bool,
+    report: &mut BackfillReport,
+) -> anyhow::Result<()> {
+    let dry_run = report.dry_run;
+    let mut stored = Vec::new();
+    let mut replicated = HashMap::new();
+    for prefix in ["/vaults/", "/blobs/"] {
+        stored.extend(replication.primary().list(prefix).await?);
+        for meta in replication.replica().list(prefix).await? {
+            replicated.insert(meta.key, meta.size);
+        }
+    }
+    report.scanned_keys += stored.len() as u64;
+
+    for meta in &stored {
+        // pending tails of resumable uploads aren't replicated
+        if meta.key.contains("/uploads/") || replicated.get(&meta.key) == Some(&meta.size) {
+            report.up_to_date += 1;
+            continue;
+        }
+
+        tracing::info!(
+            "replicate-backfill: copying {} ({} bytes)",
+            meta.key,
+            meta.size
+        );
+        if dry_run {
+            continue;
+        }
+
+        match replication.sync_key(&meta.key).await {
+            Ok(()) => {
+                report.copied += 1;
+                report.copied_bytes += meta.size;
+            }
+            Err(error) => {
+                tracing::error!(error = ?error, "replicate-backfill: failed to copy {}", meta.key);
+                report.failed += 1;
+            }
+        }
+    }
+
+    if prune {
+        let stored_keys = stored
+            .iter()
+            .map(|meta| meta.key.as_str())
+            .collect::<HashSet<_>>();
+        for key in replicated.keys() {
+            if stored_keys.contains(key.as_str()) {
+                continue;
+            }
+
+            tracing::info!("replicate-backfill: {key} only exists on the replica");
+            if dry_run {
+                continue;
+            }
+
+            // it may have been written after the listing, sync_key only removes it
+            // when the primary really doesn't have it
+            match replication.sync_key(key).await {
+                Ok(()) => report.pruned += 1,
+                Err(error) => {
+                    tracing::error!(error = ?error, "replicate-backfill: failed to prune {key}");
+                    report.failed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use crate::state::ApiState;
+
+/// How many keys are rewrapped per query
+const BATCH_SIZE: i64 = 500;
+
+/// Rewraps every blob and vault key with the current master key. Content is
+/// left untouched, afterwards the old master key can be retired.
+pub async fn rotate_master_key(state: &ApiState) -> anyhow::Result<()> {
+    let Some(keys) = &state.storage.keys else {
+        anyhow::bail!("STORAGE_MASTER_KEY is not set, there is nothing to rotate");
+    };
+    let current = keys.current_id();
+
+    let mut blobs = 0;
+    loop {
+        let stale = state.pg.list_stale_blob_keys(current, BATCH_SIZE).await?;
+        if stale.is_empty() {
+            break;
+        }
+
+        for (hash, wrapped) in stale {
+            if let Some(rewrapped) = keys.rewrap(&wrapped)? {
+                state
+                    .pg
+                    .update_blob_key(&hash, &wrapped, &rewrapped)
+                    .await?;
+                blobs += 1;
+            }
+        }
+    }
+
+    let mut vaults = 0;
+    loop {
+        let stale = state.pg.list_stale_vault_keys(current, BATCH_SIZE).await?;
+        if stale.is_empty() {
+            break;
+        }
+
+        for (user_id, wrapped) in stale {
+            if let Some(rewrapped) = keys.rewrap(&wrapped)? {
+                state.pg.update_vault_key(user_id, &rewrapped).await?;
+                vaults += 1;
+            }
+        }
+    }
+
+    tracing::info!(
+        "rewrapped {blobs} blob keys and {vaults} vault keys with master key '{current}'"
+    );
+    Ok(())
+}
@@ -3,12 +3,17 @@ use std::env;
 use anyhow::Result;
 use chrono::NaiveDateTime;
 use serde::Serialize;
-use sqlx::{prelude::FromRow, PgConnection, Pool, Postgres};
+use sqlx::{prelude::FromRow, types::Json, PgConnection, Pool, Postgres};
 use uuid::Uuid;
 
+use crate::storage::{
+    blob_key, crypto::WrappedKey, object_key, thumb_key, variants_prefix, UploadPart,
+};
+
 // Searchable objects:
-// - Text files
+// - Text files: text/*, json, configs and source code (decoded, see `text`)
 // - Image files: png, jpeg, webp (with OCR)
+// - PDFs: their text layer, or OCR of the first pages of scans
 
 pub struct CreateCdnObject {
     pub id: Uuid,
@@ -17,14 +22,27 @@ pub struct CreateCdnObject {
     pub content_size: i64,
     pub hash: String,
     pub file_name: String,
+    /// Whether this upload stored the blob itself, or only references an existing one
+    pub wrote_blob: bool,
 }
 
-/// Indicates this object has been OCR'd and indexed into meilisearch
+/// The object doesn't exist, is deleted or belongs to someone else, answered with 404
+#[derive(Debug, thiserror::Error)]
+#[error("cdn object not found")]
+pub struct ObjectNotFound;
+
+/// Indicates this object's text has been extracted and indexed into meilisearch
 pub const COF_INDEXED: i64 = 1;
 
 /// Indicates this object is searchable, i.e image or text
 pub const COF_SEARCHABLE: i64 = 2;
 
+/// Indicates this object is an image that gets thumbnails
+pub const COF_THUMBNAILABLE: i64 = 4;
+
+/// Indicates the thumbnails of this object's current content were rendered
+pub const COF_THUMBNAILED: i64 = 8;
+
 #[derive(FromRow, Serialize, Clone, Debug)]
 pub struct CdnObject {
     pub id: Uuid,
@@ -37,6 +55,209 @@ pub struct CdnObject {
     pub is_public: bool,
     pub sha256_hash: String,
     pub flags: i64,
+    #[serde(skip_serializing)]
+    pub blob_hash: Option<String>,
+    pub current_version: i32,
+    /// When the scrub worker last compared the content against its hash
+    #[serde(skip_serializing)]
+    pub verified_at: Option<NaiveDateTime>,
+    #[serde(skip_serializing)]
+    pub integrity_error: Option<String>,
+    /// The pool holding the current content and the thumbnail
+    #[serde(skip_serializing)]
+    pub storage_pool: String,
+    /// The public link answers 410 from then on
+    pub link_expires_at: Option<NaiveDateTime>,
+    pub link_max_downloads: Option<i32>,
+    /// Downloads through the public link, counted while it has limits
+    pub link_downloads: i32,
+    /// Computed by postgres, `None` for links without a download limit
+    pub link_remaining_downloads: Option<i32>,
+    /// When the last allowed download was handed out
+    #[serde(skip_serializing)]
+    pub link_spent_at: Option<NaiveDateTime>,
+    /// Whether the object is deleted once its link is spent
+    pub delete_when_spent: bool,
+    /// Argon2 hash of the password the public link asks for
+    #[serde(skip_serializing)]
+    pub link_password_hash: Option<String>,
+    /// Computed by postgres from `link_password_hash`
+    pub link_is_protected: bool,
+    /// Times the thumbnails of the current content were tried to be rendered
+    #[serde(skip_serializing)]
+    pub thumbnail_attempts: i32,
+    /// When the object started serving its current content, unlike
+    /// `uploaded_at` it moves forward when an older version is restored
+    #[serde(skip_serializing)]
+    pub content_modified_at: NaiveDateTime,
+}
+
+impl CdnObject {
+    /// Whether downloads through the public link are counted and checked
+    pub fn has_link_limits(&self) -> bool {
+        self.link_expires_at.is_some() || self.link_max_downloads.is_some()
+    }
+
+    /// Where the content of this object lives in storage, objects uploaded
+    /// before deduplication still keep their content in the vault
+    pub fn storage_key(&self) -> String {
+        match &self.blob_hash {
+            Some(hash) => blob_key(hash),
+            None => object_key(self.user_id, self.id),
+        }
+    }
+
+    /// This object as it was at `version`, for serving older content
+    pub fn at_version(&self, version: &ObjectVersion) -> CdnObject {
+        CdnObject {
+            uploaded_at: version.uploaded_at,
+            content_modified_at: version.uploaded_at,
+            content_type: version.content_type.clone(),
+            content_size: version.content_size,
+            file_name: version.file_name.clone(),
+            sha256_hash: version.sha256_hash.clone(),
+            blob_hash: version.blob_hash.clone(),
+            current_version: version.version,
+            storage_pool: version.storage_pool.clone(),
+            ..self.clone()
+        }
+    }
+}
+
+/// One upload of an object's content, the object serves its current one
+#[derive(FromRow, Serialize, Clone, Debug)]
+pub struct ObjectVersion {
+    pub object_id: Uuid,
+    pub version: i32,
+    pub content_type: String,
+    pub content_size: i64,
+    pub file_name: String,
+    pub sha256_hash: String,
+    #[serde(skip_serializing)]
+    pub blob_hash: Option<String>,
+    pub uploaded_at: NaiveDateTime,
+    #[serde(skip_serializing)]
+    pub storage_pool: String,
+}
+
+/// A resumable upload that hasn't been turned into a cdn object yet
+#[derive(FromRow, Clone, Debug)]
+pub struct UploadSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub object_id: Uuid,
+    pub multipart_id: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub parts: Json<Vec<UploadPart>>,
+    pub content_type: String,
+    pub file_name: String,
+    pub created_at: NaiveDateTime,
+    /// The pool the upload is staged in
+    pub storage_pool: String,
+    /// Pushed forward whenever content arrives, the uploads worker drops the
+    /// session and its content after
+    pub expires_at: NaiveDateTime,
+    /// Set once all content is assembled under the object's key
+    pub completing: bool,
+    /// The hash of the assembled content, once it was computed
+    pub sha256_hash: Option<String>,
+}
+
+/// An object id handed out for a presigned upload that hasn't been finalized
+#[derive(FromRow, Clone, Debug)]
+pub struct UploadReservation {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub content_type: String,
+    pub content_size: i64,
+    pub file_name: String,
+    pub sha256_hash: String,
+    pub multipart_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// The pool the upload is staged in
+    pub storage_pool: String,
+}
+
+/// Something a deleted object left behind, see [`PgClient::schedule_object_deletion`]
+#[derive(FromRow, Clone, Debug)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub kind: String,
+    pub target: String,
+    pub attempts: i32,
+    /// The pool `target` is stored in, for object and thumbnail entries
+    pub storage_pool: Option<String>,
+}
+
+pub const OUTBOX_BLOB: &str = "blob";
+pub const OUTBOX_OBJECT: &str = "object";
+pub const OUTBOX_THUMBNAIL: &str = "thumbnail";
+/// Every image variant of an object, `target` is their common key prefix
+pub const OUTBOX_VARIANTS: &str = "variants";
+pub const OUTBOX_SEARCH_DOCUMENT: &str = "search_document";
+
+/// How long a claimed blob may go without a reference before it's deleted
+const BLOB_CLAIM_GRACE_SECS: i64 = 60 * 60;
+
+/// What [`PgClient::claim_blob`] found
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlobClaim {
+    /// The caller writes the content and completes the claim
+    Claimed,
+    /// The content is stored already
+    Stored,
+    /// Another upload is writing the content right now
+    Busy,
+}
+
+pub const LINK_PUBLISHED: &str = "published";
+pub const LINK_UNPUBLISHED: &str = "unpublished";
+/// The object got a new slug and the old one was retired
+pub const LINK_ROTATED: &str = "rotated";
+
+/// Limits a public link is published with, they survive rotations
+#[derive(Clone, Default, Debug)]
+pub struct LinkLimits {
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_downloads: Option<i32>,
+    pub delete_when_spent: bool,
+    /// Argon2 hash of the password the link asks for
+    pub password_hash: Option<String>,
+}
+
+/// A change to the public link of an object
+#[derive(FromRow, Serialize, Debug)]
+pub struct LinkAuditEntry {
+    pub id: i64,
+    pub object_id: Uuid,
+    pub action: String,
+    pub old_slug: Option<String>,
+    pub new_slug: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Deletions of the thumbnail and image variants of an object, they are
+/// stored in the pool of the content they were rendered from
+fn rendered_deletions(
+    user_id: Uuid,
+    object_id: Uuid,
+    storage_pool: &str,
+) -> [(&'static str, Option<Uuid>, String, Option<String>); 2] {
+    [
+        (
+            OUTBOX_THUMBNAIL,
+            Some(object_id),
+            thumb_key(user_id, object_id),
+            Some(storage_pool.to_owned()),
+        ),
+        (
+            OUTBOX_VARIANTS,
+            Some(object_id),
+            variants_prefix(user_id, object_id),
+            Some(storage_pool.to_owned()),
+        ),
+    ]
 }
 
 #[derive(Clone)]
@@ -60,7 +281,7 @@ impl PgClient {
         skip: i32,
     ) -> anyhow::Result<Vec<CdnObject>> {
         let objects: Vec<CdnObject> =
-            sqlx::query_as("SELECT * FROM cdn_objects WHERE user_id = $1 ORDER BY uploaded_at DESC LIMIT $2 OFFSET $3 ")
+            sqlx::query_as("SELECT * FROM cdn_objects WHERE user_id = $1 AND deleted_at IS NULL ORDER BY uploaded_at DESC LIMIT $2 OFFSET $3 ")
                 .bind(user_id)
                 .bind(limit)
                 .bind(skip)
@@ -75,7 +296,7 @@ impl PgClient {
         object_id: Uuid,
     ) -> anyhow::Result<CdnObject> {
         let object: CdnObject = sqlx::query_as(
-            "SELECT * FROM cdn_objects WHERE user_id = $1 AND id = $2",
+            "SELECT * FROM cdn_objects WHERE user_id = $1 AND id = $2 AND deleted_at IS NULL",
         )
         .bind(user_id)
         .bind(object_id)
@@ -85,84 +306,1658 @@ impl PgClient {
     }
 
     pub async fn fetch_cdn_object_slug(&self, slug: &str) -> anyhow::Result<CdnObject> {
-        let object: CdnObject = sqlx::query_as("SELECT * FROM cdn_objects WHERE slug = $1")
+        let object: CdnObject = sqlx::query_as("SELECT * FROM cdn_objects WHERE slug = $1 AND deleted_at IS NULL")
             .bind(slug)
             .fetch_one(&self.inner)
             .await?;
         Ok(object)
     }
 
-    pub async fn find_existing_hash(&self, user_id: Uuid, hash: &str) -> anyhow::Result<bool> {
-        let obj = sqlx::query!(
-            "SELECT sha256_hash FROM cdn_objects WHERE user_id = $1 AND sha256_hash = $2",
-            user_id,
-            hash
+    pub async fn blob_exists(&self, hash: &str) -> anyhow::Result<bool> {
+        let exists: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM blobs WHERE sha256_hash = $1 AND claimed_until IS NULL)",
         )
-        .fetch_optional(&self.inner)
+        .bind(hash)
+        .fetch_one(&self.inner)
         .await?;
-        Ok(obj.map(|x| x.sha256_hash == hash).unwrap_or(false))
+        Ok(exists)
+    }
+
+    /// The pool a blob is stored in, `None` if there is no such blob
+    pub async fn fetch_blob_pool(&self, hash: &str) -> anyhow::Result<Option<String>> {
+        let storage_pool =
+            sqlx::query_scalar("SELECT storage_pool FROM blobs WHERE sha256_hash = $1")
+                .bind(hash)
+                .fetch_optional(&self.inner)
+                .await?;
+        Ok(storage_pool)
     }
 
+    /// Inserts the object and takes a reference on its blob, in one transaction
     pub async fn create_cdn_object(
         &self,
         obj: CreateCdnObject,
         conn: Option<&mut PgConnection>,
         flags: i64,
     ) -> anyhow::Result<CdnObject> {
-        let query = sqlx::query_as!(
-            CdnObject,
+        let Some(conn) = conn else {
+            let mut trans = self.inner.begin().await?;
+            let cdn_obj = Self::insert_cdn_object(obj, &mut trans, flags).await?;
+            trans.commit().await?;
+            return Ok(cdn_obj);
+        };
+
+        Self::insert_cdn_object(obj, conn, flags).await
+    }
+
+    /// Inserts the row of a blob whose content is about to be written, without
+    /// references and claimed by `claim_id` for `lease_secs`. A claim that ran
+    /// out is taken over, its upload is assumed dead. While the claim holds,
+    /// other uploads of the same content get `Busy` and wait, so they never
+    /// overwrite the content with one encrypted under another data key. The
+    /// claim is queued for deletion, which keeps the blob if an object
+    /// references it by then.
+    pub async fn claim_blob(
+        &self,
+        hash: &str,
+        content_size: i64,
+        storage_pool: &str,
+        key: Option<&WrappedKey>,
+        claim_id: Uuid,
+        lease_secs: i64,
+    ) -> anyhow::Result<BlobClaim> {
+        let mut trans = self.inner.begin().await?;
+        let claimed = sqlx::query(
+            r#"
+            INSERT INTO blobs (sha256_hash, content_size, ref_count, master_key_id, wrapped_key, storage_pool, claim_id, claimed_until)
+            VALUES ($1, $2, 0, $3, $4, $5, $6, NOW() + make_interval(secs => $7))
+            ON CONFLICT (sha256_hash) DO UPDATE SET
+                content_size = EXCLUDED.content_size,
+                master_key_id = EXCLUDED.master_key_id,
+                wrapped_key = EXCLUDED.wrapped_key,
+                storage_pool = EXCLUDED.storage_pool,
+                claim_id = EXCLUDED.claim_id,
+                claimed_until = EXCLUDED.claimed_until
+            WHERE blobs.claimed_until < NOW()
+        "#,
+        )
+        .bind(hash)
+        .bind(content_size)
+        .bind(key.map(|key| &key.master_key_id))
+        .bind(key.map(|key| &key.wrapped))
+        .bind(storage_pool)
+        .bind(claim_id)
+        .bind(lease_secs as f64)
+        .execute(&mut *trans)
+        .await?
+        .rows_affected()
+            == 1;
+        if !claimed {
+            let stored: Option<bool> = sqlx::query_scalar(
+                "SELECT claimed_until IS NULL FROM blobs WHERE sha256_hash = $1",
+            )
+            .bind(hash)
+            .fetch_optional(&mut *trans)
+            .await?;
+            // a blob removed in the meantime can be claimed on the next try
+            return Ok(match stored {
+                Some(true) => BlobClaim::Stored,
+                _ => BlobClaim::Busy,
+            });
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO deletion_outbox (kind, target, next_attempt_at)
+            VALUES ($1, $2, NOW() + make_interval(secs => $3))
+        "#,
+        )
+        .bind(OUTBOX_BLOB)
+        .bind(hash)
+        .bind(BLOB_CLAIM_GRACE_SECS as f64)
+        .execute(&mut *trans)
+        .await?;
+        trans.commit().await?;
+        Ok(BlobClaim::Claimed)
+    }
+
+    /// Marks the content of a claimed blob as written, `false` if the claim
+    /// ran out and another upload took it over
+    pub async fn complete_blob_claim(&self, hash: &str, claim_id: Uuid) -> anyhow::Result<bool> {
+        let completed = sqlx::query(
+            "UPDATE blobs SET claim_id = NULL, claimed_until = NULL WHERE sha256_hash = $1 AND claim_id = $2",
+        )
+        .bind(hash)
+        .bind(claim_id)
+        .execute(&self.inner)
+        .await?
+        .rows_affected()
+            == 1;
+        Ok(completed)
+    }
+
+    /// Ends the claim of an upload that failed to write the content, another
+    /// upload of it takes over right away
+    pub async fn release_blob_claim(&self, hash: &str, claim_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE blobs SET claimed_until = NOW() WHERE sha256_hash = $1 AND claim_id = $2",
+        )
+        .bind(hash)
+        .bind(claim_id)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
+    }
+
+    /// Lets the deletion worker look at the claims of a failed upload right
+    /// away, it only removes blobs nobody took a reference on
+    pub async fn expire_blob_claims(&self, hashes: &[String]) -> anyhow::Result<()> {
+        sqlx::query("UPDATE deletion_outbox SET next_attempt_at = NOW() WHERE kind = $1 AND target = ANY($2)")
+            .bind(OUTBOX_BLOB)
+            .bind(hashes)
+            .execute(&self.inner)
+            .await?;
+        Ok(())
+    }
+
+    /// Takes a reference on the blob of an upload, which the upload claimed or
+    /// found stored, returns the pool the blob lives in
+    async fn reference_blob(
+        obj: &CreateCdnObject,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<String> {
+        let storage_pool: Option<String> = sqlx::query_scalar(
+            "UPDATE blobs SET ref_count = ref_count + 1 WHERE sha256_hash = $1 AND claimed_until IS NULL RETURNING storage_pool",
+        )
+        .bind(&obj.hash)
+        .fetch_optional(conn)
+        .await?;
+
+        // the blob was released and removed between checking for it and now,
+        // or its claim ran out and another upload is writing it again
+        match storage_pool {
+            Some(storage_pool) => Ok(storage_pool),
+            None => anyhow::bail!(
+                "blob {} was removed while uploading object {}",
+                obj.hash,
+                obj.id
+            ),
+        }
+    }
+
+    async fn insert_object_version(
+        obj: &CreateCdnObject,
+        version: i32,
+        storage_pool: &str,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<ObjectVersion> {
+        let version = sqlx::query_as(
+            r#"
+            INSERT INTO object_versions (object_id, version, content_type, content_size, file_name, sha256_hash, blob_hash, storage_pool)
+            VALUES ($1, $2, $3, $4, $5, $6, $6, $7)
+            RETURNING *
+        "#,
+        )
+        .bind(obj.id)
+        .bind(version)
+        .bind(&obj.content_type)
+        .bind(obj.content_size)
+        .bind(&obj.file_name)
+        .bind(&obj.hash)
+        .bind(storage_pool)
+        .fetch_one(conn)
+        .await?;
+        Ok(version)
+    }
+
+    async fn insert_cdn_object(
+        obj: CreateCdnObject,
+        conn: &mut PgConnection,
+        flags: i64,
+    ) -> anyhow::Result<CdnObject> {
+        let storage_pool = Self::reference_blob(&obj, &mut *conn).await?;
+
+        // objects are read by column name, the row may carry more than they need
+        let cdn_obj: CdnObject = sqlx::query_as(
             r#"
-            INSERT INTO cdn_objects (id, user_id, content_type, content_size, file_name, is_public, sha256_hash, flags)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            INSERT INTO cdn_objects (id, user_id, content_type, content_size, file_name, is_public, sha256_hash, flags, blob_hash, storage_pool)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $7, $9)
             RETURNING *
         "#,
-            obj.id,
-            obj.user_id,
-            obj.content_type,
-            obj.content_size,
-            obj.file_name,
-            false,
-            obj.hash,
-            flags
-        );
+        )
+        .bind(obj.id)
+        .bind(obj.user_id)
+        .bind(&obj.content_type)
+        .bind(obj.content_size)
+        .bind(&obj.file_name)
+        .bind(false)
+        .bind(&obj.hash)
+        .bind(flags)
+        .bind(&storage_pool)
+        .fetch_one(&mut *conn)
+        .await?;
+        Self::insert_object_version(&obj, 1, &storage_pool, conn).await?;
+        Ok(cdn_obj)
+    }
 
-        if let Some(conn) = conn {
-            let cdn_obj = query.fetch_one(conn).await?;
-            Ok(cdn_obj)
-        } else {
-            let cdn_obj = query.fetch_one(&self.inner).await?;
-            Ok(cdn_obj)
+    /// Makes an upload the new current version of an existing object
+    pub async fn add_object_version(
+        &self,
+        obj: CreateCdnObject,
+        flags: i64,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<CdnObject> {
+        // locking the object serializes concurrent uploads of new versions
+        let latest: i32 = sqlx::query_scalar(
+            r#"
+            SELECT COALESCE((SELECT MAX(version) FROM object_versions WHERE object_id = o.id), 0)
+            FROM cdn_objects AS o
+            WHERE o.id = $1 AND o.user_id = $2 AND o.deleted_at IS NULL
+            FOR UPDATE
+        "#,
+        )
+        .bind(obj.id)
+        .bind(obj.user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let storage_pool = Self::reference_blob(&obj, &mut *conn).await?;
+        let version =
+            Self::insert_object_version(&obj, latest + 1, &storage_pool, &mut *conn).await?;
+        self.restore_object_version(&version, flags, conn).await
+    }
+
+    /// Points the object at one of its versions, the thumbnail is queued for
+    /// deletion since it shows the previous content. `flags` are the ones of
+    /// freshly uploaded content, so the new content gets indexed again.
+    pub async fn restore_object_version(
+        &self,
+        version: &ObjectVersion,
+        flags: i64,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<CdnObject> {
+        let (old_flags, old_pool): (i64, String) = sqlx::query_as(
+            "SELECT flags, storage_pool FROM cdn_objects WHERE id = $1 FOR UPDATE",
+        )
+        .bind(version.object_id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let cdn_obj: CdnObject = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET
+                content_type = $2,
+                content_size = $3,
+                file_name = $4,
+                sha256_hash = $5,
+                blob_hash = $6,
+                uploaded_at = $7,
+                current_version = $8,
+                flags = $9,
+                storage_pool = $10,
+                content_modified_at = NOW(),
+                thumbnail_attempts = 0,
+                thumbnail_leased_until = NULL
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING *
+        "#,
+        )
+        .bind(version.object_id)
+        .bind(&version.content_type)
+        .bind(version.content_size)
+        .bind(&version.file_name)
+        .bind(&version.sha256_hash)
+        .bind(&version.blob_hash)
+        .bind(version.uploaded_at)
+        .bind(version.version)
+        .bind(flags)
+        .bind(&version.storage_pool)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        // the thumbnail was stored next to the previous content. Variants are
+        // named after the content they show and stay valid, unless they're
+        // left behind in a pool the object doesn't use anymore.
+        let [thumbnail, variants] = rendered_deletions(cdn_obj.user_id, cdn_obj.id, &old_pool);
+        let mut entries = vec![thumbnail];
+        if old_pool != cdn_obj.storage_pool {
+            entries.push(variants);
+        }
+        // searchable content gets indexed again and replaces the document
+        if old_flags & COF_SEARCHABLE != 0 && flags & COF_SEARCHABLE == 0 {
+            entries.push((
+                OUTBOX_SEARCH_DOCUMENT,
+                Some(cdn_obj.id),
+                cdn_obj.id.to_string(),
+                None,
+            ));
         }
+
+        Self::enqueue_deletions(&entries, conn).await?;
+        Ok(cdn_obj)
+    }
+
+    pub async fn list_object_versions(
+        &self,
+        user_id: Uuid,
+        object_id: Uuid,
+    ) -> anyhow::Result<Vec<ObjectVersion>> {
+        let versions = sqlx::query_as(
+            r#"
+            SELECT v.* FROM object_versions AS v
+            JOIN cdn_objects AS o ON o.id = v.object_id
+            WHERE o.id = $1 AND o.user_id = $2 AND o.deleted_at IS NULL
+            ORDER BY v.version DESC
+        "#,
+        )
+        .bind(object_id)
+        .bind(user_id)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(versions)
+    }
+
+    pub async fn fetch_object_version(
+        &self,
+        user_id: Uuid,
+        object_id: Uuid,
+        version: i32,
+    ) -> anyhow::Result<ObjectVersion> {
+        let version = sqlx::query_as(
+            r#"
+            SELECT v.* FROM object_versions AS v
+            JOIN cdn_objects AS o ON o.id = v.object_id
+            WHERE o.id = $1 AND o.user_id = $2 AND o.deleted_at IS NULL AND v.version = $3
+        "#,
+        )
+        .bind(object_id)
+        .bind(user_id)
+        .bind(version)
+        .fetch_one(&self.inner)
+        .await?;
+        Ok(version)
     }
 
-    pub async fn delete_cdn_objects(
+    /// Marks the objects as deleted and queues everything they own for the
+    /// deletion worker, returns the objects that were marked. Storage isn't
+    /// touched, so this only fails together with the rest of the transaction.
+    pub async fn schedule_object_deletion(
         &self,
         user_id: Uuid,
         objects: &Vec<Uuid>,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<Vec<CdnObject>> {
+        let deleted: Vec<CdnObject> = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET deleted_at = NOW(), is_public = false
+            WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL
+            RETURNING *
+        "#,
+        )
+        .bind(user_id)
+        .bind(objects)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        // links of deleted objects answer 410 and can't be claimed again
+        for obj in &deleted {
+            if let Some(slug) = &obj.slug {
+                Self::retire_slug(slug, obj.id, &mut *conn).await?;
+            }
+        }
+
+        // every version holds a reference on its blob
+        let ids = deleted.iter().map(|obj| obj.id).collect::<Vec<_>>();
+        let versions: Vec<(Uuid, Option<String>, String)> = sqlx::query_as(
+            "SELECT object_id, blob_hash, storage_pool FROM object_versions WHERE object_id = ANY($1)",
+        )
+        .bind(&ids)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let hashes = versions
+            .iter()
+            .filter_map(|(_, hash, _)| hash.clone())
+            .collect::<Vec<_>>();
+        let released = Self::release_blobs(&hashes, &mut *conn).await?;
+
+        let mut entries = Vec::new();
+        for obj in &deleted {
+            entries.extend(rendered_deletions(obj.user_id, obj.id, &obj.storage_pool));
+
+            let legacy_version = versions
+                .iter()
+                .find(|(id, hash, _)| *id == obj.id && hash.is_none());
+            if let Some((_, _, storage_pool)) = legacy_version {
+                entries.push((
+                    OUTBOX_OBJECT,
+                    Some(obj.id),
+                    object_key(obj.user_id, obj.id),
+                    Some(storage_pool.clone()),
+                ));
+            }
+
+            if obj.flags & COF_SEARCHABLE != 0 {
+                entries.push((OUTBOX_SEARCH_DOCUMENT, Some(obj.id), obj.id.to_string(), None));
+            }
+        }
+        // the deletion worker looks up which pool a blob is in when it removes it
+        for hash in released {
+            entries.push((OUTBOX_BLOB, None, hash, None));
+        }
+
+        Self::enqueue_deletions(&entries, conn).await?;
+        Ok(deleted)
+    }
+
+    /// Queues `(kind, object_id, target, storage_pool)` entries for the deletion worker
+    async fn enqueue_deletions(
+        entries: &[(&str, Option<Uuid>, String, Option<String>)],
+        conn: &mut PgConnection,
     ) -> anyhow::Result<()> {
-        sqlx::query!(
-            "DELETE FROM cdn_objects WHERE user_id = $1 AND id = ANY($2)",
+        let kinds = entries.iter().map(|(kind, ..)| *kind).collect::<Vec<_>>();
+        let object_ids = entries.iter().map(|(_, id, ..)| *id).collect::<Vec<_>>();
+        let targets = entries
+            .iter()
+            .map(|(_, _, target, _)| target.as_str())
+            .collect::<Vec<_>>();
+        let pools = entries
+            .iter()
+            .map(|(.., pool)| pool.as_deref())
+            .collect::<Vec<_>>();
+
+        sqlx::query(
+            r#"
+            INSERT INTO deletion_outbox (kind, object_id, target, storage_pool)
+            SELECT * FROM UNNEST($1::VARCHAR[], $2::UUID[], $3::TEXT[], $4::VARCHAR[])
+        "#,
+        )
+        .bind(&kinds)
+        .bind(&object_ids)
+        .bind(&targets)
+        .bind(&pools)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Drops one reference per hash (a hash can appear more than once) and
+    /// returns the hashes nobody references anymore. Their rows stay until the
+    /// deletion worker removes the content, an upload of the same content in
+    /// the meantime just takes a reference again.
+    async fn release_blobs(
+        hashes: &[String],
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<Vec<String>> {
+        let released: Vec<String> = sqlx::query_scalar(
+            r#"
+            WITH updated AS (
+                UPDATE blobs SET ref_count = blobs.ref_count - released.count
+                FROM (SELECT hash, COUNT(1) AS count FROM UNNEST($1::TEXT[]) AS hash GROUP BY hash) AS released
+                WHERE blobs.sha256_hash = released.hash
+                RETURNING blobs.sha256_hash, blobs.ref_count
+            )
+            SELECT sha256_hash FROM updated WHERE ref_count <= 0
+        "#,
+        )
+        .bind(hashes)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(released)
+    }
+
+    /// Publishes an object under `slug` and retires the slug it had before.
+    /// `limits` replace the ones of the link and restart its download count,
+    /// without them the link keeps its limits. Returns `None` if another
+    /// object has the slug or it was retired, `ObjectNotFound` if the object
+    /// is gone.
+    pub async fn assign_slug(
+        &self,
+        user_id: Uuid,
+        object_id: Uuid,
+        slug: &str,
+        limits: Option<LinkLimits>,
+        action: &str,
+    ) -> anyhow::Result<Option<CdnObject>> {
+        let mut trans = self.inner.begin().await?;
+        let old_slug: Option<Option<String>> = sqlx::query_scalar(
+            "SELECT slug FROM cdn_objects WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL FOR UPDATE",
+        )
+        .bind(object_id)
+        .bind(user_id)
+        .fetch_optional(&mut *trans)
+        .await?;
+        // deleted since the caller looked it up
+        let Some(old_slug) = old_slug else {
+            return Err(ObjectNotFound.into());
+        };
+
+        if Self::slug_is_retired(slug, &mut trans).await? {
+            return Ok(None);
+        }
+
+        let replace_limits = limits.is_some();
+        let limits = limits.unwrap_or_default();
+        let result = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET
+                slug = $1,
+                is_public = true,
+                link_expires_at = CASE WHEN $3 THEN $4 ELSE link_expires_at END,
+                link_max_downloads = CASE WHEN $3 THEN $5 ELSE link_max_downloads END,
+                link_downloads = CASE WHEN $3 THEN 0 ELSE link_downloads END,
+                link_spent_at = CASE WHEN $3 THEN NULL ELSE link_spent_at END,
+                delete_when_spent = CASE WHEN $3 THEN $6 ELSE delete_when_spent END,
+                link_password_hash = CASE WHEN $3 THEN $7 ELSE link_password_hash END
+            WHERE id = $2
+            RETURNING *
+        "#,
+        )
+        .bind(slug)
+        .bind(object_id)
+        .bind(replace_limits)
+        .bind(limits.expires_at)
+        .bind(limits.max_downloads)
+        .bind(limits.delete_when_spent)
+        .bind(limits.password_hash)
+        .fetch_one(&mut *trans)
+        .await;
+        let object: CdnObject = match result {
+            Ok(v) => v,
+            Err(sqlx::Error::Database(error)) if error.is_unique_violation() => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        if let Some(old_slug) = &old_slug {
+            Self::retire_slug(old_slug, object_id, &mut trans).await?;
+        }
+        Self::audit_link(
             user_id,
-            objects
+            object_id,
+            action,
+            old_slug.as_deref(),
+            Some(slug),
+            &mut trans,
         )
-        .execute(&self.inner)
         .await?;
 
-        Ok(())
+        trans.commit().await?;
+        Ok(Some(object))
     }
 
-    pub async fn create_slug_and_publish(&self, object_id: Uuid) -> anyhow::Result<String> {
-        let slug_num = object_id.as_fields().0;
-        let slug = format!("{:x}", slug_num);
+    /// Takes an object's link down, its slug is retired so it answers 410.
+    /// Fails with `ObjectNotFound` if the object is gone.
+    pub async fn unpublish_object(
+        &self,
+        user_id: Uuid,
+        object_id: Uuid,
+    ) -> anyhow::Result<CdnObject> {
+        let mut trans = self.inner.begin().await?;
+        let old_slug: Option<Option<String>> = sqlx::query_scalar(
+            "SELECT slug FROM cdn_objects WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL FOR UPDATE",
+        )
+        .bind(object_id)
+        .bind(user_id)
+        .fetch_optional(&mut *trans)
+        .await?;
+        // deleted since the caller looked it up
+        let Some(old_slug) = old_slug else {
+            return Err(ObjectNotFound.into());
+        };
 
-        sqlx::query!(
-            "UPDATE cdn_objects SET slug = $1, is_public = true WHERE id = $2",
-            slug.to_owned(),
-            object_id
+        // the limits go too, an unpublished object must not be deleted for them
+        let object: CdnObject = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET
+                slug = NULL,
+                is_public = false,
+                link_expires_at = NULL,
+                link_max_downloads = NULL,
+                link_downloads = 0,
+                link_spent_at = NULL,
+                delete_when_spent = false,
+                link_password_hash = NULL
+            WHERE id = $1
+            RETURNING *
+        "#,
         )
-        .execute(&self.inner)
+        .bind(object_id)
+        .fetch_one(&mut *trans)
+        .await?;
+
+        if let Some(old_slug) = &old_slug {
+            Self::retire_slug(old_slug, object_id, &mut trans).await?;
+        }
+        Self::audit_link(
+            user_id,
+            object_id,
+            LINK_UNPUBLISHED,
+            old_slug.as_deref(),
+            None,
+            &mut trans,
+        )
+        .await?;
+
+        trans.commit().await?;
+        Ok(object)
+    }
+
+    /// Counts a download through the public link of an object, `None` once the
+    /// link expired or ran out of downloads. The check and the count are one
+    /// statement, so concurrent requests can't exceed the limit.
+    pub async fn claim_link_download(&self, object_id: Uuid) -> anyhow::Result<Option<CdnObject>> {
+        let object = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET
+                link_downloads = link_downloads + 1,
+                link_spent_at = CASE
+                    WHEN link_downloads + 1 >= link_max_downloads THEN NOW()
+                    ELSE link_spent_at
+                END
+            WHERE id = $1 AND is_public AND deleted_at IS NULL
+                AND (link_expires_at IS NULL OR link_expires_at > NOW())
+                AND (link_max_downloads IS NULL OR link_downloads < link_max_downloads)
+            RETURNING *
+        "#,
+        )
+        .bind(object_id)
+        .fetch_optional(&self.inner)
+        .await?;
+        Ok(object)
+    }
+
+    /// Whether a cached lookup of `slug` still holds, that is the link wasn't
+    /// unpublished, rotated or changed by another instance since
+    pub async fn is_slug_current(&self, slug: &str, obj: &CdnObject) -> anyhow::Result<bool> {
+        let current = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM cdn_objects
+                WHERE id = $1 AND slug = $2 AND is_public AND deleted_at IS NULL
+                    AND sha256_hash = $3
+                    AND link_password_hash IS NOT DISTINCT FROM $4
+                    AND link_expires_at IS NOT DISTINCT FROM $5
+                    AND link_max_downloads IS NOT DISTINCT FROM $6
+            )
+        "#,
+        )
+        .bind(obj.id)
+        .bind(slug)
+        .bind(&obj.sha256_hash)
+        .bind(&obj.link_password_hash)
+        .bind(obj.link_expires_at)
+        .bind(obj.link_max_downloads)
+        .fetch_one(&self.inner)
         .await?;
+        Ok(current)
+    }
 
-        Ok(slug)
+    /// The object behind a public link for requests that don't count as a
+    /// download, `None` once the link expired or was spent
+    pub async fn fetch_usable_link(&self, object_id: Uuid) -> anyhow::Result<Option<CdnObject>> {
+        let object = sqlx::query_as(
+            r#"
+            SELECT * FROM cdn_objects
+            WHERE id = $1 AND is_public AND deleted_at IS NULL
+                AND (link_expires_at IS NULL OR link_expires_at > NOW())
+                AND link_spent_at IS NULL
+        "#,
+        )
+        .bind(object_id)
+        .fetch_optional(&self.inner)
+        .await?;
+        Ok(object)
+    }
+
+    /// Public objects to be deleted because their link expired or ran out of
+    /// downloads more than `grace_secs` ago
+    pub async fn list_spent_links(&self, grace_secs: i64) -> anyhow::Result<Vec<CdnObject>> {
+        let objects = sqlx::query_as(
+            r#"
+            SELECT * FROM cdn_objects
+            WHERE delete_when_spent AND is_public AND deleted_at IS NULL
+                AND LEAST(link_expires_at, link_spent_at) < NOW() - make_interval(secs => $1)
+        "#,
+        )
+        .bind(grace_secs as f64)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(objects)
+    }
+
+    /// Whether a slug was taken down, retired slugs are never handed out again
+    pub async fn is_slug_retired(&self, slug: &str) -> anyhow::Result<bool> {
+        let mut conn = self.inner.acquire().await?;
+        Self::slug_is_retired(slug, &mut conn).await
+    }
+
+    async fn slug_is_retired(slug: &str, conn: &mut PgConnection) -> anyhow::Result<bool> {
+        let retired =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM retired_slugs WHERE slug = $1)")
+                .bind(slug)
+                .fetch_one(conn)
+                .await?;
+        Ok(retired)
+    }
+
+    async fn retire_slug(
+        slug: &str,
+        object_id: Uuid,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO retired_slugs (slug, object_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(slug)
+        .bind(object_id)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn audit_link(
+        user_id: Uuid,
+        object_id: Uuid,
+        action: &str,
+        old_slug: Option<&str>,
+        new_slug: Option<&str>,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO link_audit_log (user_id, object_id, action, old_slug, new_slug)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+        )
+        .bind(user_id)
+        .bind(object_id)
+        .bind(action)
+        .bind(old_slug)
+        .bind(new_slug)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// The slug and link changes of an object, newest first
+    pub async fn list_link_audit(
+        &self,
+        user_id: Uuid,
+        object_id: Uuid,
+    ) -> anyhow::Result<Vec<LinkAuditEntry>> {
+        let entries = sqlx::query_as(
+            "SELECT * FROM link_audit_log WHERE user_id = $1 AND object_id = $2 ORDER BY id DESC",
+        )
+        .bind(user_id)
+        .bind(object_id)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(entries)
+    }
+
+    pub async fn create_upload_session(
+        &self,
+        session: &UploadSession,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO upload_sessions (id, user_id, object_id, multipart_id, upload_length, upload_offset, parts, content_type, file_name, storage_pool, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+        "#,
+        )
+        .bind(session.id)
+        .bind(session.user_id)
+        .bind(session.object_id)
+        .bind(&session.multipart_id)
+        .bind(session.upload_length)
+        .bind(session.upload_offset)
+        .bind(&session.parts)
+        .bind(&session.content_type)
+        .bind(&session.file_name)
+        .bind(&session.storage_pool)
+        .bind(session.expires_at)
+        .bind(session.created_at)
+        .execute(&self.inner)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn fetch_upload_session(
+        &self,
+        user_id: Uuid,
+        upload_id: Uuid,
+    ) -> anyhow::Result<UploadSession> {
+        let session: UploadSession = sqlx::query_as(
+            "SELECT * FROM upload_sessions WHERE user_id = $1 AND id = $2",
+        )
+        .bind(user_id)
+        .bind(upload_id)
+        .fetch_one(&self.inner)
+        .await?;
+        Ok(session)
+    }
+
+    /// Saves the progress of an upload, `parts` must only hold flushed parts
+    pub async fn update_upload_session(
+        &self,
+        upload_id: Uuid,
+        upload_offset: i64,
+        parts: &Json<Vec<UploadPart>>,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE upload_sessions SET upload_offset = $1, parts = $2, expires_at = $3 WHERE id = $4",
+        )
+        .bind(upload_offset)
+        .bind(parts)
+        .bind(expires_at)
+        .bind(upload_id)
+        .execute(&self.inner)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes resumable uploads that expired and returns them, so what they
+    /// staged in storage can be dropped
+    pub async fn take_expired_upload_sessions(&self) -> anyhow::Result<Vec<UploadSession>> {
+        let sessions =
+            sqlx::query_as("DELETE FROM upload_sessions WHERE expires_at < NOW() RETURNING *")
+                .fetch_all(&self.inner)
+                .await?;
+        Ok(sessions)
+    }
+
+    /// Records how far finishing an upload got, so a retry skips what's done
+    pub async fn update_upload_completion(
+        &self,
+        upload_id: Uuid,
+        sha256_hash: Option<&str>,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE upload_sessions SET completing = TRUE, sha256_hash = $1 WHERE id = $2")
+            .bind(sha256_hash)
+            .bind(upload_id)
+            .execute(&self.inner)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_upload_session(
+        &self,
+        upload_id: Uuid,
+        conn: Option<&mut PgConnection>,
+    ) -> anyhow::Result<()> {
+        let query = sqlx::query("DELETE FROM upload_sessions WHERE id = $1").bind(upload_id);
+
+        if let Some(conn) = conn {
+            query.execute(conn).await?;
+        } else {
+            query.execute(&self.inner).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn create_upload_reservation(
+        &self,
+        reservation: &UploadReservation,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO upload_reservations (id, user_id, content_type, content_size, file_name, sha256_hash, multipart_id, storage_pool, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+        )
+        .bind(reservation.id)
+        .bind(reservation.user_id)
+        .bind(&reservation.content_type)
+        .bind(reservation.content_size)
+        .bind(&reservation.file_name)
+        .bind(&reservation.sha256_hash)
+        .bind(&reservation.multipart_id)
+        .bind(&reservation.storage_pool)
+        .bind(reservation.created_at)
+        .execute(&self.inner)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn fetch_upload_reservation(
+        &self,
+        user_id: Uuid,
+        id: Uuid,
+    ) -> anyhow::Result<UploadReservation> {
+        let reservation: UploadReservation = sqlx::query_as(
+            "SELECT * FROM upload_reservations WHERE user_id = $1 AND id = $2",
+        )
+        .bind(user_id)
+        .bind(id)
+        .fetch_one(&self.inner)
+        .await?;
+        Ok(reservation)
+    }
+
+    /// Removes the reservations that are older than `max_age_secs` and returns
+    /// them, what was uploaded for them is left to the caller
+    pub async fn take_expired_upload_reservations(
+        &self,
+        max_age_secs: i64,
+    ) -> anyhow::Result<Vec<UploadReservation>> {
+        let reservations = sqlx::query_as(
+            "DELETE FROM upload_reservations WHERE created_at < NOW() - make_interval(secs => $1) RETURNING *",
+        )
+        .bind(max_age_secs as f64)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(reservations)
+    }
+
+    pub async fn delete_upload_reservation(
+        &self,
+        id: Uuid,
+        conn: Option<&mut PgConnection>,
+    ) -> anyhow::Result<()> {
+        let query = sqlx::query("DELETE FROM upload_reservations WHERE id = $1").bind(id);
+
+        if let Some(conn) = conn {
+            query.execute(conn).await?;
+        } else {
+            query.execute(&self.inner).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn fetch_blob_key(&self, hash: &str) -> anyhow::Result<Option<WrappedKey>> {
+        let row: Option<(Option<String>, Option<Vec<u8>>)> =
+            sqlx::query_as("SELECT master_key_id, wrapped_key FROM blobs WHERE sha256_hash = $1")
+                .bind(hash)
+                .fetch_optional(&self.inner)
+                .await?;
+
+        Ok(match row {
+            Some((Some(master_key_id), Some(wrapped))) => Some(WrappedKey {
+                master_key_id,
+                wrapped,
+            }),
+            _ => None,
+        })
+    }
+
+    pub async fn fetch_vault_key(&self, user_id: Uuid) -> anyhow::Result<Option<WrappedKey>> {
+        let row: Option<(String, Vec<u8>)> =
+            sqlx::query_as("SELECT master_key_id, wrapped_key FROM vault_keys WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_optional(&self.inner)
+                .await?;
+
+        Ok(row.map(|(master_key_id, wrapped)| WrappedKey {
+            master_key_id,
+            wrapped,
+        }))
+    }
+
+    /// Stores the key of a vault unless it already has one, returns the key in use
+    pub async fn create_vault_key(
+        &self,
+        user_id: Uuid,
+        key: &WrappedKey,
+    ) -> anyhow::Result<WrappedKey> {
+        let (master_key_id, wrapped): (String, Vec<u8>) = sqlx::query_as(
+            r#"
+            INSERT INTO vault_keys (user_id, master_key_id, wrapped_key)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE SET user_id = vault_keys.user_id
+            RETURNING master_key_id, wrapped_key
+        "#,
+        )
+        .bind(user_id)
+        .bind(&key.master_key_id)
+        .bind(&key.wrapped)
+        .fetch_one(&self.inner)
+        .await?;
+
+        Ok(WrappedKey {
+            master_key_id,
+            wrapped,
+        })
+    }
+
+    /// Blob keys not wrapped by `master_key_id`, at most `limit` of them
+    pub async fn list_stale_blob_keys(
+        &self,
+        master_key_id: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(String, WrappedKey)>> {
+        let rows: Vec<(String, String, Vec<u8>)> = sqlx::query_as(
+            r#"
+            SELECT sha256_hash, master_key_id, wrapped_key FROM blobs
+            WHERE master_key_id IS NOT NULL AND master_key_id <> $1
+            LIMIT $2
+        "#,
+        )
+        .bind(master_key_id)
+        .bind(limit)
+        .fetch_all(&self.inner)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(hash, master_key_id, wrapped)| {
+                (
+                    hash,
+                    WrappedKey {
+                        master_key_id,
+                        wrapped,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    /// Replaces a blob key, unless it changed since it was read
+    pub async fn update_blob_key(
+        &self,
+        hash: &str,
+        old: &WrappedKey,
+        new: &WrappedKey,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE blobs SET master_key_id = $2, wrapped_key = $3
+            WHERE sha256_hash = $1 AND wrapped_key = $4
+        "#,
+        )
+        .bind(hash)
+        .bind(&new.master_key_id)
+        .bind(&new.wrapped)
+        .bind(&old.wrapped)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
+    }
+
+    /// Vault keys not wrapped by `master_key_id`, at most `limit` of them
+    pub async fn list_stale_vault_keys(
+        &self,
+        master_key_id: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<(Uuid, WrappedKey)>> {
+        let rows: Vec<(Uuid, String, Vec<u8>)> = sqlx::query_as(
+            "SELECT user_id, master_key_id, wrapped_key FROM vault_keys WHERE master_key_id <> $1 LIMIT $2",
+        )
+        .bind(master_key_id)
+        .bind(limit)
+        .fetch_all(&self.inner)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(user_id, master_key_id, wrapped)| {
+                (
+                    user_id,
+                    WrappedKey {
+                        master_key_id,
+                        wrapped,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    pub async fn update_vault_key(&self, user_id: Uuid, key: &WrappedKey) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE vault_keys SET master_key_id = $2, wrapped_key = $3 WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .bind(&key.master_key_id)
+        .bind(&key.wrapped)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
+    }
+
+    /// Every version of every object as `(user_id, id, blob_hash, storage_pool)`,
+    /// used to reconcile storage
+    pub async fn list_object_refs(
+        &self,
+    ) -> anyhow::Result<Vec<(Uuid, Uuid, Option<String>, String)>> {
+        let refs = sqlx::query_as(
+            r#"
+            SELECT o.user_id, o.id, v.blob_hash, v.storage_pool FROM cdn_objects AS o
+            JOIN object_versions AS v ON v.object_id = o.id
+            WHERE o.deleted_at IS NULL
+        "#,
+        )
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(refs)
+    }
+
+    /// Every blob as `(sha256_hash, storage_pool)`
+    pub async fn list_blob_hashes(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let hashes = sqlx::query_as("SELECT sha256_hash, storage_pool FROM blobs")
+            .fetch_all(&self.inner)
+            .await?;
+        Ok(hashes)
+    }
+
+    /// Objects that are still being uploaded as `(upload_id, object_id, storage_pool)`,
+    /// their content is staged under the object key and the upload sessions
+    /// keep a pending tail around
+    pub async fn list_pending_uploads(&self) -> anyhow::Result<Vec<(Uuid, Uuid, String)>> {
+        let pending = sqlx::query_as(
+            r#"
+            SELECT id, object_id, storage_pool FROM upload_sessions
+            UNION ALL
+            SELECT id, id, storage_pool FROM upload_reservations
+        "#,
+        )
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(pending)
+    }
+
+    /// Claims outbox entries that are due by pushing their next attempt
+    /// `lease_secs` out, so other workers and `cancel_pool_deletions` leave
+    /// them alone while they're processed. One that is never completed or
+    /// failed comes up again once the lease runs out.
+    pub async fn claim_due_outbox_entries(
+        &self,
+        kinds: &[&str],
+        limit: i64,
+        lease_secs: i64,
+    ) -> anyhow::Result<Vec<OutboxEntry>> {
+        let entries = sqlx::query_as(
+            r#"
+            UPDATE deletion_outbox SET next_attempt_at = NOW() + make_interval(secs => $3)
+            WHERE id IN (
+                SELECT id FROM deletion_outbox
+                WHERE kind = ANY($1) AND next_attempt_at <= NOW()
+                ORDER BY id
+                LIMIT $2
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+        "#,
+        )
+        .bind(kinds)
+        .bind(limit)
+        .bind(lease_secs as f64)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(entries)
+    }
+
+    pub async fn complete_outbox_entry(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM deletion_outbox WHERE id = $1")
+            .bind(id)
+            .execute(&self.inner)
+            .await?;
+        Ok(())
+    }
+
+    /// Pushes the next attempt back exponentially, capped at `max_backoff_secs`
+    pub async fn fail_outbox_entry(
+        &self,
+        id: i64,
+        error: &str,
+        max_backoff_secs: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE deletion_outbox SET
+                attempts = attempts + 1,
+                last_error = $2,
+                next_attempt_at = NOW() + make_interval(secs => LEAST(POWER(2, LEAST(attempts, 30)), $3))
+            WHERE id = $1
+        "#,
+        )
+        .bind(id)
+        .bind(error)
+        .bind(max_backoff_secs as f64)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
+    }
+
+    /// Locks the row of a released blob and returns its pool, `None` if the
+    /// blob is gone already or an upload took a reference on it again. Fails
+    /// while an upload holds a claim on it and is still writing the content.
+    pub async fn lock_released_blob(
+        &self,
+        hash: &str,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<Option<String>> {
+        let row: Option<(i64, String, bool)> = sqlx::query_as(
+            r#"
+            SELECT ref_count, storage_pool, COALESCE(claimed_until > NOW(), FALSE)
+            FROM blobs WHERE sha256_hash = $1 FOR UPDATE
+        "#,
+        )
+        .bind(hash)
+        .fetch_optional(conn)
+        .await?;
+        match row {
+            Some((_, _, true)) => anyhow::bail!("blob {hash} is still being written"),
+            Some((ref_count, storage_pool, false)) if ref_count <= 0 => Ok(Some(storage_pool)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether any row, including ones waiting for deletion, still points at the blob
+    pub async fn blob_has_objects(
+        &self,
+        hash: &str,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<bool> {
+        let has_objects = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM cdn_objects WHERE blob_hash = $1)
+                OR EXISTS(SELECT 1 FROM object_versions WHERE blob_hash = $1)
+        "#,
+        )
+        .bind(hash)
+        .fetch_one(conn)
+        .await?;
+        Ok(has_objects)
+    }
+
+    pub async fn delete_blob(&self, hash: &str, conn: &mut PgConnection) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM blobs WHERE sha256_hash = $1")
+            .bind(hash)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes deleted objects that have nothing left in the outbox
+    pub async fn purge_deleted_objects(&self) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM cdn_objects AS obj
+            WHERE obj.deleted_at IS NOT NULL
+                AND NOT EXISTS (SELECT 1 FROM deletion_outbox WHERE object_id = obj.id)
+        "#,
+        )
+        .execute(&self.inner)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// The object whose content was verified longest ago, skipping ones
+    /// verified after `verified_before`
+    pub async fn next_object_to_scrub(
+        &self,
+        verified_before: NaiveDateTime,
+    ) -> anyhow::Result<Option<CdnObject>> {
+        let object = sqlx::query_as(
+            r#"
+            SELECT * FROM cdn_objects
+            WHERE deleted_at IS NULL AND (verified_at IS NULL OR verified_at < $1)
+            ORDER BY verified_at NULLS FIRST
+            LIMIT 1
+        "#,
+        )
+        .bind(verified_before)
+        .fetch_optional(&self.inner)
+        .await?;
+        Ok(object)
+    }
+
+    /// Records a scrub, unless the object got new content while it was read
+    pub async fn record_scrub(
+        &self,
+        object_id: Uuid,
+        sha256_hash: &str,
+        error: Option<&str>,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE cdn_objects SET verified_at = NOW(), integrity_error = $3
+            WHERE id = $1 AND sha256_hash = $2
+        "#,
+        )
+        .bind(object_id)
+        .bind(sha256_hash)
+        .bind(error)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
+    }
+
+    /// Records that the object couldn't be scrubbed at all, it's listed with
+    /// the failures and comes up again once `verified_at` is due
+    pub async fn record_scrub_error(
+        &self,
+        object_id: Uuid,
+        error: &str,
+        verified_at: NaiveDateTime,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE cdn_objects SET verified_at = $2, integrity_error = $3 WHERE id = $1")
+            .bind(object_id)
+            .bind(verified_at)
+            .bind(error)
+            .execute(&self.inner)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_integrity_failures(&self) -> anyhow::Result<Vec<CdnObject>> {
+        let objects = sqlx::query_as(
+            r#"
+            SELECT * FROM cdn_objects
+            WHERE deleted_at IS NULL AND integrity_error IS NOT NULL
+            ORDER BY verified_at DESC
+        "#,
+        )
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(objects)
+    }
+
+    /// Counts of `(verified, never verified, failed)` objects
+    pub async fn integrity_summary(&self) -> anyhow::Result<(i64, i64, i64)> {
+        let summary = sqlx::query_as(
+            r#"
+            SELECT
+                COUNT(1) FILTER (WHERE verified_at IS NOT NULL AND integrity_error IS NULL),
+                COUNT(1) FILTER (WHERE verified_at IS NULL),
+                COUNT(1) FILTER (WHERE integrity_error IS NOT NULL)
+            FROM cdn_objects
+            WHERE deleted_at IS NULL
+        "#,
+        )
+        .fetch_one(&self.inner)
+        .await?;
+        Ok(summary)
+    }
+
+    /// Queues an object to be scrubbed next
+    pub async fn reset_verification(&self, object_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cdn_objects SET verified_at = NULL WHERE id = $1 AND deleted_at IS NULL",
+        )
+        .bind(object_id)
+        .execute(&self.inner)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Referenced blobs stored in a pool, only ones used by objects of `user_id` if given
+    pub async fn list_pool_blobs(
+        &self,
+        storage_pool: &str,
+        user_id: Option<Uuid>,
+    ) -> anyhow::Result<Vec<String>> {
+        let hashes = sqlx::query_scalar(
+            r#"
+            SELECT b.sha256_hash FROM blobs AS b
+            WHERE b.storage_pool = $1 AND b.ref_count > 0 AND ($2::UUID IS NULL OR EXISTS(
+                SELECT 1 FROM object_versions AS v
+                JOIN cdn_objects AS o ON o.id = v.object_id
+                WHERE v.blob_hash = b.sha256_hash AND o.user_id = $2
+            ))
+        "#,
+        )
+        .bind(storage_pool)
+        .bind(user_id)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(hashes)
+    }
+
+    /// Objects with content uploaded before deduplication stored in a pool, as `(user_id, id)`
+    pub async fn list_pool_legacy_objects(
+        &self,
+        storage_pool: &str,
+        user_id: Option<Uuid>,
+    ) -> anyhow::Result<Vec<(Uuid, Uuid)>> {
+        let objects = sqlx::query_as(
+            r#"
+            SELECT DISTINCT o.user_id, o.id FROM cdn_objects AS o
+            JOIN object_versions AS v ON v.object_id = o.id
+            WHERE v.blob_hash IS NULL AND v.storage_pool = $1 AND o.deleted_at IS NULL
+                AND ($2::UUID IS NULL OR o.user_id = $2)
+        "#,
+        )
+        .bind(storage_pool)
+        .bind(user_id)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(objects)
+    }
+
+    /// Points a blob and everything using it at the pool its content was copied
+    /// to, then queues the old copy and the thumbnails stored next to it for
+    /// deletion. False if the blob left `from` or was released in the meantime.
+    /// Drops the queued deletions of `key` in `storage_pool`, which moving
+    /// content out of the pool left behind, so content moved back in stays.
+    /// `false` if one may be running right now, the key can't be moved then.
+    pub async fn cancel_pool_deletions(
+        &self,
+        key: &str,
+        storage_pool: &str,
+    ) -> anyhow::Result<bool> {
+        let mut trans = self.inner.begin().await?;
+        // entries the deletion worker claimed aren't due until their lease runs out
+        let due: Vec<bool> = sqlx::query_scalar(
+            r#"
+            SELECT next_attempt_at <= NOW() FROM deletion_outbox
+            WHERE kind = $1 AND target = $2 AND storage_pool = $3
+            FOR UPDATE
+        "#,
+        )
+        .bind(OUTBOX_OBJECT)
+        .bind(key)
+        .bind(storage_pool)
+        .fetch_all(&mut *trans)
+        .await?;
+        if due.iter().any(|due| !due) {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            "DELETE FROM deletion_outbox WHERE kind = $1 AND target = $2 AND storage_pool = $3",
+        )
+        .bind(OUTBOX_OBJECT)
+        .bind(key)
+        .bind(storage_pool)
+        .execute(&mut *trans)
+        .await?;
+        trans.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn move_blob(
+        &self,
+        hash: &str,
+        from: &str,
+        to: &str,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<bool> {
+        // uploads referencing the blob and the deletion worker wait for this lock
+        let ref_count: Option<i64> = sqlx::query_scalar(
+            "SELECT ref_count FROM blobs WHERE sha256_hash = $1 AND storage_pool = $2 FOR UPDATE",
+        )
+        .bind(hash)
+        .bind(from)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if ref_count.is_none_or(|count| count <= 0) {
+            return Ok(false);
+        }
+
+        sqlx::query("UPDATE blobs SET storage_pool = $2 WHERE sha256_hash = $1")
+            .bind(hash)
+            .bind(to)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("UPDATE object_versions SET storage_pool = $2 WHERE blob_hash = $1")
+            .bind(hash)
+            .bind(to)
+            .execute(&mut *conn)
+            .await?;
+        let objects: Vec<(Uuid, Uuid)> = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET storage_pool = $2
+            WHERE blob_hash = $1 AND deleted_at IS NULL
+            RETURNING user_id, id
+        "#,
+        )
+        .bind(hash)
+        .bind(to)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut entries = vec![(OUTBOX_OBJECT, None, blob_key(hash), Some(from.to_owned()))];
+        for (user_id, id) in objects {
+            entries.extend(rendered_deletions(user_id, id, from));
+        }
+
+        Self::enqueue_deletions(&entries, conn).await?;
+        Ok(true)
+    }
+
+    /// [`PgClient::move_blob`] for content kept in the vault of its object
+    pub async fn move_legacy_object(
+        &self,
+        user_id: Uuid,
+        object_id: Uuid,
+        from: &str,
+        to: &str,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<bool> {
+        let locked: Option<Uuid> = sqlx::query_scalar(
+            "SELECT id FROM cdn_objects WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+        )
+        .bind(object_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if locked.is_none() {
+            return Ok(false);
+        }
+
+        let versions = sqlx::query(
+            r#"
+            UPDATE object_versions SET storage_pool = $3
+            WHERE object_id = $1 AND blob_hash IS NULL AND storage_pool = $2
+        "#,
+        )
+        .bind(object_id)
+        .bind(from)
+        .bind(to)
+        .execute(&mut *conn)
+        .await?;
+        if versions.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        let mut entries = vec![(
+            OUTBOX_OBJECT,
+            None,
+            object_key(user_id, object_id),
+            Some(from.to_owned()),
+        )];
+
+        // the row only follows when the legacy content is the current one
+        let current = sqlx::query(
+            r#"
+            UPDATE cdn_objects SET storage_pool = $3
+            WHERE id = $1 AND blob_hash IS NULL AND storage_pool = $2
+        "#,
+        )
+        .bind(object_id)
+        .bind(from)
+        .bind(to)
+        .execute(&mut *conn)
+        .await?;
+        if current.rows_affected() > 0 {
+            entries.extend(rendered_deletions(user_id, object_id, from));
+        }
+
+        Self::enqueue_deletions(&entries, conn).await?;
+        Ok(true)
+    }
+
+    /// Leases images whose thumbnails still have to be rendered, oldest first,
+    /// and counts an attempt for each
+    pub async fn claim_pending_thumbnails(
+        &self,
+        lease_secs: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<CdnObject>> {
+        // the lease keeps other workers off the objects, and makes ones that
+        // failed come up again once it runs out
+        let objects = sqlx::query_as(
+            r#"
+            UPDATE cdn_objects SET
+                thumbnail_attempts = thumbnail_attempts + 1,
+                thumbnail_leased_until = NOW() + make_interval(secs => $3)
+            WHERE id IN (
+                SELECT id FROM cdn_objects
+                WHERE (flags & $1) = $1 AND (flags & $2) = 0 AND deleted_at IS NULL
+                    AND (thumbnail_leased_until IS NULL OR thumbnail_leased_until <= NOW())
+                ORDER BY uploaded_at
+                LIMIT $4
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+        "#,
+        )
+        .bind(COF_THUMBNAILABLE)
+        .bind(COF_THUMBNAILED)
+        .bind(lease_secs as f64)
+        .bind(limit)
+        .fetch_all(&self.inner)
+        .await?;
+        Ok(objects)
+    }
+
+    /// Records that the thumbnails of an object were rendered, or that they
+    /// can't be when `rendered` is false. Does nothing if the object got new
+    /// content in the meantime.
+    pub async fn finish_thumbnails(
+        &self,
+        object_id: Uuid,
+        sha256_hash: &str,
+        rendered: bool,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE cdn_objects
+            SET flags = CASE WHEN $3 THEN flags | $4 ELSE flags & ~$5::BIGINT END
+            WHERE id = $1 AND sha256_hash = $2
+        "#,
+        )
+        .bind(object_id)
+        .bind(sha256_hash)
+        .bind(rendered)
+        .bind(COF_THUMBNAILED)
+        .bind(COF_THUMBNAILABLE)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
+    }
+
+    /// Queues the thumbnails of an object to be rendered again
+    pub async fn reset_thumbnails(&self, object_id: Uuid, sha256_hash: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE cdn_objects SET
+                flags = flags & ~$3::BIGINT,
+                thumbnail_attempts = 0,
+                thumbnail_leased_until = NULL
+            WHERE id = $1 AND sha256_hash = $2
+        "#,
+        )
+        .bind(object_id)
+        .bind(sha256_hash)
+        .bind(COF_THUMBNAILED)
+        .execute(&self.inner)
+        .await?;
+        Ok(())
     }
 }
@@ -0,0 +1,344 @@
+//! Resizing and re-encoding of images, used for thumbnails and for the
+//! variants requested through image urls
+
+use std::{
+    collections::HashMap,
+    env,
+    io::Cursor,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use fast_image_resize::{images::Image, PixelType, ResizeOptions, Resizer};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder},
+    DynamicImage, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageReader,
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, Semaphore};
+
+use crate::pdf;
+
+/// Images above this size aren't transformed, decoding them takes too much memory
+pub const MAX_SOURCE_SIZE: i64 = 64 * 1024 * 1024;
+
+/// Variants rendered at once, each takes a core and holds the decoded image
+const MAX_RENDERS: usize = 4;
+
+/// Content types variants can be rendered from
+const SOURCE_TYPES: [&str; 5] = [
+    "image/jpeg",
+    "image/jpg",
+    "image/png",
+    "image/webp",
+    "image/gif",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Fill the box, cropping what sticks out
+    Cover,
+    /// Fit the whole image into the box
+    Contain,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Webp,
+    Avif,
+    Png,
+    Jpeg,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "webp" => Some(Format::Webp),
+            "avif" => Some(Format::Avif),
+            "png" => Some(Format::Png),
+            "jpeg" | "jpg" => Some(Format::Jpeg),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Webp => "image/webp",
+            Format::Avif => "image/avif",
+            Format::Png => "image/png",
+            Format::Jpeg => "image/jpeg",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Transform {
+    /// A missing side follows from the aspect ratio of the source
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: Format,
+    pub quality: u8,
+}
+
+impl Transform {
+    /// Names the variant in storage keys and entity tags
+    pub fn spec(&self) -> String {
+        let fit = match self.fit {
+            Fit::Cover => "cover",
+            Fit::Contain => "contain",
+        };
+        format!(
+            "{}x{}-{fit}-q{}.{}",
+            self.width.unwrap_or(0),
+            self.height.unwrap_or(0),
+            self.quality,
+            self.format.extension()
+        )
+    }
+
+    /// Names the variant of content with the sha256 `hash` in storage
+    pub fn variant_name(&self, hash: &str) -> String {
+        format!("{hash}-{}", self.spec())
+    }
+
+    /// The size of the variant of a `width` x `height` source
+    fn target_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (src_w, src_h) = (width as f64, height as f64);
+        let scaled = |v: f64| (v.round() as u32).max(1);
+
+        match (self.width, self.height) {
+            (None, None) => (width, height),
+            (Some(w), None) => (w, scaled(w as f64 * src_h / src_w)),
+            (None, Some(h)) => (scaled(h as f64 * src_w / src_h), h),
+            (Some(w), Some(h)) => match self.fit {
+                Fit::Cover => (w, h),
+                Fit::Contain => {
+                    let scale = (w as f64 / src_w).min(h as f64 / src_h);
+                    (scaled(src_w * scale), scaled(src_h * scale))
+                }
+            },
+        }
+    }
+}
+
+/// The thumbnails rendered for every image, from `THUMBNAIL_SIZES`,
+/// `THUMBNAIL_FORMATS` and `THUMBNAIL_QUALITY`
+pub struct ThumbnailConfig {
+    /// Each thumbnail fits into a square box of this size
+    pub sizes: Vec<u32>,
+    /// The first one is served when no format is asked for
+    pub formats: Vec<Format>,
+    pub quality: u8,
+}
+
+impl ThumbnailConfig {
+    pub fn from_env() -> anyhow::Result<ThumbnailConfig> {
+        let var = |name: &str, default: &str| match env::var(name) {
+            Ok(v) if !v.is_empty() => v,
+            _ => default.to_owned(),
+        };
+
+        let sizes = var("THUMBNAIL_SIZES", "128,256,512,1024")
+            .split(',')
+            .map(|size| size.trim().parse())
+            .collect::<Result<Vec<u32>, _>>()?;
+        let formats = var("THUMBNAIL_FORMATS", "webp")
+            .split(',')
+            .map(|name| {
+                Format::parse(name.trim()).ok_or(anyhow!("unknown thumbnail format '{name}'"))
+            })
+            .collect::<anyhow::Result<Vec<Format>>>()?;
+        let quality = var("THUMBNAIL_QUALITY", "70").parse()?;
+
+        if sizes.is_empty() || formats.is_empty() || sizes.contains(&0) {
+            anyhow::bail!("THUMBNAIL_SIZES and THUMBNAIL_FORMATS need at least one valid entry");
+        }
+
+        Ok(ThumbnailConfig {
+            sizes,
+            formats,
+            quality,
+        })
+    }
+
+    /// The size served when no size is asked for
+    pub fn default_size(&self) -> u32 {
+        if self.sizes.contains(&256) {
+            256
+        } else {
+            self.sizes[0]
+        }
+    }
+
+    pub fn transform(&self, size: u32, format: Format) -> Transform {
+        Transform {
+            width: Some(size),
+            height: Some(size),
+            fit: Fit::Contain,
+            format,
+            quality: self.quality,
+        }
+    }
+
+    /// Every thumbnail an image gets
+    pub fn transforms(&self) -> Vec<Transform> {
+        self.sizes
+            .iter()
+            .flat_map(|size| {
+                self.formats
+                    .iter()
+                    .map(|format| self.transform(*size, *format))
+            })
+            .collect()
+    }
+}
+
+pub fn is_transformable(content_type: &str) -> bool {
+    SOURCE_TYPES.contains(&content_type)
+}
+
+/// Whether objects of this type get thumbnails, PDFs show their first page
+pub fn has_thumbnails(content_type: &str) -> bool {
+    is_transformable(content_type) || content_type == pdf::CONTENT_TYPE
+}
+
+/// The content isn't an image in a format we can read, or is corrupt
+#[derive(Debug, thiserror::Error)]
+#[error("undecodable image: {0}")]
+pub struct UndecodableImage(#[from] ImageError);
+
+/// Decodes an image and turns it upright as its EXIF orientation says
+pub fn decode(content: &[u8]) -> Result<DynamicImage, UndecodableImage> {
+    let mut decoder = ImageReader::new(Cursor::new(content))
+        .with_guessed_format()
+        .map_err(ImageError::IoError)?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Bounds how many variants are rendered at once, and makes concurrent
+/// requests for a variant that isn't stored yet wait for a single render
+pub struct VariantRenders {
+    permits: Semaphore,
+    /// Locks of the variants being rendered, by storage key
+    in_flight: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+/// Held while a variant is rendered and stored, see `VariantRenders::claim`
+pub struct RenderClaim<'a> {
+    renders: &'a VariantRenders,
+    key: String,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Default for VariantRenders {
+    fn default() -> VariantRenders {
+        VariantRenders {
+            permits: Semaphore::new(MAX_RENDERS),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl VariantRenders {
+    /// Waits until no other request renders the variant stored at `key`.
+    /// Storage has to be checked again afterwards, the variant is likely
+    /// there by then.
+    pub async fn claim(&self, key: &str) -> RenderClaim<'_> {
+        let lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        RenderClaim {
+            renders: self,
+            key: key.to_owned(),
+            _guard: lock.lock_owned().await,
+        }
+    }
+
+    /// Renders a variant off the runtime, waiting while too many are rendered
+    pub async fn render(&self, content: Vec<u8>, transform: Transform) -> anyhow::Result<Vec<u8>> {
+        let _permit = self.permits.acquire().await?;
+        tokio::task::spawn_blocking(move || render(&content, &transform)).await?
+    }
+}
+
+impl Drop for RenderClaim<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.renders.in_flight.lock().unwrap();
+        // the map and our guard hold the lock, anyone else is still waiting for it
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) <= 2)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Decodes an image and renders it as described by `transform`
+pub fn render(content: &[u8], transform: &Transform) -> anyhow::Result<Vec<u8>> {
+    render_image(&decode(content)?, transform)
+}
+
+/// Renders an already decoded image as described by `transform`. Every pixel
+/// type is converted to 8 bit first, e.g. 16 bit PNGs or grayscale with alpha.
+pub fn render_image(source: &DynamicImage, transform: &Transform) -> anyhow::Result<Vec<u8>> {
+    // 8 bit RGB(A) is what every encoder takes, jpeg has no alpha channel
+    let (source, pixel_type, color) = match transform.format {
+        Format::Jpeg => (
+            DynamicImage::ImageRgb8(source.to_rgb8()),
+            PixelType::U8x3,
+            ExtendedColorType::Rgb8,
+        ),
+        _ => (
+            DynamicImage::ImageRgba8(source.to_rgba8()),
+            PixelType::U8x4,
+            ExtendedColorType::Rgba8,
+        ),
+    };
+
+    let (width, height) = transform.target_size(source.width(), source.height());
+    let mut resized = Image::new(width, height, pixel_type);
+    // crops the source to the aspect ratio of the result, which only differs for `cover`
+    Resizer::new().resize(
+        &source,
+        &mut resized,
+        &Some(ResizeOptions::new().fit_into_destination(None)),
+    )?;
+
+    let buffer = resized.buffer();
+    let mut out = Vec::new();
+    match transform.format {
+        Format::Webp => {
+            let encoded = webp::Encoder::from_rgba(buffer, width, height)
+                .encode_simple(false, transform.quality as f32)
+                .map_err(|error| anyhow!("failed to encode webp: {error:?}"))?;
+            out.extend_from_slice(&encoded);
+        }
+        Format::Avif => AvifEncoder::new_with_speed_quality(&mut out, 8, transform.quality)
+            .write_image(buffer, width, height, color)?,
+        Format::Png => PngEncoder::new(&mut out).write_image(buffer, width, height, color)?,
+        Format::Jpeg => JpegEncoder::new_with_quality(&mut out, transform.quality)
+            .write_image(buffer, width, height, color)?,
+    }
+
+    Ok(out)
+}
@@ -4,13 +4,17 @@ use state::ApiState;
 use uuid::Uuid;
 
 mod auth;
+mod commands;
 mod db;
+mod imaging;
 mod meili;
 mod ocr;
+mod pdf;
 mod server;
 mod state;
 mod storage;
 mod tasks;
+mod text;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -19,6 +23,11 @@ async fn main() -> anyhow::Result<()> {
 
     let state = Arc::new(ApiState::new().await?);
 
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some((command, args)) = args.split_first() {
+        return commands::run(state, command, args).await;
+    }
+
     state
         .meili
         .index_object(
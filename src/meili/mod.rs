@@ -1,10 +1,7 @@
 use std::env;
 
 use chrono::{DateTime, Utc};
-use meilisearch_sdk::{
-    client::Client,
-    search::{SearchResult, SearchResults},
-};
+use meilisearch_sdk::{client::Client, search::SearchResults};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -49,6 +46,12 @@ impl MeiliClient {
         Ok(())
     }
 
+    pub async fn delete_object(&self, id: Uuid) -> anyhow::Result<()> {
+        let idx = self.client.index("objects_ocr");
+        idx.delete_document(id).await?;
+        Ok(())
+    }
+
     pub async fn search_objects(
         &self,
         user_id: Uuid,
@@ -0,0 +1,91 @@
+//! Reads PDFs with the poppler command line tools `pdftotext`, `pdfinfo` and
+//! `pdftoppm`, they have to be installed next to the panel
+
+use std::{path::Path, process::Stdio, time::Duration};
+
+use anyhow::anyhow;
+use tokio::process::Command;
+
+pub const CONTENT_TYPE: &str = "application/pdf";
+
+/// How long a single tool may run, a crafted PDF can keep them busy for ages
+const TOOL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pages whose text layer is indexed
+const MAX_TEXT_PAGES: u32 = 200;
+
+/// A tool ran but couldn't read the file, e.g. it's encrypted, corrupt or
+/// takes too long to read
+#[derive(Debug, thiserror::Error)]
+#[error("{program} can't read the pdf: {stderr}")]
+pub struct UnreadablePdf {
+    program: &'static str,
+    stderr: String,
+}
+
+async fn run(program: &'static str, mut command: Command) -> anyhow::Result<Vec<u8>> {
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| anyhow!("failed to run {program}, is poppler installed? {error}"))?;
+
+    let output = match tokio::time::timeout(TOOL_TIMEOUT, child.wait_with_output()).await {
+        Ok(output) => output?,
+        // the same file would time out again, so it counts as unreadable
+        Err(_) => {
+            return Err(UnreadablePdf {
+                program,
+                stderr: format!("took longer than {TOOL_TIMEOUT:?}"),
+            }
+            .into())
+        }
+    };
+    if !output.status.success() {
+        return Err(UnreadablePdf {
+            program,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }
+        .into());
+    }
+
+    Ok(output.stdout)
+}
+
+/// The embedded text layer, empty for scans without one
+pub async fn extract_text(path: &Path) -> anyhow::Result<String> {
+    let mut command = Command::new("pdftotext");
+    command
+        .args(["-enc", "UTF-8", "-l", &MAX_TEXT_PAGES.to_string()])
+        .arg(path)
+        .arg("-");
+
+    let text = run("pdftotext", command).await?;
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+pub async fn page_count(path: &Path) -> anyhow::Result<u32> {
+    let mut command = Command::new("pdfinfo");
+    command.arg(path);
+
+    let info = run("pdfinfo", command).await?;
+    String::from_utf8_lossy(&info)
+        .lines()
+        .find_map(|line| line.strip_prefix("Pages:"))
+        .and_then(|pages| pages.trim().parse().ok())
+        .ok_or(anyhow!("pdfinfo didn't report a page count"))
+}
+
+/// Renders a page, counting from 1, as a PNG whose longer side is `size` pixels
+pub async fn render_page(path: &Path, page: u32, size: u32) -> anyhow::Result<Vec<u8>> {
+    let page = page.to_string();
+    let mut command = Command::new("pdftoppm");
+    command
+        .args(["-png", "-singlefile", "-f", &page, "-l", &page])
+        .args(["-scale-to", &size.to_string()])
+        .arg(path);
+
+    run("pdftoppm", command).await
+}
@@ -0,0 +1,122 @@
+//! Endpoints for the operators listed in `ADMIN_USER_IDS`
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Path, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::error::ApiError;
+use crate::{
+    auth::user::TokenClaims,
+    state::ApiState,
+    storage::{HotCacheStats, ReplicationStats},
+};
+
+pub fn router(state: Arc<ApiState>) -> Router<Arc<ApiState>> {
+    Router::new()
+        .route("/integrity", get(integrity_report))
+        .route("/integrity/:id/rescrub", post(rescrub_object))
+        .route("/replication", get(replication_stats))
+        .route("/hot-cache", get(hot_cache_stats))
+        .route_layer(middleware::from_fn_with_state(state, admin_middleware))
+}
+
+async fn admin_middleware(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.admins.contains(&claims.sub) {
+        tracing::warn!("user {} tried to use the admin api", claims.sub);
+        return ApiError::Forbidden.into_response();
+    }
+
+    next.run(request).await
+}
+
+#[derive(Serialize)]
+pub struct IntegrityFailure {
+    id: Uuid,
+    user_id: Uuid,
+    file_name: String,
+    sha256_hash: String,
+    storage_key: String,
+    error: String,
+    verified_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct IntegrityReport {
+    verified: i64,
+    unverified: i64,
+    failed: i64,
+    failures: Vec<IntegrityFailure>,
+}
+
+pub async fn integrity_report(
+    State(state): State<Arc<ApiState>>,
+) -> Result<Json<IntegrityReport>, ApiError> {
+    let (verified, unverified, failed) = state.pg.integrity_summary().await?;
+    let failures = state
+        .pg
+        .list_integrity_failures()
+        .await?
+        .into_iter()
+        .map(|obj| IntegrityFailure {
+            storage_key: obj.storage_key(),
+            id: obj.id,
+            user_id: obj.user_id,
+            file_name: obj.file_name,
+            sha256_hash: obj.sha256_hash,
+            error: obj.integrity_error.unwrap_or_default(),
+            verified_at: obj.verified_at,
+        })
+        .collect();
+
+    Ok(Json(IntegrityReport {
+        verified,
+        unverified,
+        failed,
+        failures,
+    }))
+}
+
+/// Moves an object to the front of the scrub queue, e.g. after repairing it
+pub async fn rescrub_object(
+    State(state): State<Arc<ApiState>>,
+    Path(id): Path<Uuid>,
+) -> Result<(), ApiError> {
+    if !state.pg.reset_verification(id).await? {
+        return Err(ApiError::CdnObjectNotFound);
+    }
+
+    Ok(())
+}
+
+/// Progress of mirroring writes to the replicas, by storage pool
+pub async fn replication_stats(
+    State(state): State<Arc<ApiState>>,
+) -> Json<HashMap<String, ReplicationStats>> {
+    let stats = state
+        .storage
+        .pools()
+        .filter_map(|(name, pool)| {
+            let replication = pool.replication.as_ref()?;
+            Some((name.clone(), replication.stats()))
+        })
+        .collect();
+    Json(stats)
+}
+
+pub async fn hot_cache_stats(State(state): State<Arc<ApiState>>) -> Json<HotCacheStats> {
+    Json(state.hot_cache.stats())
+}
@@ -39,9 +39,9 @@ pub async fn discord_signin(
         DiscordTokenInfo::Error {
             error,
             error_description,
-            ..
+            error_uri,
         } => {
-            tracing::error!(error = ?error, error_description = ?error_description, "discord auth error");
+            tracing::error!(error = ?error, error_description = ?error_description, error_uri = ?error_uri, "discord auth error");
             return Err(ApiError::Unauthorized);
         }
     };
@@ -91,10 +91,10 @@ pub async fn discord_signin(
     cookie.set_secure(true);
     cookie.set_path("/");
 
-    return Ok((
+    Ok((
         jar.add(cookie),
         Json(SignInResp {
             username: prof.username,
         }),
-    ));
+    ))
 }
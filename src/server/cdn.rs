@@ -1,19 +1,23 @@
 use axum::{
+    body::Body,
     extract::{DefaultBodyLimit, Path, Query, State},
-    http::{header, StatusCode},
-    response::IntoResponse,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
     routing::{get, post},
     Extension, Json, Router,
 };
 use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
-use fast_image_resize::{images::Image, IntoImageView, ResizeOptions, Resizer};
-use futures::{stream::FuturesUnordered, StreamExt};
-use image::{codecs::webp::WebPEncoder, ImageEncoder};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{self, FuturesUnordered},
+    StreamExt,
+};
 use scopeguard::guard_on_success;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    io::{BufReader, BufWriter, Read},
+    io::{BufReader, Read},
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -24,27 +28,60 @@ use std::{
 use tempfile::NamedTempFile;
 use tokio::{fs::File, sync::Mutex};
 use uuid::Uuid;
-use webp::Encoder;
 
-use super::error::ApiError;
+use super::{
+    conditional::Validators,
+    disposition::{content_disposition, DownloadQuery},
+    error::ApiError,
+    presign,
+    range::{self, ByteRange, RangeRequest},
+    slug, thumbnails,
+    transform::{variant_file_name, TransformQuery},
+    tus, unlock, versions,
+};
 use crate::{
-    auth::user::TokenClaims,
-    db::{CdnObject, CreateCdnObject, COF_SEARCHABLE},
+    auth::{link, user::TokenClaims},
+    db::{
+        CdnObject, CreateCdnObject, LinkAuditEntry, LinkLimits, COF_SEARCHABLE, COF_THUMBNAILABLE,
+        LINK_PUBLISHED, LINK_ROTATED,
+    },
+    imaging::{self, Transform, UndecodableImage},
+    pdf,
     state::ApiState,
+    storage::{variant_key, ByteStream},
+    text,
 };
 
+/// Longest password a link can be protected with, argon2 hashes all of it
+const MAX_LINK_PASSWORD_LENGTH: usize = 1024;
+
+/// Largest object a user can upload, no matter which upload path is used
+pub const MAX_UPLOAD_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+
+/// Longest content type and file name an upload can declare, in characters,
+/// as their columns allow
+pub const MAX_CONTENT_TYPE_LENGTH: usize = 64;
+pub const MAX_FILE_NAME_LENGTH: usize = 128;
+
 pub fn router() -> Router<Arc<ApiState>> {
     Router::new()
         .route("/objects/:id", get(fetch_object))
-        .route("/objects/:id/thumbnail", get(fetch_object_thumb))
+        .route("/objects/:id/thumbnail", get(thumbnails::fetch_thumbnail))
+        .route("/objects/:id/thumbnails", get(thumbnails::list_thumbnails))
         .route("/objects/list", get(list_objects))
         .route("/objects/upload", post(upload))
+        .nest("/objects/:id/versions", versions::router())
         .layer(DefaultBodyLimit::max(5000000000))
         .route("/objects/delete", post(delete_objects))
         .route("/objects/publish", post(publish_object))
+        .route("/objects/unpublish", post(unpublish_object))
+        .route("/objects/rotate-slug", post(rotate_slug))
+        .route("/objects/:id/links", get(list_link_audit))
         .route("/objects/search", get(search_objects))
+        .nest("/objects/uploads", tus::router())
+        .nest("/objects/presign", presign::router())
         .route("/objects/count", get(list_pages))
-        .route("/*slug", get(fetch_obj_by_slug))
+        .route("/*slug", get(fetch_obj_by_slug).post(unlock::unlock_link))
 }
 
 #[derive(Serialize)]
@@ -57,7 +94,7 @@ pub async fn list_pages(
     Extension(claims): Extension<TokenClaims>,
 ) -> Result<Json<ObjectCountResp>, ApiError> {
     let rec = sqlx::query!(
-        "SELECT COUNT(1) AS \"count!: i64\" FROM cdn_objects WHERE user_id = $1",
+        "SELECT COUNT(1) AS \"count!: i64\" FROM cdn_objects WHERE user_id = $1 AND deleted_at IS NULL",
         claims.sub
     )
     .fetch_one(&state.pg.inner)
@@ -97,12 +134,13 @@ pub async fn search_objects(
         .map(|x| x.result.id)
         .collect::<Vec<_>>();
 
-    let mut objects: Vec<CdnObject> =
-        sqlx::query_as("SELECT * FROM cdn_objects WHERE user_id = $1 AND id = ANY($2)")
-            .bind(claims.sub)
-            .bind(&object_ids)
-            .fetch_all(&state.pg.inner)
-            .await?;
+    let mut objects: Vec<CdnObject> = sqlx::query_as(
+        "SELECT * FROM cdn_objects WHERE user_id = $1 AND id = ANY($2) AND deleted_at IS NULL",
+    )
+    .bind(claims.sub)
+    .bind(&object_ids)
+    .fetch_all(&state.pg.inner)
+    .await?;
 
     objects.sort_by(|a, b| {
         let pos_a = objects_found
@@ -133,46 +171,403 @@ pub async fn search_objects(
     }))
 }
 
-pub async fn fetch_obj_by_slug(
-    State(state): State<Arc<ApiState>>,
-    Path(slug): Path<String>,
-) -> Result<impl IntoResponse, ApiError> {
-    let obj_pg = match state.pg.fetch_cdn_object_slug(&slug).await {
+/// The object a public link points to, 410 for links that were taken down
+pub(super) async fn find_slug_object(state: &ApiState, slug: &str) -> Result<CdnObject, ApiError> {
+    // the cache only spares fetching the row, postgres still has to confirm
+    // the link wasn't taken down or changed on another instance
+    if let Some(obj_pg) = state.hot_cache.lookup_slug(slug) {
+        if state.pg.is_slug_current(slug, &obj_pg).await? {
+            return Ok(obj_pg);
+        }
+    }
+
+    let obj_pg = match state.pg.fetch_cdn_object_slug(slug).await {
         Ok(v) => v,
+        Err(_) if state.pg.is_slug_retired(slug).await? => {
+            return Err(ApiError::LinkGone);
+        }
         Err(error) => {
             tracing::error!(error = ?error, "error when fetching a cdn object from postgres");
             return Err(ApiError::CdnObjectNotFound);
         }
     };
+    state.hot_cache.remember_slug(slug, &obj_pg);
+    Ok(obj_pg)
+}
+
+pub async fn fetch_obj_by_slug(
+    State(state): State<Arc<ApiState>>,
+    Path(slug): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    Query(transform): Query<TransformQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let transform = transform.parse()?;
+    let obj_pg = find_slug_object(&state, &slug).await?;
+
+    if obj_pg.link_password_hash.is_some() && !unlock::is_unlocked(&state, &headers, &slug) {
+        return unlock::locked_response(&headers, false);
+    }
+    let audience = if obj_pg.link_password_hash.is_some() || obj_pg.has_link_limits() {
+        Audience::Restricted
+    } else {
+        Audience::Public
+    };
+
+    match transform {
+        Some(transform) => {
+            variant_response(
+                &state,
+                obj_pg,
+                transform,
+                &headers,
+                audience,
+                query.forced(),
+            )
+            .await
+        }
+        None => object_response(&state, obj_pg, &headers, audience, query.forced()).await,
+    }
+}
+
+/// Who a download is served to
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Audience {
+    /// Anyone with the link, small objects are served from the hot cache
+    Public,
+    /// Anyone who got past the password or limits of a link, served like
+    /// `Public` but shared caches must not keep it
+    Restricted,
+    /// The owner of the object, always read from storage
+    Owner,
+}
+
+impl Audience {
+    fn cache_control(self, state: &ApiState) -> &str {
+        match self {
+            Audience::Public => &state.cache.public,
+            Audience::Restricted | Audience::Owner => &state.cache.private,
+        }
+    }
+}
+
+/// Checks the limits of a public link right before it serves a response.
+/// `transfer` counts a download, which is any response carrying content, so
+/// every range of a file fetched in pieces counts as well. Revalidations only
+/// need the link to still be usable, a spent link refuses everything.
+async fn check_link_limits(
+    state: &ApiState,
+    obj_pg: CdnObject,
+    audience: Audience,
+    transfer: bool,
+) -> Result<CdnObject, ApiError> {
+    if audience == Audience::Owner || !obj_pg.has_link_limits() {
+        return Ok(obj_pg);
+    }
+    if obj_pg.link_spent_at.is_some() {
+        return Err(ApiError::LinkGone);
+    }
+
+    let obj_pg = if transfer {
+        state.pg.claim_link_download(obj_pg.id).await?
+    } else {
+        state.pg.fetch_usable_link(obj_pg.id).await?
+    };
+    obj_pg.ok_or(ApiError::LinkGone)
+}
+
+/// Builds the response for an object download, honouring conditional and
+/// `Range` requests. The body is streamed from storage so large objects
+/// never sit in memory, small public ones come from the hot cache.
+/// `download` asks for an attachment even when the type could be shown inline.
+pub(super) async fn object_response(
+    state: &ApiState,
+    obj_pg: CdnObject,
+    headers: &HeaderMap,
+    audience: Audience,
+    download: bool,
+) -> Result<Response, ApiError> {
+    let size: u64 = obj_pg.content_size.try_into()?;
+    let validators = Validators {
+        etag: format!("\"{}\"", obj_pg.sha256_hash),
+        last_modified: obj_pg.content_modified_at.and_utc(),
+        cache_control: audience.cache_control(state),
+    };
+    if validators.is_not_modified(headers) {
+        check_link_limits(state, obj_pg, audience, false).await?;
+        return Ok(validators.not_modified()?);
+    }
+
+    let disposition = content_disposition(&obj_pg.file_name, &obj_pg.content_type, download);
+    let requested =
+        range::requested_range(headers, size, &validators.etag, validators.last_modified);
+    let transfer = !matches!(requested, RangeRequest::Unsatisfiable);
+    let obj_pg = check_link_limits(state, obj_pg, audience, transfer).await?;
+
+    let ranges = match requested {
+        RangeRequest::Full => {
+            let stream = open_object_stream(state, &obj_pg, None, audience).await?;
+            let response = validators
+                .apply(axum::http::Response::builder())
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, obj_pg.content_type) // Set appropriate MIME type
+                .header(header::CONTENT_LENGTH, size)
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::CONTENT_DISPOSITION, disposition)
+                .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+                .body(Body::from_stream(stream))?;
+            return Ok(response);
+        }
+        RangeRequest::Unsatisfiable => return Err(ApiError::RangeNotSatisfiable(size)),
+        RangeRequest::Partial(ranges) => ranges,
+    };
+
+    let builder = validators
+        .apply(axum::http::Response::builder())
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+
+    if let [range] = ranges.as_slice() {
+        let stream = open_object_stream(state, &obj_pg, Some(*range), audience).await?;
+        let response = builder
+            .header(header::CONTENT_TYPE, obj_pg.content_type)
+            .header(header::CONTENT_RANGE, range.content_range(size))
+            .header(header::CONTENT_LENGTH, range.byte_len())
+            .body(Body::from_stream(stream))?;
+        return Ok(response);
+    }
 
-    let obj_s3 = match state
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut content_length = 0;
+    let mut parts: Vec<ByteStream> = Vec::with_capacity(ranges.len() * 2 + 1);
+    for range in &ranges {
+        let part_header = format!(
+            "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            obj_pg.content_type,
+            range.content_range(size)
+        );
+        content_length += part_header.len() as u64 + range.byte_len();
+
+        parts.push(Box::pin(stream::once(async move {
+            Ok::<_, anyhow::Error>(Bytes::from(part_header))
+        })));
+        parts.push(open_object_stream(state, &obj_pg, Some(*range), audience).await?);
+    }
+
+    let closing = format!("\r\n--{boundary}--\r\n");
+    content_length += closing.len() as u64;
+    parts.push(Box::pin(stream::once(async move {
+        Ok::<_, anyhow::Error>(Bytes::from(closing))
+    })));
+
+    let response = builder
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+        )
+        .header(header::CONTENT_LENGTH, content_length)
+        .body(Body::from_stream(stream::iter(parts).flatten()))?;
+    Ok(response)
+}
+
+/// Builds the response for a resized or re-encoded image. Variants are
+/// rendered on first use and kept in storage until the object is deleted.
+async fn variant_response(
+    state: &ApiState,
+    obj_pg: CdnObject,
+    transform: Transform,
+    headers: &HeaderMap,
+    audience: Audience,
+    download: bool,
+) -> Result<Response, ApiError> {
+    if !imaging::is_transformable(&obj_pg.content_type) {
+        return Err(ApiError::UnsupportedMediaType);
+    }
+    if obj_pg.content_size > imaging::MAX_SOURCE_SIZE {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let name = transform.variant_name(&obj_pg.sha256_hash);
+    let validators = Validators {
+        etag: format!("\"{name}\""),
+        last_modified: obj_pg.content_modified_at.and_utc(),
+        cache_control: audience.cache_control(state),
+    };
+    if validators.is_not_modified(headers) {
+        check_link_limits(state, obj_pg, audience, false).await?;
+        return Ok(validators.not_modified()?);
+    }
+    let obj_pg = check_link_limits(state, obj_pg, audience, true).await?;
+
+    let key = variant_key(obj_pg.user_id, obj_pg.id, &name);
+    let content_type = transform.format.content_type();
+    let vault_key = state.vault_key(obj_pg.user_id).await?;
+    let variant = match state
         .storage
-        .get_user_object(obj_pg.user_id, obj_pg.id)
+        .get_derived(&obj_pg.storage_pool, &key, vault_key.as_ref())
         .await
     {
         Ok(v) => v,
-        Err(error) => {
-            tracing::error!(error = ?error, "error when fetching a cdn object from s3");
-            return Err(ApiError::CdnObjectNotFound);
+        Err(_) => {
+            let _claim = state.variant_renders.claim(&key).await;
+            // another request may have rendered it while we waited
+            match state
+                .storage
+                .get_derived(&obj_pg.storage_pool, &key, vault_key.as_ref())
+                .await
+            {
+                Ok(v) => v,
+                Err(_) => {
+                    let rendered = render_variant(state, &obj_pg, transform).await?;
+                    // the variant is rendered again next time if it can't be stored
+                    if let Err(error) = state
+                        .storage
+                        .put_derived(
+                            &obj_pg.storage_pool,
+                            &key,
+                            &rendered,
+                            content_type,
+                            vault_key.as_ref(),
+                        )
+                        .await
+                    {
+                        tracing::warn!(error = ?error, "failed to store variant {key}");
+                    }
+                    tracing::debug!("rendered variant {name} of object {}", obj_pg.id);
+                    rendered
+                }
+            }
         }
     };
 
-    let response = axum::http::Response::builder()
+    let file_name = variant_file_name(&obj_pg.file_name, transform.format);
+    let response = validators
+        .apply(axum::http::Response::builder())
         .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, obj_pg.content_type) // Set appropriate MIME type
-        .header(header::CONTENT_LENGTH, obj_s3.len())
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, variant.len())
         .header(
             header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", obj_pg.file_name),
+            content_disposition(&file_name, content_type, download),
         )
-        .body(axum::body::Body::from(obj_s3))?;
-
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from(variant))?;
     Ok(response)
 }
 
+async fn render_variant(
+    state: &ApiState,
+    obj_pg: &CdnObject,
+    transform: Transform,
+) -> Result<Vec<u8>, ApiError> {
+    let content = match state.read_object(obj_pg).await {
+        Ok(v) => v,
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching a cdn object from s3");
+            return Err(ApiError::CdnObjectNotFound);
+        }
+    };
+    state
+        .variant_renders
+        .render(content, transform)
+        .await
+        .map_err(|error| match error.downcast::<UndecodableImage>() {
+            Ok(_) => ApiError::UndecodableImage,
+            Err(error) => error.into(),
+        })
+}
+
+async fn open_object_stream(
+    state: &ApiState,
+    obj_pg: &CdnObject,
+    range: Option<ByteRange>,
+    audience: Audience,
+) -> Result<ByteStream, ApiError> {
+    let size: u64 = obj_pg.content_size.try_into()?;
+    if audience != Audience::Owner && state.hot_cache.admits(size) {
+        let content = state
+            .hot_cache
+            .get_or_fetch(obj_pg, || async move {
+                let content = state.read_object(obj_pg).await?;
+                if content.len() as u64 != size {
+                    anyhow::bail!(
+                        "object {} has {} bytes instead of {size}",
+                        obj_pg.id,
+                        content.len()
+                    );
+                }
+                Ok(content)
+            })
+            .await;
+
+        return match content {
+            Ok(content) => {
+                let content = match range {
+                    Some(r) => content.slice(r.start as usize..=r.end as usize),
+                    None => content,
+                };
+                Ok(Box::pin(stream::once(async move { Ok(content) })))
+            }
+            Err(error) => {
+                tracing::error!(error = ?error, "error when fetching a cdn object from s3");
+                Err(ApiError::CdnObjectNotFound)
+            }
+        };
+    }
+
+    let range = range.map(|r| (r.start, r.end));
+    match state.open_object(obj_pg, range).await {
+        Ok(v) => Ok(v),
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching a cdn object from s3");
+            Err(ApiError::CdnObjectNotFound)
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct PublishObjectReq {
     id: Uuid,
+    /// A vanity slug, a random one is generated without it
+    slug: Option<String>,
+    /// The link answers 410 from then on
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<i32>,
+    /// Deletes the object once the link expired or ran out of downloads
+    #[serde(default)]
+    delete_when_spent: bool,
+    /// Anonymous visitors have to enter it before they get the object
+    password: Option<String>,
+}
+
+impl PublishObjectReq {
+    fn limits(&self) -> Result<LinkLimits, ApiError> {
+        if self.expires_at.is_some_and(|at| at <= Utc::now())
+            || self.max_downloads.is_some_and(|max| max < 1)
+        {
+            return Err(ApiError::BadRequest);
+        }
+        // a link without limits is never spent
+        if self.delete_when_spent && self.expires_at.is_none() && self.max_downloads.is_none() {
+            return Err(ApiError::BadRequest);
+        }
+        if self
+            .password
+            .as_ref()
+            .is_some_and(|v| v.is_empty() || v.len() > MAX_LINK_PASSWORD_LENGTH)
+        {
+            return Err(ApiError::BadRequest);
+        }
+
+        Ok(LinkLimits {
+            expires_at: self.expires_at.map(|at| at.naive_utc()),
+            max_downloads: self.max_downloads,
+            delete_when_spent: self.delete_when_spent,
+            password_hash: None,
+        })
+    }
 }
 
 pub async fn publish_object(
@@ -180,7 +575,7 @@ pub async fn publish_object(
     Extension(claims): Extension<TokenClaims>,
     Json(body): Json<PublishObjectReq>,
 ) -> Result<Json<CdnObject>, ApiError> {
-    let mut object = match state.pg.fetch_cdn_object(claims.sub, body.id).await {
+    let object = match state.pg.fetch_cdn_object(claims.sub, body.id).await {
         Ok(v) => v,
         Err(error) => {
             tracing::error!(error = ?error, "error when fetching a cdn object from postgres");
@@ -192,21 +587,30 @@ pub async fn publish_object(
         return Err(ApiError::ObjectIsAlreadyPublic);
     }
 
-    let slug = state.pg.create_slug_and_publish(object.id).await?;
-
-    object.slug = Some(slug);
-    object.is_public = true;
+    let mut limits = body.limits()?;
+    if let Some(password) = body.password.clone() {
+        let hash = tokio::task::spawn_blocking(move || link::hash_password(&password)).await??;
+        limits.password_hash = Some(hash);
+    }
+    let object = slug::assign(
+        &state,
+        claims.sub,
+        object.id,
+        body.slug.as_deref(),
+        Some(limits),
+        LINK_PUBLISHED,
+    )
+    .await?;
 
     Ok(Json(object))
 }
 
-#[axum::debug_handler]
-pub async fn fetch_object_thumb(
-    State(state): State<Arc<ApiState>>,
-    Extension(claims): Extension<TokenClaims>,
-    Path(id): Path<Uuid>,
-) -> Result<impl IntoResponse, ApiError> {
-    let obj_pg = match state.pg.fetch_cdn_object(claims.sub, id).await {
+async fn fetch_public_object(
+    state: &ApiState,
+    user_id: Uuid,
+    id: Uuid,
+) -> Result<CdnObject, ApiError> {
+    let object = match state.pg.fetch_cdn_object(user_id, id).await {
         Ok(v) => v,
         Err(error) => {
             tracing::error!(error = ?error, "error when fetching a cdn object from postgres");
@@ -214,92 +618,78 @@ pub async fn fetch_object_thumb(
         }
     };
 
-    if !obj_pg.content_type.starts_with("image/") {
-        return Err(ApiError::ObjectHasNoThumbnail);
-    }
-
-    if let Ok(object) = state.storage.get_object_thumb(claims.sub, id).await {
-        tracing::debug!("loading thubmnail for object {} from cache", obj_pg.id);
-        let response = axum::http::Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "image/webp") // Set appropriate MIME type
-            .header(header::CONTENT_LENGTH, object.len())
-            .header(
-                header::CONTENT_DISPOSITION,
-                format!("attachment; filename=\"{}\"", obj_pg.file_name),
-            )
-            .body(axum::body::Body::from(object))?;
-        return Ok(response);
+    if !object.is_public {
+        return Err(ApiError::ObjectIsNotPublic);
     }
+    Ok(object)
+}
 
-    let obj_s3 = match state.storage.get_user_object(claims.sub, id).await {
-        Ok(v) => v,
-        Err(error) => {
-            tracing::error!(error = ?error, "error when fetching a cdn object from s3");
-            return Err(ApiError::CdnObjectNotFound);
-        }
-    };
-
-    let buffer = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<u8>> {
-        let src_image = image::load_from_memory(&obj_s3)?;
-
-        let dst_width = 256;
-        let dst_height = 256;
-        let mut dst_image = Image::new(dst_width, dst_height, src_image.pixel_type().unwrap());
-
-        let mut resizer = Resizer::new();
-        resizer
-            .resize(
-                &src_image,
-                &mut dst_image,
-                &Some(ResizeOptions::new().fit_into_destination(None)),
-            )
-            .unwrap();
-
-        let mut result_buf = BufWriter::new(Vec::new());
-        WebPEncoder::new_lossless(&mut result_buf)
-            .write_image(
-                dst_image.buffer(),
-                dst_width,
-                dst_height,
-                src_image.color().into(),
-            )
-            .unwrap();
+#[derive(Deserialize)]
+pub struct RotateSlugReq {
+    id: Uuid,
+    /// A vanity slug, a random one is generated without it
+    slug: Option<String>,
+}
 
-        let result_buf = result_buf.into_inner()?;
-        let dynimg = image::load_from_memory(&result_buf)?;
-        let webp_encoder = Encoder::from_image(&dynimg).unwrap();
-        let webp_image = webp_encoder.encode_simple(false, 70.0).unwrap();
+/// Gives a public object a new slug, the old link answers 410 from now on.
+/// The link keeps its expiry and download count.
+pub async fn rotate_slug(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(body): Json<RotateSlugReq>,
+) -> Result<Json<CdnObject>, ApiError> {
+    let object = fetch_public_object(&state, claims.sub, body.id).await?;
+    let object = slug::assign(
+        &state,
+        claims.sub,
+        object.id,
+        body.slug.as_deref(),
+        None,
+        LINK_ROTATED,
+    )
+    .await?;
+    state.hot_cache.invalidate(object.id).await;
 
-        Ok(webp_image.to_vec())
-    })
-    .await??;
+    Ok(Json(object))
+}
 
-    let out = state
-        .storage
-        .upload_object_thumb(claims.sub, obj_pg.id, buffer, "image/webp")
-        .await?;
+#[derive(Deserialize)]
+pub struct UnpublishObjectReq {
+    id: Uuid,
+}
 
-    tracing::debug!("create a thumbnail for object {}", obj_pg.id);
+/// Takes the public link of an object down, it answers 410 from now on
+pub async fn unpublish_object(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(body): Json<UnpublishObjectReq>,
+) -> Result<Json<CdnObject>, ApiError> {
+    let object = fetch_public_object(&state, claims.sub, body.id).await?;
+    let object = state.pg.unpublish_object(claims.sub, object.id).await?;
+    state.hot_cache.invalidate(object.id).await;
 
-    let response = axum::http::Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "image/webp") // Set appropriate MIME type
-        .header(header::CONTENT_LENGTH, out.buffer.len())
-        .header(
-            header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", obj_pg.file_name),
-        )
-        .body(axum::body::Body::from(out.buffer))?;
+    Ok(Json(object))
+}
 
-    Ok(response)
+/// Every publish, unpublish and rotation of an object's link
+pub async fn list_link_audit(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<LinkAuditEntry>>, ApiError> {
+    let entries = state.pg.list_link_audit(claims.sub, id).await?;
+    Ok(Json(entries))
 }
 
 pub async fn fetch_object(
     State(state): State<Arc<ApiState>>,
     Extension(claims): Extension<TokenClaims>,
     Path(id): Path<Uuid>,
-) -> Result<impl IntoResponse, ApiError> {
+    Query(query): Query<DownloadQuery>,
+    Query(transform): Query<TransformQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let transform = transform.parse()?;
     let obj_pg = match state.pg.fetch_cdn_object(claims.sub, id).await {
         Ok(v) => v,
         Err(error) => {
@@ -308,25 +698,20 @@ pub async fn fetch_object(
         }
     };
 
-    let obj_s3 = match state.storage.get_user_object(claims.sub, id).await {
-        Ok(v) => v,
-        Err(error) => {
-            tracing::error!(error = ?error, "error when fetching a cdn object from s3");
-            return Err(ApiError::CdnObjectNotFound);
+    match transform {
+        Some(transform) => {
+            variant_response(
+                &state,
+                obj_pg,
+                transform,
+                &headers,
+                Audience::Owner,
+                query.forced(),
+            )
+            .await
         }
-    };
-
-    let response = axum::http::Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, obj_pg.content_type) // Set appropriate MIME type
-        .header(header::CONTENT_LENGTH, obj_s3.len())
-        .header(
-            header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", obj_pg.file_name),
-        )
-        .body(axum::body::Body::from(obj_s3))?;
-
-    Ok(response)
+        None => object_response(&state, obj_pg, &headers, Audience::Owner, query.forced()).await,
+    }
 }
 
 #[derive(Deserialize)]
@@ -357,14 +742,48 @@ pub async fn delete_objects(
     Extension(claims): Extension<TokenClaims>,
     Json(body): Json<DeleteObjectsRequest>,
 ) -> Result<(), ApiError> {
-    state.pg.delete_cdn_objects(claims.sub, &body.files).await?;
-    for file in body.files {
-        state.storage.delete_user_object(claims.sub, file).await?;
+    // content is removed by the deletion worker, so a storage outage
+    // can't fail the request after the objects are already gone
+    let mut trans = state.pg.inner.begin().await?;
+    let deleted = state
+        .pg
+        .schedule_object_deletion(claims.sub, &body.files, &mut trans)
+        .await?;
+    trans.commit().await?;
+
+    for obj in &deleted {
+        state.hot_cache.invalidate(obj.id).await;
     }
 
+    tracing::debug!("scheduled {} objects for deletion", deleted.len());
     Ok(())
 }
 
+/// Flags a freshly uploaded object gets, based on its content type and, for
+/// text uploaded with a generic type, its file name
+pub fn initial_object_flags(id: Uuid, content_type: &str, file_name: &str) -> i64 {
+    const SUPPORTED_TYPES: [&str; 5] = [
+        "image/jpeg",
+        "image/jpg",
+        "image/png",
+        "image/webp",
+        pdf::CONTENT_TYPE,
+    ];
+
+    let mut flags = 0;
+    if SUPPORTED_TYPES.iter().any(|x| x == &content_type) || text::is_text(content_type, file_name)
+    {
+        flags |= COF_SEARCHABLE;
+
+        tracing::debug!("object {} is searchable", id);
+    }
+    if imaging::has_thumbnails(content_type) {
+        flags |= COF_THUMBNAILABLE;
+    }
+
+    flags
+}
+
 #[derive(TryFromMultipart, Debug)]
 pub struct UploadRequest {
     #[form_data(limit = "5GiB")]
@@ -372,7 +791,7 @@ pub struct UploadRequest {
     // options: String,
 }
 
-fn compute_sha256(filename: &PathBuf) -> anyhow::Result<String> {
+pub(super) fn compute_sha256(filename: &PathBuf) -> anyhow::Result<String> {
     let file = std::fs::File::open(filename)?;
     let mut reader = BufReader::new(file);
     let mut hasher = Sha256::new();
@@ -403,24 +822,25 @@ async fn process_upload(
     let hash = compute_sha256(&path)?;
 
     tracing::debug!("uploading file hash is {hash}");
-    let existing_hash = state.pg.find_existing_hash(user_id, &hash).await?;
-    if existing_hash {
-        tracing::debug!("skipping hash {hash}, it already exists");
-        return Ok(());
-    }
-
-    let mut content = File::open(path).await?;
     let content_type = file
         .metadata
         .content_type
         .unwrap_or("application/octet-stream".to_owned());
+    let content_size = tokio::fs::metadata(&path).await?.len();
+
+    let storage_pool = state.storage.default_pool.clone();
+    let mut wrote_blob = false;
+    if state.pg.blob_exists(&hash).await? {
+        tracing::debug!("blob {hash} is already stored, only adding a reference");
+    } else {
+        let mut content = File::open(path).await?;
+        wrote_blob = state
+            .store_blob(&storage_pool, &hash, &mut content, &content_type)
+            .await?;
+    }
 
-    let obj = state
-        .storage
-        .upload_user_object(user_id, &mut content, &content_type)
-        .await?;
-
-    let prefix = obj.id.to_string().chars().take(12).collect::<String>();
+    let id = Uuid::new_v4();
+    let prefix = id.to_string().chars().take(12).collect::<String>();
     let file_name = file
         .metadata
         .file_name
@@ -428,10 +848,11 @@ async fn process_upload(
     let cdn_obj = CreateCdnObject {
         content_type,
         file_name,
-        content_size: obj.size.try_into()?,
+        content_size: content_size.try_into()?,
         user_id,
         hash,
-        id: obj.id,
+        id,
+        wrote_blob,
     };
 
     let mut objects = objects.lock().await;
@@ -451,7 +872,6 @@ pub async fn upload(
 
     let uo_copy = Arc::clone(&uploaded_objects);
     let state_copy = Arc::clone(&state);
-    let user_id = claims.sub;
 
     let success_cond = Arc::new(AtomicBool::new(false));
     let success_guard = guard_on_success(Arc::clone(&success_cond), move |success_cond| {
@@ -462,16 +882,20 @@ pub async fn upload(
             }
 
             let lock = uo_copy.lock().await;
-            for obj in lock.iter() {
-                if let Err(error) = state_copy.storage.delete_user_object(user_id, obj.id).await {
-                    tracing::error!(error = ?error, "failed to delete object in defer");
-                }
+            // someone else may have started referencing the blobs in the
+            // meantime, the deletion worker keeps those
+            let hashes = lock
+                .iter()
+                .filter(|obj| obj.wrote_blob)
+                .map(|obj| obj.hash.clone())
+                .collect::<Vec<_>>();
+            if let Err(error) = state_copy.pg.expire_blob_claims(&hashes).await {
+                tracing::error!(error = ?error, "failed to release blobs in defer");
             }
         });
     });
 
     let start = Instant::now();
-    let mut trans = state.pg.inner.begin().await?;
     let mut objects = Vec::with_capacity(body.files.len());
     let future_set = FuturesUnordered::new();
 
@@ -494,16 +918,10 @@ pub async fn upload(
     drop(up_objects_lock);
 
     let cdn_objects_len = up_objects.len();
+    // only begun now, so no connection is held while the files are stored
+    let mut trans = state.pg.inner.begin().await?;
     for o in up_objects.into_iter() {
-        const SUPPORTED_TYPES: [&str; 4] = ["image/jpeg", "image/jpg", "image/png", "image/webp"];
-
-        let mut flags = 0;
-        if SUPPORTED_TYPES.iter().any(|x| x == &o.content_type) {
-            flags |= COF_SEARCHABLE;
-
-            tracing::debug!("object {} is searchable", o.id);
-        }
-
+        let flags = initial_object_flags(o.id, &o.content_type, &o.file_name);
         let created_object = state
             .pg
             .create_cdn_object(o, Some(&mut *trans), flags)
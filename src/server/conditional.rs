@@ -0,0 +1,130 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+
+/// Validators of a response, sent with the content and checked against
+/// `If-None-Match` and `If-Modified-Since`
+pub struct Validators<'a> {
+    /// A strong entity tag including its quotes
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+    pub cache_control: &'a str,
+}
+
+/// Formats a date the way HTTP headers want it, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether any tag in an `If-None-Match` list matches, using the weak
+/// comparison RFC 9110 asks for
+fn etag_list_matches(value: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    value
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl Validators<'_> {
+    /// Checks `If-None-Match`, or `If-Modified-Since` when there is none
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+            return value
+                .to_str()
+                .is_ok_and(|value| etag_list_matches(value, &self.etag));
+        }
+
+        let Some(value) = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+
+        // HTTP dates only have second precision
+        match DateTime::parse_from_rfc2822(value.trim()) {
+            Ok(date) => self.last_modified.timestamp() <= date.timestamp(),
+            Err(_) => false,
+        }
+    }
+
+    /// Adds the validators to a response that is being built
+    pub fn apply(&self, builder: axum::http::response::Builder) -> axum::http::response::Builder {
+        builder
+            .header(header::ETAG, &self.etag)
+            .header(header::LAST_MODIFIED, http_date(self.last_modified))
+            .header(header::CACHE_CONTROL, self.cache_control)
+    }
+
+    pub fn not_modified(&self) -> Result<Response, axum::http::Error> {
+        self.apply(Response::builder().status(StatusCode::NOT_MODIFIED))
+            .body(Body::empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn validators() -> Validators<'static> {
+        Validators {
+            etag: "\"abc\"".to_owned(),
+            last_modified: Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap(),
+            cache_control: "no-cache",
+        }
+    }
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let validators = validators();
+        for value in ["\"abc\"", "W/\"abc\"", "\"x\", \"abc\"", "*"] {
+            assert!(
+                validators.is_not_modified(&headers(header::IF_NONE_MATCH, value)),
+                "{value}"
+            );
+        }
+        assert!(!validators.is_not_modified(&headers(header::IF_NONE_MATCH, "\"x\"")));
+        assert!(!validators.is_not_modified(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn if_modified_since_uses_second_precision() {
+        let validators = validators();
+        let check =
+            |value: &str| validators.is_not_modified(&headers(header::IF_MODIFIED_SINCE, value));
+
+        assert!(check("Wed, 01 May 2024 12:00:00 GMT"));
+        assert!(check("Thu, 02 May 2024 12:00:00 GMT"));
+        assert!(!check("Wed, 01 May 2024 11:59:59 GMT"));
+        assert!(!check("yesterday"));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let mut headers = headers(header::IF_NONE_MATCH, "\"x\"");
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Thu, 02 May 2024 12:00:00 GMT"),
+        );
+        assert!(!validators().is_not_modified(&headers));
+    }
+
+    #[test]
+    fn dates_are_formatted_for_headers() {
+        let date = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        assert_eq!(http_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
@@ -0,0 +1,123 @@
+//! `Content-Disposition` of downloads. Content browsers render safely is shown
+//! inline unless `?download=1` asks for a download, everything else, HTML and
+//! SVG in particular, is always downloaded so it can't run scripts on our
+//! origin.
+
+use serde::Deserialize;
+
+/// Types shown inline besides `video/*` and `audio/*`
+const INLINE_TYPES: [&str; 8] = [
+    "image/png",
+    "image/jpeg",
+    "image/jpg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "image/bmp",
+    "application/pdf",
+];
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    download: Option<String>,
+}
+
+impl DownloadQuery {
+    /// Whether the client asked for an attachment with `?download=1`
+    pub fn forced(&self) -> bool {
+        matches!(self.download.as_deref(), Some("1" | "true"))
+    }
+}
+
+fn is_inline_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("video/")
+        || essence.starts_with("audio/")
+        || INLINE_TYPES.contains(&essence.as_str())
+}
+
+/// Builds the header value with a plain ASCII `filename` for old clients and
+/// the exact name as `filename*` (RFC 6266 and RFC 5987)
+pub fn content_disposition(file_name: &str, content_type: &str, download: bool) -> String {
+    let kind = if download || !is_inline_type(content_type) {
+        "attachment"
+    } else {
+        "inline"
+    };
+
+    let fallback = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    let mut encoded = String::with_capacity(file_name.len());
+    for byte in file_name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_types_are_shown_inline() {
+        assert_eq!(
+            content_disposition("cat.png", "image/png", false),
+            "inline; filename=\"cat.png\"; filename*=UTF-8''cat.png"
+        );
+        assert!(content_disposition("a.mp4", "video/mp4", false).starts_with("inline;"));
+        assert!(content_disposition("a.pdf", "Application/PDF; x=1", false).starts_with("inline;"));
+    }
+
+    #[test]
+    fn everything_else_is_downloaded() {
+        for content_type in ["text/html", "image/svg+xml", "application/octet-stream", ""] {
+            assert!(
+                content_disposition("a", content_type, false).starts_with("attachment;"),
+                "{content_type}"
+            );
+        }
+        assert!(content_disposition("cat.png", "image/png", true).starts_with("attachment;"));
+    }
+
+    #[test]
+    fn file_names_are_escaped() {
+        assert_eq!(
+            content_disposition("my \"cat\" ü.png", "image/png", false),
+            "inline; filename=\"my _cat_ _.png\"; filename*=UTF-8''my%20%22cat%22%20%C3%BC.png"
+        );
+        assert_eq!(
+            content_disposition("a\\b\r\n.txt", "text/plain", true),
+            "attachment; filename=\"a_b__.txt\"; filename*=UTF-8''a%5Cb%0D%0A.txt"
+        );
+    }
+
+    #[test]
+    fn downloads_are_forced_with_a_query() {
+        let query = |download: Option<&str>| DownloadQuery {
+            download: download.map(str::to_owned),
+        };
+        assert!(query(Some("1")).forced());
+        assert!(query(Some("true")).forced());
+        assert!(!query(Some("0")).forced());
+        assert!(!query(None).forced());
+    }
+}
@@ -1,14 +1,41 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
 };
 
+use crate::db::ObjectNotFound;
+
 // #[non_exhaustive]
 pub enum ApiError {
     Unauthorized,
+    Forbidden,
     CdnObjectNotFound,
     ObjectIsAlreadyPublic,
+    ObjectIsNotPublic,
+    /// The link was unpublished or rotated
+    LinkGone,
     ObjectHasNoThumbnail,
+    /// Carries the full size of the object for the `Content-Range` header
+    RangeNotSatisfiable(u64),
+    BadRequest,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// The client's `Tus-Resumable` version isn't supported
+    TusVersionMismatch,
+    /// The client's `Upload-Offset` doesn't match what the server has stored
+    UploadOffsetMismatch,
+    /// Another request is already appending to this upload
+    UploadLocked,
+    /// The uploaded content doesn't match the declared size or hash
+    UploadVerificationFailed,
+    /// An image to render a variant from can't be decoded
+    UndecodableImage,
+    /// A vanity slug has characters or a length we don't allow
+    InvalidSlug,
+    SlugReserved,
+    SlugTaken,
+    /// Too many wrong link passwords, carries the seconds until the next try
+    TooManyRequests(u64),
     Internal(anyhow::Error),
 }
 
@@ -20,11 +47,50 @@ impl IntoResponse for ApiError {
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
             }
             Self::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
+            Self::Forbidden => (StatusCode::FORBIDDEN, "Forbidden").into_response(),
             Self::CdnObjectNotFound => (StatusCode::NOT_FOUND, "Not Found").into_response(),
-            Self::ObjectIsAlreadyPublic => (StatusCode::CONFLICT, "Conflict").into_response(),
+            Self::ObjectIsAlreadyPublic | Self::ObjectIsNotPublic => {
+                (StatusCode::CONFLICT, "Conflict").into_response()
+            }
+            Self::LinkGone => (StatusCode::GONE, "Gone").into_response(),
             Self::ObjectHasNoThumbnail => {
                 (StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed").into_response()
             }
+            Self::RangeNotSatisfiable(size) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+                "Range Not Satisfiable",
+            )
+                .into_response(),
+            Self::BadRequest => (StatusCode::BAD_REQUEST, "Bad Request").into_response(),
+            Self::PayloadTooLarge => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large").into_response()
+            }
+            Self::UnsupportedMediaType => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported Media Type").into_response()
+            }
+            Self::TusVersionMismatch => (
+                StatusCode::PRECONDITION_FAILED,
+                [("tus-version", "1.0.0")],
+                "Precondition Failed",
+            )
+                .into_response(),
+            Self::UploadOffsetMismatch => (StatusCode::CONFLICT, "Conflict").into_response(),
+            Self::UploadLocked => (StatusCode::LOCKED, "Locked").into_response(),
+            Self::UploadVerificationFailed | Self::UndecodableImage => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Unprocessable Entity").into_response()
+            }
+            Self::InvalidSlug => (StatusCode::UNPROCESSABLE_ENTITY, "Invalid Slug").into_response(),
+            Self::SlugReserved => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "Slug Is Reserved").into_response()
+            }
+            Self::SlugTaken => (StatusCode::CONFLICT, "Slug Is Taken").into_response(),
+            Self::TooManyRequests(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "Too Many Requests",
+            )
+                .into_response(),
         }
     }   
 }
@@ -34,6 +100,9 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self::Internal(err.into())
+        match err.into().downcast::<ObjectNotFound>() {
+            Ok(_) => Self::CdnObjectNotFound,
+            Err(err) => Self::Internal(err),
+        }
     }
 }
@@ -7,7 +7,7 @@ use axum::{
 };
 use axum_extra::extract::CookieJar;
 use http::StatusCode;
-use std::{env, sync::Arc};
+use std::{env, net::SocketAddr, sync::Arc};
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
@@ -17,10 +17,21 @@ use tracing::Level;
 
 use crate::state::ApiState;
 
+mod admin;
 mod auth;
 mod cdn;
+mod conditional;
+mod disposition;
 mod error;
+mod presign;
 mod profile;
+mod range;
+mod slug;
+mod thumbnails;
+mod transform;
+mod tus;
+mod unlock;
+mod versions;
 
 async fn auth_middleware(
     State(state): State<Arc<ApiState>>,
@@ -82,7 +93,8 @@ pub async fn create_server(state: Arc<ApiState>) -> anyhow::Result<()> {
             Router::new()
                 .nest("/cdn", cdn::router())
                 .nest("/auth", auth::router())
-                .nest("/user", profile::router()),
+                .nest("/user", profile::router())
+                .nest("/admin", admin::router(state.clone())),
         )
         .layer(
             ServiceBuilder::new().layer(CompressionLayer::new()).layer(
@@ -101,7 +113,12 @@ pub async fn create_server(state: Arc<ApiState>) -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(&addr).await?;
 
     tracing::info!("serving on http://{addr}");
-    axum::serve(listener, app).await?;
+    // the peer address is what failed link unlocks are counted by
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
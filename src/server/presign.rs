@@ -0,0 +1,255 @@
+//! Uploads that go straight from the client to storage. The panel only hands
+//! out presigned URLs and verifies the result before creating the cdn object.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    routing::post,
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    cdn::{initial_object_flags, MAX_CONTENT_TYPE_LENGTH, MAX_FILE_NAME_LENGTH, MAX_UPLOAD_SIZE},
+    error::ApiError,
+};
+use crate::{
+    auth::user::TokenClaims,
+    db::{CdnObject, CreateCdnObject, UploadReservation},
+    state::ApiState,
+    storage::{object_key, upload_key, UploadPart},
+};
+
+/// How long presigned URLs stay valid
+const PRESIGN_EXPIRY_SECS: u32 = 60 * 60;
+
+/// Objects above this size are uploaded in parts of this size
+const PRESIGN_PART_SIZE: i64 = 64 * 1024 * 1024;
+
+pub fn router() -> Router<Arc<ApiState>> {
+    Router::new()
+        .route("/", post(presign_upload))
+        .route("/:id/finalize", post(finalize_upload))
+}
+
+#[derive(Deserialize)]
+pub struct PresignUploadReq {
+    file_name: String,
+    content_type: Option<String>,
+    size: i64,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+pub struct PresignedPart {
+    number: u32,
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct PresignUploadResp {
+    id: Uuid,
+    /// Set for single request uploads
+    url: Option<String>,
+    /// Set for multipart uploads, every part but the last is `part_size` long
+    parts: Vec<PresignedPart>,
+    part_size: Option<i64>,
+    expires_in: u32,
+}
+
+pub async fn presign_upload(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(body): Json<PresignUploadReq>,
+) -> Result<Json<PresignUploadResp>, ApiError> {
+    let is_sha256 = body.sha256.len() == 64
+        && body
+            .sha256
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    if !is_sha256 || body.size < 0 || body.file_name.is_empty() {
+        return Err(ApiError::BadRequest);
+    }
+    if body.size > MAX_UPLOAD_SIZE {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let id = Uuid::new_v4();
+    let key = object_key(claims.sub, id);
+    let content_type = body
+        .content_type
+        .unwrap_or("application/octet-stream".to_owned());
+    // checked before storage is touched, the reservation couldn't hold them
+    if content_type.chars().count() > MAX_CONTENT_TYPE_LENGTH
+        || body.file_name.chars().count() > MAX_FILE_NAME_LENGTH
+    {
+        return Err(ApiError::BadRequest);
+    }
+    let storage_pool = state.storage.default_pool.clone();
+    let backend = state.storage.backend(&storage_pool)?;
+
+    let mut resp = PresignUploadResp {
+        id,
+        url: None,
+        parts: Vec::new(),
+        part_size: None,
+        expires_in: PRESIGN_EXPIRY_SECS,
+    };
+
+    let mut multipart_id = None;
+    if body.size > PRESIGN_PART_SIZE {
+        let upload_id = backend.create_multipart(&key, &content_type).await?;
+        let part_count = (body.size + PRESIGN_PART_SIZE - 1) / PRESIGN_PART_SIZE;
+
+        for number in 1..=part_count as u32 {
+            let url = backend
+                .presign_part(&key, &upload_id, number, PRESIGN_EXPIRY_SECS)
+                .await?;
+            resp.parts.push(PresignedPart { number, url });
+        }
+        resp.part_size = Some(PRESIGN_PART_SIZE);
+        multipart_id = Some(upload_id);
+    } else {
+        resp.url = Some(backend.presign_put(&key, PRESIGN_EXPIRY_SECS).await?);
+    }
+
+    let reserved = state
+        .pg
+        .create_upload_reservation(&UploadReservation {
+            id,
+            user_id: claims.sub,
+            content_type,
+            content_size: body.size,
+            file_name: body.file_name,
+            sha256_hash: body.sha256,
+            multipart_id: multipart_id.clone(),
+            created_at: chrono::Utc::now().naive_utc(),
+            storage_pool,
+        })
+        .await;
+    if let Err(err) = reserved {
+        // nothing would ever clean up a multipart upload without its reservation
+        if let Some(upload_id) = multipart_id {
+            if let Err(err) = backend.abort_multipart(&key, &upload_id).await {
+                tracing::warn!("failed to abort multipart upload of {id}: {err}");
+            }
+        }
+        return Err(err.into());
+    }
+
+    tracing::debug!("reserved object {id} for a presigned upload");
+    Ok(Json(resp))
+}
+
+#[derive(Deserialize)]
+pub struct FinalizedPart {
+    number: u32,
+    etag: String,
+}
+
+#[derive(Deserialize)]
+pub struct FinalizeUploadReq {
+    /// The etags s3 returned for each part of a multipart upload
+    #[serde(default)]
+    parts: Vec<FinalizedPart>,
+}
+
+pub async fn finalize_upload(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<FinalizeUploadReq>,
+) -> Result<Json<CdnObject>, ApiError> {
+    let reservation = match state.pg.fetch_upload_reservation(claims.sub, id).await {
+        Ok(v) => v,
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching an upload reservation from postgres");
+            return Err(ApiError::CdnObjectNotFound);
+        }
+    };
+
+    let key = object_key(reservation.user_id, reservation.id);
+    let backend = state.storage.backend(&reservation.storage_pool)?;
+
+    if let Some(upload_id) = &reservation.multipart_id {
+        let parts = body
+            .parts
+            .into_iter()
+            .map(|part| UploadPart {
+                number: part.number,
+                etag: part.etag,
+                size: 0,
+            })
+            .collect::<Vec<_>>();
+        backend.complete_multipart(&key, upload_id, &parts).await?;
+    }
+
+    // the presigned URL stays valid after this, so the upload is verified and
+    // adopted from a copy the client can't overwrite anymore
+    let staged_key = upload_key(reservation.user_id, reservation.id);
+    if backend.head(&key).await?.is_some() {
+        backend.copy(&key, &staged_key).await?;
+        backend.delete(&key).await?;
+    }
+
+    let stored_size = backend.head(&staged_key).await?.map(|meta| meta.size);
+    let is_valid = match stored_size {
+        Some(size) if i64::try_from(size)? == reservation.content_size => {
+            let hash = state
+                .storage
+                .hash_object(&reservation.storage_pool, &staged_key)
+                .await?;
+            hash == reservation.sha256_hash
+        }
+        _ => false,
+    };
+
+    if !is_valid {
+        tracing::warn!("presigned upload {id} doesn't match its reservation, discarding it");
+        backend.delete(&staged_key).await?;
+        state.pg.delete_upload_reservation(id, None).await?;
+        return Err(ApiError::UploadVerificationFailed);
+    }
+
+    let wrote_blob = state
+        .adopt_blob(
+            &reservation.storage_pool,
+            &staged_key,
+            &reservation.sha256_hash,
+            reservation.content_size,
+        )
+        .await?;
+
+    let flags = initial_object_flags(
+        reservation.id,
+        &reservation.content_type,
+        &reservation.file_name,
+    );
+    let mut trans = state.pg.inner.begin().await?;
+    let object = state
+        .pg
+        .create_cdn_object(
+            CreateCdnObject {
+                id: reservation.id,
+                user_id: reservation.user_id,
+                content_type: reservation.content_type,
+                content_size: reservation.content_size,
+                hash: reservation.sha256_hash,
+                file_name: reservation.file_name,
+                wrote_blob,
+            },
+            Some(&mut *trans),
+            flags,
+        )
+        .await?;
+    state
+        .pg
+        .delete_upload_reservation(id, Some(&mut *trans))
+        .await?;
+    trans.commit().await?;
+
+    tracing::info!("finalized presigned upload {id}");
+    Ok(Json(object))
+}
@@ -0,0 +1,274 @@
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Utc};
+
+/// Most ranges we will serve for a single request once overlapping and
+/// adjacent ones are merged, anything above that is treated as if no `Range`
+/// header was sent at all
+const MAX_RANGES: usize = 16;
+
+/// An inclusive byte range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn byte_len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// Send the whole object back
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header against an object of `size` bytes. Malformed
+/// headers are ignored as allowed by RFC 9110.
+pub fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let Some(specs) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return RangeRequest::Full;
+        };
+
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return RangeRequest::Full,
+            // suffix range, the last N bytes
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                if suffix == 0 || size == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: size.saturating_sub(suffix),
+                    end: size - 1,
+                }
+            }
+            (start, end) => {
+                let Ok(start) = start.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                let end = if end.is_empty() {
+                    u64::MAX
+                } else {
+                    match end.parse::<u64>() {
+                        Ok(v) if v >= start => v,
+                        _ => return RangeRequest::Full,
+                    }
+                };
+                if start >= size {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: end.min(size - 1),
+                }
+            }
+        };
+        ranges.push(range);
+    }
+
+    // asking for the same bytes over and over would make us send more than
+    // the whole object
+    let requested = ranges.iter().map(ByteRange::byte_len).sum::<u64>();
+    if requested > size {
+        return RangeRequest::Full;
+    }
+
+    let ranges = merge_ranges(ranges);
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    RangeRequest::Partial(ranges)
+}
+
+/// Sorts ranges and merges the ones that overlap or touch
+fn merge_ranges(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Checks the `If-Range` precondition, a missing header always passes
+pub fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+
+    // If-Range requires a strong comparison, weak tags never match
+    if value.starts_with('"') {
+        return value == etag;
+    }
+    if value.starts_with("W/") {
+        return false;
+    }
+
+    match DateTime::parse_from_rfc2822(value) {
+        Ok(date) => date.timestamp() == last_modified.timestamp(),
+        Err(_) => false,
+    }
+}
+
+/// Reads the `Range` header of a request, taking `If-Range` into account
+pub fn requested_range(
+    headers: &HeaderMap,
+    size: u64,
+    etag: &str,
+    last_modified: DateTime<Utc>,
+) -> RangeRequest {
+    let Some(value) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return RangeRequest::Full;
+    };
+
+    if !if_range_matches(headers, etag, last_modified) {
+        return RangeRequest::Full;
+    }
+
+    parse_range(value, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-4", 10),
+            RangeRequest::Partial(vec![range(0, 4)])
+        );
+        assert_eq!(
+            parse_range("bytes=5-", 10),
+            RangeRequest::Partial(vec![range(5, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=-3", 10),
+            RangeRequest::Partial(vec![range(7, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=8-100", 10),
+            RangeRequest::Partial(vec![range(8, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=10-20", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-0", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for value in ["items=0-4", "bytes=abc", "bytes=4-2", "bytes=-", "bytes=1"] {
+            assert_eq!(parse_range(value, 10), RangeRequest::Full, "{value}");
+        }
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(
+            parse_range("bytes=0-4,5-9", 20),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=12-15,0-2,1-3", 20),
+            RangeRequest::Partial(vec![range(0, 3), range(12, 15)])
+        );
+
+        // merging brings these under the limit
+        let value = format!(
+            "bytes={}",
+            (0..=MAX_RANGES)
+                .map(|i| format!("{i}-{i}"))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        assert_eq!(
+            parse_range(&value, 1000),
+            RangeRequest::Partial(vec![range(0, MAX_RANGES as u64)])
+        );
+    }
+
+    #[test]
+    fn ranges_adding_up_to_more_than_the_object_are_ignored() {
+        assert_eq!(parse_range("bytes=0-,0-", 10), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=0-5,2-9", 10), RangeRequest::Full);
+    }
+
+    #[test]
+    fn too_many_ranges_are_ignored() {
+        let value = format!(
+            "bytes={}",
+            (0..=MAX_RANGES)
+                .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        assert_eq!(parse_range(&value, 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        let last_modified = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let check = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_RANGE, HeaderValue::from_str(value).unwrap());
+            if_range_matches(&headers, "\"abc\"", last_modified)
+        };
+
+        assert!(if_range_matches(
+            &HeaderMap::new(),
+            "\"abc\"",
+            last_modified
+        ));
+        assert!(check("\"abc\""));
+        assert!(!check("\"def\""));
+        assert!(!check("W/\"abc\""));
+        assert!(check("Wed, 01 May 2024 12:00:00 GMT"));
+        assert!(!check("Wed, 01 May 2024 11:00:00 GMT"));
+    }
+}
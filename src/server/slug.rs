@@ -0,0 +1,167 @@
+//! Slugs of public links. Generated ones are random base62, vanity ones are
+//! chosen by the user, both are unique across all objects, deleted ones
+//! included, and slugs that were taken down are never handed out again, so an
+//! old link never starts serving someone else's file.
+
+use anyhow::anyhow;
+use rand::Rng;
+use uuid::Uuid;
+
+use super::error::ApiError;
+use crate::{
+    db::{CdnObject, LinkLimits},
+    state::ApiState,
+};
+
+const ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Length of generated slugs, 62^10 leaves collisions to bad luck
+const RANDOM_LENGTH: usize = 10;
+
+/// How often a taken random slug is replaced before giving up
+const MAX_ATTEMPTS: usize = 5;
+
+const MIN_VANITY_LENGTH: usize = 3;
+const MAX_VANITY_LENGTH: usize = 64;
+
+/// Vanity slugs that could be mistaken for our own pages, compared without case
+const RESERVED: [&str; 24] = [
+    "admin",
+    "api",
+    "assets",
+    "auth",
+    "cdn",
+    "dashboard",
+    "docs",
+    "download",
+    "favicon",
+    "health",
+    "help",
+    "login",
+    "logout",
+    "metrics",
+    "null",
+    "objects",
+    "panel",
+    "robots",
+    "settings",
+    "static",
+    "status",
+    "undefined",
+    "user",
+    "www",
+];
+
+fn random_slug() -> String {
+    let mut rng = rand::thread_rng();
+    (0..RANDOM_LENGTH)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Checks a slug a user asked for, letters, digits, `-` and `_` only
+fn validate_vanity(slug: &str) -> Result<(), ApiError> {
+    if !(MIN_VANITY_LENGTH..=MAX_VANITY_LENGTH).contains(&slug.len()) {
+        return Err(ApiError::InvalidSlug);
+    }
+    if !slug
+        .bytes()
+        .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+    {
+        return Err(ApiError::InvalidSlug);
+    }
+    // leading and trailing separators are easy to lose when a link is copied
+    if slug.starts_with(['-', '_']) || slug.ends_with(['-', '_']) {
+        return Err(ApiError::InvalidSlug);
+    }
+    if RESERVED.contains(&slug.to_ascii_lowercase().as_str()) {
+        return Err(ApiError::SlugReserved);
+    }
+
+    Ok(())
+}
+
+/// Publishes an object under `vanity`, or a random slug when there is none,
+/// the slug it had before is retired. `limits` replace the ones of the link.
+pub async fn assign(
+    state: &ApiState,
+    user_id: Uuid,
+    object_id: Uuid,
+    vanity: Option<&str>,
+    limits: Option<LinkLimits>,
+    action: &str,
+) -> Result<CdnObject, ApiError> {
+    if let Some(slug) = vanity {
+        validate_vanity(slug)?;
+        return state
+            .pg
+            .assign_slug(user_id, object_id, slug, limits, action)
+            .await?
+            .ok_or(ApiError::SlugTaken);
+    }
+
+    for _ in 0..MAX_ATTEMPTS {
+        let slug = random_slug();
+        let object = state
+            .pg
+            .assign_slug(user_id, object_id, &slug, limits.clone(), action)
+            .await?;
+        if let Some(object) = object {
+            return Ok(object);
+        }
+        tracing::warn!("slug {slug} is already taken, generating another one");
+    }
+
+    Err(anyhow!("no free slug after {MAX_ATTEMPTS} attempts").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_slugs_are_base62() {
+        let slug = random_slug();
+        assert_eq!(slug.len(), RANDOM_LENGTH);
+        assert!(slug.bytes().all(|c| ALPHABET.contains(&c)));
+        assert_ne!(random_slug(), slug);
+    }
+
+    #[test]
+    fn accepts_vanity_slugs() {
+        let longest = "x".repeat(MAX_VANITY_LENGTH);
+        for slug in ["abc", "my-cat_2024", "A1b2C3", longest.as_str()] {
+            assert!(validate_vanity(slug).is_ok(), "{slug}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_vanity_slugs() {
+        let too_long = "x".repeat(MAX_VANITY_LENGTH + 1);
+        for slug in [
+            "ab",
+            too_long.as_str(),
+            "has space",
+            "dots.txt",
+            "ünï",
+            "-lead",
+            "trail_",
+            "a/b",
+        ] {
+            assert!(
+                matches!(validate_vanity(slug), Err(ApiError::InvalidSlug)),
+                "{slug}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_vanity_slugs() {
+        for slug in ["admin", "API", "Favicon"] {
+            assert!(
+                matches!(validate_vanity(slug), Err(ApiError::SlugReserved)),
+                "{slug}"
+            );
+        }
+    }
+}
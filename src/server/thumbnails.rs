@@ -0,0 +1,170 @@
+//! Thumbnails of images. They are rendered in the background after upload in
+//! every size and format of `THUMBNAIL_SIZES` and `THUMBNAIL_FORMATS`, until
+//! then the endpoint answers with 202 and a `Retry-After` header.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    conditional::Validators, disposition::content_disposition, error::ApiError,
+    transform::variant_file_name,
+};
+use crate::{
+    auth::user::TokenClaims,
+    db::{CdnObject, COF_THUMBNAILABLE, COF_THUMBNAILED},
+    imaging::Format,
+    state::ApiState,
+    storage::variant_key,
+};
+
+/// Seconds a client is asked to wait for pending thumbnails
+const RETRY_AFTER_SECS: u32 = 5;
+
+async fn find_image(state: &ApiState, user_id: Uuid, id: Uuid) -> Result<CdnObject, ApiError> {
+    let obj_pg = match state.pg.fetch_cdn_object(user_id, id).await {
+        Ok(v) => v,
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching a cdn object from postgres");
+            return Err(ApiError::CdnObjectNotFound);
+        }
+    };
+
+    if obj_pg.flags & COF_THUMBNAILABLE == 0 {
+        return Err(ApiError::ObjectHasNoThumbnail);
+    }
+    Ok(obj_pg)
+}
+
+fn pending() -> Result<Response, axum::http::Error> {
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(header::RETRY_AFTER, RETRY_AFTER_SECS)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty())
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    size: Option<u32>,
+    format: Option<String>,
+}
+
+pub async fn fetch_thumbnail(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<ThumbnailQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let obj_pg = find_image(&state, claims.sub, id).await?;
+
+    let config = &state.thumbnails;
+    let size = query.size.unwrap_or(config.default_size());
+    let format = match query.format.as_deref() {
+        Some(name) => Format::parse(name).ok_or(ApiError::BadRequest)?,
+        None => config.formats[0],
+    };
+    if !config.sizes.contains(&size) || !config.formats.contains(&format) {
+        return Err(ApiError::BadRequest);
+    }
+
+    if obj_pg.flags & COF_THUMBNAILED == 0 {
+        return Ok(pending()?);
+    }
+
+    // thumbnails only change together with the content
+    let name = config
+        .transform(size, format)
+        .variant_name(&obj_pg.sha256_hash);
+    let validators = Validators {
+        etag: format!("\"{name}\""),
+        last_modified: obj_pg.content_modified_at.and_utc(),
+        cache_control: &state.cache.private,
+    };
+    if validators.is_not_modified(&headers) {
+        return Ok(validators.not_modified()?);
+    }
+
+    let vault_key = state.vault_key(claims.sub).await?;
+    let key = variant_key(claims.sub, id, &name);
+    let thumbnail = match state
+        .storage
+        .get_derived(&obj_pg.storage_pool, &key, vault_key.as_ref())
+        .await
+    {
+        Ok(v) => v,
+        Err(error) => {
+            // e.g. the size was added to THUMBNAIL_SIZES since they were rendered
+            tracing::warn!(error = ?error, "thumbnail {name} of object {id} is missing, rendering it again");
+            state.pg.reset_thumbnails(id, &obj_pg.sha256_hash).await?;
+            return Ok(pending()?);
+        }
+    };
+
+    let file_name = variant_file_name(&obj_pg.file_name, format);
+    let response = validators
+        .apply(Response::builder())
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CONTENT_LENGTH, thumbnail.len())
+        .header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(&file_name, format.content_type(), false),
+        )
+        .body(Body::from(thumbnail))?;
+    Ok(response)
+}
+
+#[derive(Serialize)]
+pub struct ThumbnailVariant {
+    /// The box the thumbnail fits into
+    size: u32,
+    format: &'static str,
+    content_type: &'static str,
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct ThumbnailList {
+    /// False while the thumbnails are still being rendered
+    ready: bool,
+    variants: Vec<ThumbnailVariant>,
+}
+
+pub async fn list_thumbnails(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<ThumbnailList>, ApiError> {
+    let obj_pg = find_image(&state, claims.sub, id).await?;
+
+    let variants = state
+        .thumbnails
+        .transforms()
+        .into_iter()
+        .filter_map(|transform| {
+            let size = transform.width?;
+            let format = transform.format.extension();
+            Some(ThumbnailVariant {
+                size,
+                format,
+                content_type: transform.format.content_type(),
+                url: format!("/api/cdn/objects/{id}/thumbnail?size={size}&format={format}"),
+            })
+        })
+        .collect();
+
+    Ok(Json(ThumbnailList {
+        ready: obj_pg.flags & COF_THUMBNAILED != 0,
+        variants,
+    }))
+}
@@ -0,0 +1,126 @@
+//! Resized and re-encoded copies of images, requested with e.g.
+//! `?variant=medium&format=webp`. Only a handful of named presets exist, so a
+//! client can't make us render and store more than a few variants of an
+//! image. Rendered variants are kept in storage and reused until the object
+//! is deleted.
+
+use serde::Deserialize;
+
+use super::error::ApiError;
+use crate::imaging::{Fit, Format, Transform};
+
+/// Named variants by width, height and fit
+const PRESETS: [(&str, Option<u32>, Option<u32>, Fit); 5] = [
+    ("avatar", Some(128), Some(128), Fit::Cover),
+    ("thumb", Some(256), Some(256), Fit::Cover),
+    ("small", Some(640), None, Fit::Contain),
+    ("medium", Some(1280), None, Fit::Contain),
+    ("large", Some(1920), None, Fit::Contain),
+];
+
+const QUALITY: u8 = 75;
+
+#[derive(Deserialize)]
+pub struct TransformQuery {
+    variant: Option<String>,
+    format: Option<String>,
+}
+
+impl TransformQuery {
+    /// The requested transform, `None` when the original is wanted
+    pub fn parse(&self) -> Result<Option<Transform>, ApiError> {
+        let Some(variant) = self.variant.as_deref() else {
+            // a format alone would re-encode the original at full size
+            return match self.format {
+                Some(_) => Err(ApiError::BadRequest),
+                None => Ok(None),
+            };
+        };
+
+        let (_, width, height, fit) = PRESETS
+            .into_iter()
+            .find(|(name, ..)| *name == variant)
+            .ok_or(ApiError::BadRequest)?;
+        let format = match self.format.as_deref() {
+            None => Format::Webp,
+            Some(name) => Format::parse(name).ok_or(ApiError::BadRequest)?,
+        };
+
+        Ok(Some(Transform {
+            width,
+            height,
+            fit,
+            format,
+            quality: QUALITY,
+        }))
+    }
+}
+
+/// The file name of a variant, the original name with the extension of `format`
+pub fn variant_file_name(file_name: &str, format: Format) -> String {
+    let stem = match file_name.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => file_name,
+    };
+    format!("{stem}.{}", format.extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(variant: Option<&str>, format: Option<&str>) -> TransformQuery {
+        TransformQuery {
+            variant: variant.map(str::to_owned),
+            format: format.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn no_parameters_means_the_original() {
+        assert!(matches!(query(None, None).parse(), Ok(None)));
+    }
+
+    #[test]
+    fn accepts_named_presets() {
+        let Ok(Some(transform)) = query(Some("medium"), Some("png")).parse() else {
+            panic!("medium isn't accepted");
+        };
+        assert_eq!(transform.width, Some(1280));
+        assert_eq!(transform.height, None);
+        assert!(transform.fit == Fit::Contain);
+        assert!(transform.format == Format::Png);
+        assert_eq!(transform.quality, QUALITY);
+
+        let Ok(Some(transform)) = query(Some("thumb"), None).parse() else {
+            panic!("thumb isn't accepted");
+        };
+        assert_eq!(transform.width, Some(256));
+        assert_eq!(transform.height, Some(256));
+        assert!(transform.fit == Fit::Cover);
+        assert!(transform.format == Format::Webp);
+    }
+
+    #[test]
+    fn rejects_anything_but_a_preset() {
+        let rejected = [
+            query(Some("huge"), None),
+            query(Some("800x600"), None),
+            query(Some("small"), Some("gif")),
+            query(None, Some("webp")),
+        ];
+        for query in rejected {
+            assert!(matches!(query.parse(), Err(ApiError::BadRequest)));
+        }
+    }
+
+    #[test]
+    fn variants_are_named_after_their_format() {
+        assert_eq!(variant_file_name("cat.png", Format::Webp), "cat.webp");
+        assert_eq!(
+            variant_file_name("archive.tar.gz", Format::Png),
+            "archive.tar.png"
+        );
+        assert_eq!(variant_file_name("README", Format::Jpeg), "README.jpg");
+    }
+}
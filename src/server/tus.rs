@@ -0,0 +1,524 @@
+//! Resumable uploads implementing the tus 1.0 protocol with the `creation`,
+//! `termination` and `expiration` extensions, see <https://tus.io/protocols/resumable-upload>
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{head, options},
+    Extension, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use futures::StreamExt;
+use sqlx::types::Json;
+use uuid::Uuid;
+
+use super::{
+    cdn::{initial_object_flags, MAX_CONTENT_TYPE_LENGTH, MAX_FILE_NAME_LENGTH, MAX_UPLOAD_SIZE},
+    conditional::http_date,
+    error::ApiError,
+};
+use crate::{
+    auth::user::TokenClaims,
+    db::{CreateCdnObject, UploadSession},
+    state::ApiState,
+    storage::{object_key, upload_key, UploadPart},
+};
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// Size of the parts sent to storage, s3 needs at least 5 MiB per part
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// How long a session lives without receiving content
+const SESSION_TTL_SECS: i64 = 24 * 60 * 60;
+
+pub fn router() -> Router<Arc<ApiState>> {
+    Router::new()
+        .route("/", options(tus_options).post(create_upload))
+        .route(
+            "/:id",
+            head(upload_offset)
+                .patch(append_upload)
+                .delete(terminate_upload),
+        )
+        .layer(middleware::from_fn(tus_resumable_header))
+}
+
+/// Every tus response has to carry the protocol version
+async fn tus_resumable_header(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn check_version(headers: &HeaderMap) -> Result<(), ApiError> {
+    match headers.get("tus-resumable") {
+        Some(v) if v == TUS_VERSION => Ok(()),
+        _ => Err(ApiError::TusVersionMismatch),
+    }
+}
+
+fn header_i64(headers: &HeaderMap, name: &str) -> Result<i64, ApiError> {
+    let value = headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok());
+
+    match value {
+        Some(v) if v >= 0 => Ok(v),
+        _ => Err(ApiError::BadRequest),
+    }
+}
+
+/// When a session that received content just now expires
+fn session_expiry() -> NaiveDateTime {
+    (Utc::now() + TimeDelta::seconds(SESSION_TTL_SECS)).naive_utc()
+}
+
+fn upload_expires(session: &UploadSession) -> String {
+    http_date(session.expires_at.and_utc())
+}
+
+/// Parses `Upload-Metadata`, a comma separated list of `key base64(value)` pairs
+fn parse_metadata(headers: &HeaderMap) -> HashMap<String, String> {
+    let Some(value) = headers.get("upload-metadata").and_then(|v| v.to_str().ok()) else {
+        return HashMap::new();
+    };
+
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let mut pair = pair.trim().splitn(2, ' ');
+            let key = pair.next()?.to_owned();
+            let value = match pair.next() {
+                Some(v) => String::from_utf8(STANDARD.decode(v.trim()).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+pub async fn tus_options() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            ("tus-version", TUS_VERSION.to_owned()),
+            (
+                "tus-extension",
+                "creation,termination,expiration".to_owned(),
+            ),
+            ("tus-max-size", MAX_UPLOAD_SIZE.to_string()),
+        ],
+    )
+}
+
+pub async fn create_upload(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    check_version(&headers)?;
+
+    let upload_length = header_i64(&headers, "upload-length")?;
+    if upload_length > MAX_UPLOAD_SIZE {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let mut metadata = parse_metadata(&headers);
+    let id = Uuid::new_v4();
+    let object_id = Uuid::new_v4();
+
+    let content_type = metadata
+        .remove("filetype")
+        .unwrap_or("application/octet-stream".to_owned());
+    let prefix = object_id.to_string().chars().take(12).collect::<String>();
+    let file_name = metadata
+        .remove("filename")
+        .unwrap_or(format!("{prefix}_no_file_name"));
+    if content_type.chars().count() > MAX_CONTENT_TYPE_LENGTH
+        || file_name.chars().count() > MAX_FILE_NAME_LENGTH
+    {
+        return Err(ApiError::BadRequest);
+    }
+
+    let storage_pool = state.storage.default_pool.clone();
+    let multipart_id = state
+        .storage
+        .backend(&storage_pool)?
+        .create_multipart(&object_key(claims.sub, object_id), &content_type)
+        .await?;
+
+    let session = UploadSession {
+        id,
+        user_id: claims.sub,
+        object_id,
+        multipart_id,
+        upload_length,
+        upload_offset: 0,
+        parts: Json(Vec::new()),
+        content_type,
+        file_name,
+        created_at: Utc::now().naive_utc(),
+        storage_pool,
+        expires_at: session_expiry(),
+        completing: false,
+        sha256_hash: None,
+    };
+    state.pg.create_upload_session(&session).await?;
+
+    tracing::debug!("created resumable upload {id} of {upload_length} bytes");
+
+    let expires = upload_expires(&session);
+    // an empty upload is complete the moment it is created
+    if upload_length == 0 {
+        finish_upload(&state, session, Vec::new()).await?;
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::CREATED)
+        // wherever the router is mounted, the session lives below the path it was created at
+        .header(
+            header::LOCATION,
+            format!("{}/{id}", uri.path().trim_end_matches('/')),
+        )
+        .header("upload-expires", expires)
+        .body(Body::empty())?;
+    Ok(response)
+}
+
+async fn fetch_session(
+    state: &ApiState,
+    user_id: Uuid,
+    id: Uuid,
+) -> Result<UploadSession, ApiError> {
+    match state.pg.fetch_upload_session(user_id, id).await {
+        Ok(v) => Ok(v),
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching an upload session from postgres");
+            Err(ApiError::CdnObjectNotFound)
+        }
+    }
+}
+
+pub async fn upload_offset(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    check_version(&headers)?;
+    let session = fetch_session(&state, claims.sub, id).await?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("upload-offset", session.upload_offset)
+        .header("upload-length", session.upload_length)
+        .header("upload-expires", upload_expires(&session))
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty())?;
+    Ok(response)
+}
+
+pub async fn append_upload(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ApiError> {
+    check_version(&headers)?;
+
+    let content_type = headers.get(header::CONTENT_TYPE);
+    if content_type.is_none_or(|v| v != "application/offset+octet-stream") {
+        return Err(ApiError::UnsupportedMediaType);
+    }
+
+    let offset = header_i64(&headers, "upload-offset")?;
+
+    if !state.upload_locks.lock().unwrap().insert(id) {
+        return Err(ApiError::UploadLocked);
+    }
+    let _lock = scopeguard::guard(Arc::clone(&state), move |state| {
+        state.upload_locks.lock().unwrap().remove(&id);
+    });
+
+    let mut session = fetch_session(&state, claims.sub, id).await?;
+    if offset != session.upload_offset {
+        return Err(ApiError::UploadOffsetMismatch);
+    }
+
+    // a final PATCH that failed after the content was assembled only needs
+    // the rest of the finish, the content it sends again is there already
+    if session.completing {
+        let upload_offset = session.upload_length;
+        finish_upload(&state, session, Vec::new()).await?;
+
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header("upload-offset", upload_offset)
+            .body(Body::empty())?;
+        return Ok(response);
+    }
+
+    // the worker must not drop the session while content arrives
+    session.expires_at = session_expiry();
+    state
+        .pg
+        .update_upload_session(
+            session.id,
+            session.upload_offset,
+            &session.parts,
+            session.expires_at,
+        )
+        .await?;
+
+    let backend = state.storage.backend(&session.storage_pool)?;
+    let pending_key = upload_key(session.user_id, session.id);
+    let flushed: u64 = session.parts.iter().map(|p| p.size).sum();
+    let pending_len: usize = (session.upload_offset as u64 - flushed).try_into()?;
+
+    let mut buffer = Vec::with_capacity(PART_SIZE);
+    if pending_len > 0 {
+        buffer = backend.get(&pending_key).await?;
+        // a crash between writing the tail and saving the offset can leave extra bytes
+        buffer.truncate(pending_len);
+    }
+
+    let object_key = object_key(session.user_id, session.object_id);
+    let mut stream = body.into_data_stream();
+    let mut received = 0;
+
+    let result: Result<(), ApiError> = loop {
+        let chunk = match stream.next().await {
+            Some(Ok(v)) => v,
+            Some(Err(error)) => break Err(error.into()),
+            None => break Ok(()),
+        };
+
+        received += chunk.len() as i64;
+        if session.upload_offset + received > session.upload_length {
+            break Err(ApiError::PayloadTooLarge);
+        }
+        buffer.extend_from_slice(&chunk);
+
+        // the last part is sent once the upload is complete, it may be smaller
+        while buffer.len() >= PART_SIZE
+            && (session.upload_offset + received) < session.upload_length
+        {
+            let part = buffer.drain(..PART_SIZE).collect::<Vec<u8>>();
+            let number = session.parts.len() as u32 + 1;
+            let etag = backend
+                .put_part(
+                    &object_key,
+                    &session.multipart_id,
+                    number,
+                    part,
+                    &session.content_type,
+                )
+                .await?;
+
+            session.parts.push(UploadPart {
+                number,
+                etag,
+                size: PART_SIZE as u64,
+            });
+
+            let flushed: u64 = session.parts.iter().map(|p| p.size).sum();
+            state
+                .pg
+                .update_upload_session(
+                    session.id,
+                    flushed.try_into()?,
+                    &session.parts,
+                    session.expires_at,
+                )
+                .await?;
+        }
+    };
+
+    if let Err(ApiError::PayloadTooLarge) = result {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let flushed: u64 = session.parts.iter().map(|p| p.size).sum();
+    session.upload_offset = i64::try_from(flushed)? + buffer.len() as i64;
+
+    if session.upload_offset == session.upload_length {
+        let upload_offset = session.upload_offset;
+        finish_upload(&state, session, buffer).await?;
+
+        let response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header("upload-offset", upload_offset)
+            .body(Body::empty())?;
+        return Ok(response);
+    }
+
+    if buffer.is_empty() {
+        backend.delete(&pending_key).await?;
+    } else {
+        backend
+            .put(&pending_key, &buffer, "application/octet-stream")
+            .await?;
+    }
+    state
+        .pg
+        .update_upload_session(
+            session.id,
+            session.upload_offset,
+            &session.parts,
+            session.expires_at,
+        )
+        .await?;
+
+    // whatever arrived before the connection broke is kept for the next PATCH
+    result?;
+
+    let response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("upload-offset", session.upload_offset)
+        .header("upload-expires", upload_expires(&session))
+        .body(Body::empty())?;
+    Ok(response)
+}
+
+/// Sends the remaining bytes to storage and turns the upload into a cdn object.
+/// Every step is recorded or can be repeated, so when one fails the client
+/// retries the final PATCH and this picks up where it stopped.
+async fn finish_upload(
+    state: &ApiState,
+    mut session: UploadSession,
+    tail: Vec<u8>,
+) -> anyhow::Result<()> {
+    let object_key = object_key(session.user_id, session.object_id);
+    let backend = state.storage.backend(&session.storage_pool)?;
+
+    if !session.completing {
+        if session.parts.is_empty() {
+            // small uploads never reached the part size, a single put is enough
+            backend
+                .put(&object_key, &tail, &session.content_type)
+                .await?;
+            backend
+                .abort_multipart(&object_key, &session.multipart_id)
+                .await?;
+        } else {
+            if !tail.is_empty() {
+                let number = session.parts.len() as u32 + 1;
+                let size = tail.len() as u64;
+                let etag = backend
+                    .put_part(
+                        &object_key,
+                        &session.multipart_id,
+                        number,
+                        tail,
+                        &session.content_type,
+                    )
+                    .await?;
+                session.parts.push(UploadPart { number, etag, size });
+            }
+
+            backend
+                .complete_multipart(&object_key, &session.multipart_id, &session.parts)
+                .await?;
+        }
+        state.pg.update_upload_completion(session.id, None).await?;
+    }
+    backend
+        .delete(&upload_key(session.user_id, session.id))
+        .await?;
+
+    let hash = match session.sha256_hash {
+        Some(hash) => hash,
+        None => {
+            let hash = state
+                .storage
+                .hash_object(&session.storage_pool, &object_key)
+                .await?;
+            state
+                .pg
+                .update_upload_completion(session.id, Some(&hash))
+                .await?;
+            hash
+        }
+    };
+
+    // the upload was staged under the object's key, move it into its blob.
+    // Without it an earlier attempt moved it already.
+    let mut wrote_blob = false;
+    if backend.head(&object_key).await?.is_some() {
+        wrote_blob = state
+            .adopt_blob(
+                &session.storage_pool,
+                &object_key,
+                &hash,
+                session.upload_length,
+            )
+            .await?;
+    }
+
+    let flags = initial_object_flags(session.object_id, &session.content_type, &session.file_name);
+    let mut trans = state.pg.inner.begin().await?;
+    state
+        .pg
+        .create_cdn_object(
+            CreateCdnObject {
+                id: session.object_id,
+                user_id: session.user_id,
+                content_type: session.content_type,
+                content_size: session.upload_length,
+                hash,
+                file_name: session.file_name,
+                wrote_blob,
+            },
+            Some(&mut *trans),
+            flags,
+        )
+        .await?;
+    state
+        .pg
+        .delete_upload_session(session.id, Some(&mut *trans))
+        .await?;
+    trans.commit().await?;
+
+    tracing::info!("finished resumable upload {}", session.id);
+    Ok(())
+}
+
+pub async fn terminate_upload(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    check_version(&headers)?;
+    let session = fetch_session(&state, claims.sub, id).await?;
+
+    if state.upload_locks.lock().unwrap().contains(&id) {
+        return Err(ApiError::UploadLocked);
+    }
+
+    let backend = state.storage.backend(&session.storage_pool)?;
+    backend
+        .abort_multipart(
+            &object_key(session.user_id, session.object_id),
+            &session.multipart_id,
+        )
+        .await?;
+    backend
+        .delete(&upload_key(session.user_id, session.id))
+        .await?;
+    state.pg.delete_upload_session(session.id, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,134 @@
+//! Unlocking password protected public links. Browsers get a small form that
+//! posts the password back to the link, API clients a plain 401. A correct
+//! password sets a short-lived signed cookie that only applies to that link.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, OriginalUri, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+    Form,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use serde::Deserialize;
+
+use super::{cdn::find_slug_object, error::ApiError};
+use crate::{auth::user::LINK_UNLOCK_TTL, state::ApiState};
+
+/// Scoped to the path of the link it unlocks, so one cookie name serves all links
+const UNLOCK_COOKIE: &str = "link_unlock";
+
+const UNLOCK_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Password required</title>
+<style>
+body { font-family: system-ui, sans-serif; display: grid; place-items: center; min-height: 100vh; margin: 0; background: #f4f4f5; }
+form { background: #fff; padding: 2rem; border-radius: 8px; box-shadow: 0 1px 4px #0002; display: grid; gap: .75rem; width: 18rem; }
+h1 { font-size: 1.1rem; margin: 0; }
+p { color: #b91c1c; margin: 0; font-size: .9rem; }
+input, button { font: inherit; padding: .5rem; }
+</style>
+</head>
+<body>
+<form method="post">
+<h1>This link is password protected</h1>
+{error}
+<input type="password" name="password" placeholder="Password" autofocus required>
+<button type="submit">Unlock</button>
+</form>
+</body>
+</html>
+"#;
+
+/// Whether the request carries a valid unlock cookie for `slug`
+pub fn is_unlocked(state: &ApiState, headers: &HeaderMap, slug: &str) -> bool {
+    CookieJar::from_headers(headers)
+        .get(UNLOCK_COOKIE)
+        .is_some_and(|cookie| state.tokens.verify_link_unlock(cookie.value(), slug))
+}
+
+fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"))
+}
+
+/// The 401 a locked link answers with, `failed` after a wrong password
+pub fn locked_response(headers: &HeaderMap, failed: bool) -> Result<Response, ApiError> {
+    if !wants_html(headers) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let error = if failed {
+        "<p>Wrong password, try again.</p>"
+    } else {
+        ""
+    };
+    let resp = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(UNLOCK_PAGE.replace("{error}", error)))?;
+    Ok(resp)
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    password: String,
+}
+
+pub async fn unlock_link(
+    State(state): State<Arc<ApiState>>,
+    Path(slug): Path<String>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Form(form): Form<UnlockForm>,
+) -> Result<(CookieJar, Response), ApiError> {
+    let jar = CookieJar::from_headers(&headers);
+    let ip = state.link_unlocks.client_ip(peer, &headers);
+    if let Some(wait) = state.link_unlocks.record_attempt(ip, &slug) {
+        return Err(ApiError::TooManyRequests(wait.as_secs() + 1));
+    }
+
+    let obj_pg = find_slug_object(&state, &slug).await?;
+    // the link isn't protected, or not anymore
+    let Some(hash) = obj_pg.link_password_hash else {
+        state.link_unlocks.clear(ip, &slug);
+        return Ok((jar, see_other(&uri.to_string())?));
+    };
+
+    if !state.link_unlocks.verify(hash, form.password).await? {
+        tracing::debug!("wrong password for link {slug} from {ip}");
+        return Ok((jar, locked_response(&headers, true)?));
+    }
+    state.link_unlocks.clear(ip, &slug);
+
+    let mut cookie = Cookie::new(UNLOCK_COOKIE, state.tokens.sign_link_unlock(&slug)?);
+    cookie.set_http_only(true);
+    // lax so the cookie comes along when the link is opened from elsewhere
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_secure(true);
+    cookie.set_path(uri.path().to_owned());
+    cookie.set_max_age(time::Duration::seconds(LINK_UNLOCK_TTL));
+
+    Ok((jar.add(cookie), see_other(&uri.to_string())?))
+}
+
+/// Sends the browser back to the link, the form isn't submitted again on reload
+fn see_other(location: &str) -> Result<Response, axum::http::Error> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+}
@@ -0,0 +1,183 @@
+//! Version history of an object. Uploading to an existing id adds a version
+//! and makes it current, the object's slug always serves the current one.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::Response,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+use uuid::Uuid;
+
+use super::{
+    cdn::{compute_sha256, initial_object_flags, object_response, Audience, MAX_UPLOAD_SIZE},
+    disposition::DownloadQuery,
+    error::ApiError,
+};
+use crate::{
+    auth::user::TokenClaims,
+    db::{CdnObject, CreateCdnObject, ObjectVersion},
+    state::ApiState,
+};
+
+pub fn router() -> Router<Arc<ApiState>> {
+    Router::new()
+        .route("/", get(list_versions).post(upload_version))
+        .route("/:version", get(fetch_version))
+        .route("/:version/restore", post(restore_version))
+}
+
+async fn find_object(state: &ApiState, user_id: Uuid, id: Uuid) -> Result<CdnObject, ApiError> {
+    match state.pg.fetch_cdn_object(user_id, id).await {
+        Ok(v) => Ok(v),
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching a cdn object from postgres");
+            Err(ApiError::CdnObjectNotFound)
+        }
+    }
+}
+
+async fn find_version(
+    state: &ApiState,
+    user_id: Uuid,
+    id: Uuid,
+    version: i32,
+) -> Result<ObjectVersion, ApiError> {
+    match state.pg.fetch_object_version(user_id, id, version).await {
+        Ok(v) => Ok(v),
+        Err(error) => {
+            tracing::error!(error = ?error, "error when fetching an object version from postgres");
+            Err(ApiError::CdnObjectNotFound)
+        }
+    }
+}
+
+pub async fn list_versions(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<ObjectVersion>>, ApiError> {
+    let versions = state.pg.list_object_versions(claims.sub, id).await?;
+    if versions.is_empty() {
+        return Err(ApiError::CdnObjectNotFound);
+    }
+
+    Ok(Json(versions))
+}
+
+#[derive(TryFromMultipart, Debug)]
+pub struct UploadVersionRequest {
+    #[form_data(limit = "5GiB")]
+    file: FieldData<NamedTempFile>,
+}
+
+pub async fn upload_version(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path(id): Path<Uuid>,
+    TypedMultipart(body): TypedMultipart<UploadVersionRequest>,
+) -> Result<Json<CdnObject>, ApiError> {
+    let object = find_object(&state, claims.sub, id).await?;
+
+    let path = body.file.contents.path().to_owned();
+    let content_size = tokio::fs::metadata(&path).await?.len();
+    if content_size > MAX_UPLOAD_SIZE as u64 {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let hash = compute_sha256(&path)?;
+    let content_type = body
+        .file
+        .metadata
+        .content_type
+        .unwrap_or("application/octet-stream".to_owned());
+    let file_name = body.file.metadata.file_name.unwrap_or(object.file_name);
+
+    let storage_pool = state.storage.default_pool.clone();
+    let mut wrote_blob = false;
+    if !state.pg.blob_exists(&hash).await? {
+        let mut content = File::open(&path).await?;
+        wrote_blob = state
+            .store_blob(&storage_pool, &hash, &mut content, &content_type)
+            .await?;
+    }
+
+    let flags = initial_object_flags(id, &content_type, &file_name);
+    let version = CreateCdnObject {
+        id,
+        user_id: claims.sub,
+        content_type,
+        content_size: content_size.try_into()?,
+        hash: hash.clone(),
+        file_name,
+        wrote_blob,
+    };
+
+    let mut trans = state.pg.inner.begin().await?;
+    let result = state
+        .pg
+        .add_object_version(version, flags, &mut trans)
+        .await;
+    let object = match result {
+        Ok(object) => object,
+        Err(error) => {
+            drop(trans);
+            // the deletion worker keeps the blob if someone else references it by now
+            if wrote_blob {
+                state.pg.expire_blob_claims(&[hash]).await?;
+            }
+            return Err(error.into());
+        }
+    };
+    trans.commit().await?;
+    state.hot_cache.invalidate(id).await;
+
+    tracing::info!("object {id} is now at version {}", object.current_version);
+    Ok(Json(object))
+}
+
+pub async fn fetch_version(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path((id, version)): Path<(Uuid, i32)>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let object = find_object(&state, claims.sub, id).await?;
+    let version = find_version(&state, claims.sub, id, version).await?;
+
+    object_response(
+        &state,
+        object.at_version(&version),
+        &headers,
+        Audience::Owner,
+        query.forced(),
+    )
+    .await
+}
+
+pub async fn restore_version(
+    State(state): State<Arc<ApiState>>,
+    Extension(claims): Extension<TokenClaims>,
+    Path((id, version)): Path<(Uuid, i32)>,
+) -> Result<Json<CdnObject>, ApiError> {
+    let version = find_version(&state, claims.sub, id, version).await?;
+
+    let flags = initial_object_flags(id, &version.content_type, &version.file_name);
+    let mut trans = state.pg.inner.begin().await?;
+    let object = state
+        .pg
+        .restore_object_version(&version, flags, &mut trans)
+        .await?;
+    trans.commit().await?;
+    state.hot_cache.invalidate(id).await;
+
+    tracing::info!("restored object {id} to version {}", version.version);
+    Ok(Json(object))
+}
@@ -1,16 +1,72 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tempfile::NamedTempFile;
+use tokio::{fs::File, io::AsyncWriteExt};
+use uuid::Uuid;
 
 use crate::{
-    auth::user::TokenHandler, db::PgClient, meili::MeiliClient, ocr::OcrClient,
-    storage::StorageClient,
+    auth::{link::UnlockLimiter, user::TokenHandler},
+    db::{BlobClaim, CdnObject, PgClient},
+    imaging::{ThumbnailConfig, VariantRenders},
+    meili::MeiliClient,
+    ocr::OcrClient,
+    storage::{crypto::DataKey, ByteStream, HotCache, StorageClient},
 };
 
+/// How long an upload may take to write a blob it claimed, after that the
+/// upload is taken for dead and another one takes the claim over
+const BLOB_CLAIM_LEASE_SECS: i64 = 60 * 60;
+
+/// How often an upload waiting for another one to write the same content looks again
+const BLOB_CLAIM_POLL: Duration = Duration::from_millis(500);
+
+/// `Cache-Control` values sent with downloads
+pub struct CachePolicy {
+    /// For public links, from `CACHE_CONTROL_PUBLIC`. Shared caches keep
+    /// serving a link for up to its max-age after it was unpublished or
+    /// rotated, so keep it short.
+    pub public: String,
+    /// For downloads that need a login, from `CACHE_CONTROL_PRIVATE`
+    pub private: String,
+}
+
+impl CachePolicy {
+    fn from_env() -> CachePolicy {
+        let var = |name: &str, default: &str| match env::var(name) {
+            Ok(v) if !v.is_empty() => v,
+            _ => default.to_owned(),
+        };
+
+        CachePolicy {
+            public: var("CACHE_CONTROL_PUBLIC", "public, max-age=300"),
+            private: var("CACHE_CONTROL_PRIVATE", "private, no-cache"),
+        }
+    }
+}
+
 pub struct ApiState {
     pub storage: StorageClient,
     pub pg: PgClient,
     pub tokens: Arc<TokenHandler>,
     pub ocr: OcrClient,
-    pub meili: MeiliClient
+    pub meili: MeiliClient,
+    /// Resumable uploads that currently have a request appending to them
+    pub upload_locks: Mutex<HashSet<Uuid>>,
+    /// Users allowed to use the admin api, from `ADMIN_USER_IDS`
+    pub admins: HashSet<Uuid>,
+    pub cache: CachePolicy,
+    /// Content of popular public objects, see `HOT_CACHE_*`
+    pub hot_cache: HotCache,
+    pub thumbnails: ThumbnailConfig,
+    /// Failed attempts to unlock password protected links
+    pub link_unlocks: UnlockLimiter,
+    pub variant_renders: VariantRenders,
 }
 
 impl ApiState {
@@ -20,13 +76,202 @@ impl ApiState {
         let storage = StorageClient::new().await?;
         let tokens = Arc::new(TokenHandler::new()?);
         let meili = MeiliClient::new().await?;
+        let hot_cache = HotCache::from_env(storage.keys.is_some()).await?;
+
+        let admins = env::var("ADMIN_USER_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|id| !id.trim().is_empty())
+            .map(|id| id.trim().parse())
+            .collect::<Result<HashSet<Uuid>, _>>()?;
 
         Ok(ApiState {
             storage,
             pg,
             ocr,
             tokens,
-            meili
+            meili,
+            upload_locks: Mutex::new(HashSet::new()),
+            admins,
+            cache: CachePolicy::from_env(),
+            hot_cache,
+            thumbnails: ThumbnailConfig::from_env()?,
+            link_unlocks: UnlockLimiter::from_env(),
+            variant_renders: VariantRenders::default(),
         })
     }
+
+    /// The key the content of `obj` is encrypted with, `None` if it's plaintext
+    pub async fn content_key(&self, obj: &CdnObject) -> anyhow::Result<Option<DataKey>> {
+        let (Some(keys), Some(hash)) = (&self.storage.keys, &obj.blob_hash) else {
+            return Ok(None);
+        };
+
+        match self.pg.fetch_blob_key(hash).await? {
+            Some(wrapped) => Ok(Some(keys.unwrap(&wrapped)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The key of a vault, created on first use. `None` when encryption is off.
+    pub async fn vault_key(&self, user_id: Uuid) -> anyhow::Result<Option<DataKey>> {
+        let Some(keys) = &self.storage.keys else {
+            return Ok(None);
+        };
+
+        let wrapped = match self.pg.fetch_vault_key(user_id).await? {
+            Some(wrapped) => wrapped,
+            None => {
+                let (_, wrapped) = keys.generate()?;
+                self.pg.create_vault_key(user_id, &wrapped).await?
+            }
+        };
+        Ok(Some(keys.unwrap(&wrapped)?))
+    }
+
+    /// Streams the plaintext content of an object, `range` is inclusive
+    pub async fn open_object(
+        &self,
+        obj: &CdnObject,
+        range: Option<(u64, u64)>,
+    ) -> anyhow::Result<ByteStream> {
+        let data_key = self.content_key(obj).await?;
+        self.storage
+            .open_content(
+                &obj.storage_pool,
+                &obj.storage_key(),
+                range,
+                u64::try_from(obj.content_size)?,
+                data_key.as_ref(),
+            )
+            .await
+    }
+
+    /// Reads the plaintext content of an object into memory
+    pub async fn read_object(&self, obj: &CdnObject) -> anyhow::Result<Vec<u8>> {
+        let data_key = self.content_key(obj).await?;
+        self.storage
+            .read_content(
+                &obj.storage_pool,
+                &obj.storage_key(),
+                u64::try_from(obj.content_size)?,
+                data_key.as_ref(),
+            )
+            .await
+    }
+
+    /// Claims the row of a blob before its content is written, see
+    /// [`PgClient::claim_blob`]. `None` if the blob is stored already, otherwise
+    /// the id of the claim and the key to encrypt the content with. Waits while
+    /// another upload writes the same content.
+    async fn claim_blob(
+        &self,
+        pool: &str,
+        hash: &str,
+        content_size: i64,
+    ) -> anyhow::Result<Option<(Uuid, Option<DataKey>)>> {
+        let (data_key, wrapped) = match self.storage.generate_blob_key()? {
+            Some((data_key, wrapped)) => (Some(data_key), Some(wrapped)),
+            None => (None, None),
+        };
+
+        let claim_id = Uuid::new_v4();
+        loop {
+            let claim = self
+                .pg
+                .claim_blob(
+                    hash,
+                    content_size,
+                    pool,
+                    wrapped.as_ref(),
+                    claim_id,
+                    BLOB_CLAIM_LEASE_SECS,
+                )
+                .await?;
+            match claim {
+                BlobClaim::Claimed => return Ok(Some((claim_id, data_key))),
+                BlobClaim::Stored => return Ok(None),
+                BlobClaim::Busy => tokio::time::sleep(BLOB_CLAIM_POLL).await,
+            }
+        }
+    }
+
+    /// Completes the claim on a blob once `written` says how writing its
+    /// content went, a failed upload releases the claim for the next one
+    async fn settle_blob_claim(
+        &self,
+        hash: &str,
+        claim_id: Uuid,
+        written: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if let Err(error) = written {
+            if let Err(error) = self.pg.release_blob_claim(hash, claim_id).await {
+                tracing::warn!(error = ?error, "failed to release the claim on blob {hash}");
+            }
+            return Err(error);
+        }
+
+        if !self.pg.complete_blob_claim(hash, claim_id).await? {
+            anyhow::bail!("the claim on blob {hash} ran out before its content was written");
+        }
+        Ok(())
+    }
+
+    /// Stores a file as the blob of `hash` unless another upload stored the
+    /// same content first, returns whether this one wrote it
+    pub async fn store_blob(
+        &self,
+        pool: &str,
+        hash: &str,
+        content: &mut File,
+        content_type: &str,
+    ) -> anyhow::Result<bool> {
+        let content_size = i64::try_from(content.metadata().await?.len())?;
+        let Some((claim_id, data_key)) = self.claim_blob(pool, hash, content_size).await? else {
+            return Ok(false);
+        };
+
+        let written = self
+            .storage
+            .upload_blob(pool, hash, content, content_type, data_key.as_ref())
+            .await;
+        self.settle_blob_claim(hash, claim_id, written).await?;
+        Ok(true)
+    }
+
+    /// Moves content staged under `staging_key` into the blob of `hash`, when
+    /// the blob is stored already the staged copy is just dropped. Returns
+    /// whether it wrote the blob.
+    pub async fn adopt_blob(
+        &self,
+        pool: &str,
+        staging_key: &str,
+        hash: &str,
+        content_size: i64,
+    ) -> anyhow::Result<bool> {
+        let Some((claim_id, data_key)) = self.claim_blob(pool, hash, content_size).await? else {
+            self.storage.backend(pool)?.delete(staging_key).await?;
+            return Ok(false);
+        };
+
+        let written = self
+            .storage
+            .adopt_blob(pool, staging_key, hash, data_key.as_ref())
+            .await;
+        self.settle_blob_claim(hash, claim_id, written).await?;
+        Ok(true)
+    }
+
+    /// Copies the plaintext content of an object into a temporary file, for
+    /// tools that can't read it from memory
+    pub async fn download_object(&self, obj: &CdnObject) -> anyhow::Result<NamedTempFile> {
+        let file = NamedTempFile::new()?;
+        let mut out = File::from_std(file.reopen()?);
+        let mut stream = self.open_object(obj, None).await?;
+        while let Some(chunk) = stream.next().await {
+            out.write_all(&chunk?).await?;
+        }
+        out.flush().await?;
+        Ok(file)
+    }
 }
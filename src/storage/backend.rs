@@ -0,0 +1,126 @@
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+/// A body read from storage chunk by chunk
+pub type ByteStream = Pin<Box<dyn Stream<Item = anyhow::Result<Bytes>> + Send>>;
+
+/// Metadata of an object stored in a backend
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// A part of an unfinished multipart upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadPart {
+    pub number: u32,
+    pub etag: String,
+    pub size: u64,
+}
+
+/// A place where object blobs are stored, keys are always absolute
+/// paths in the `/vaults/{user_id}/objects|thumbnails/{id}` form.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Uploads the contents of a file, returns the amount of bytes written
+    async fn put_file(
+        &self,
+        key: &str,
+        content: &mut File,
+        content_type: &str,
+    ) -> anyhow::Result<usize>;
+
+    async fn put(&self, key: &str, content: &[u8], content_type: &str) -> anyhow::Result<()>;
+
+    async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>>;
+
+    /// Streams the object, or only the bytes from `start` up to and
+    /// including `end` when a range is given
+    async fn get_stream(&self, key: &str, range: Option<(u64, u64)>) -> anyhow::Result<ByteStream>;
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()>;
+
+    /// Returns `None` if the object doesn't exist
+    async fn head(&self, key: &str) -> anyhow::Result<Option<ObjectMeta>>;
+
+    /// Lists every object whose key starts with `prefix`
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectMeta>>;
+
+    /// Starts a multipart upload, returns the upload id
+    async fn create_multipart(&self, key: &str, content_type: &str) -> anyhow::Result<String>;
+
+    /// Uploads one part of a multipart upload, returns the part's etag.
+    /// Every part but the last one has to be at least 5 MiB.
+    async fn put_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Vec<u8>,
+        content_type: &str,
+    ) -> anyhow::Result<String>;
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadPart],
+    ) -> anyhow::Result<()>;
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> anyhow::Result<()>;
+
+    /// Creates a URL a client can `PUT` the object to directly
+    async fn presign_put(&self, key: &str, _expiry_secs: u32) -> anyhow::Result<String> {
+        anyhow::bail!("storage backend can't presign uploads of {key}")
+    }
+
+    /// Creates a URL a client can `PUT` one part of a multipart upload to
+    async fn presign_part(
+        &self,
+        key: &str,
+        _upload_id: &str,
+        _number: u32,
+        _expiry_secs: u32,
+    ) -> anyhow::Result<String> {
+        anyhow::bail!("storage backend can't presign uploads of {key}")
+    }
+}
+
+/// Copies `key` from one backend to another through a temporary file,
+/// returns the amount of bytes copied or `None` if `from` doesn't have it
+pub async fn transfer(
+    from: &dyn StorageBackend,
+    to: &dyn StorageBackend,
+    key: &str,
+) -> anyhow::Result<Option<u64>> {
+    let Some(meta) = from.head(key).await? else {
+        return Ok(None);
+    };
+
+    let staged = NamedTempFile::new()?;
+    let mut out = File::from_std(staged.reopen()?);
+    let mut stream = from.get_stream(key, None).await?;
+    while let Some(chunk) = stream.next().await {
+        out.write_all(&chunk?).await?;
+    }
+    out.flush().await?;
+
+    let mut content = File::open(staged.path()).await?;
+    let content_type = meta
+        .content_type
+        .unwrap_or("application/octet-stream".to_owned());
+    let written = to.put_file(key, &mut content, &content_type).await?;
+    Ok(Some(written as u64))
+}
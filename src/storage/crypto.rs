@@ -0,0 +1,448 @@
+//! Envelope encryption of stored content.
+//!
+//! Every blob and every vault gets a random data key which is stored wrapped
+//! by a master key from config, so rotating the master key only rewraps the
+//! data keys. Content is encrypted with AES-256-GCM in fixed size chunks,
+//! which lets ranged reads decrypt just the chunks they touch.
+//!
+//! Layout: `magic (4) | base nonce (12) | chunk 0 | chunk 1 | ...`, every
+//! chunk is `CHUNK_SIZE` bytes of ciphertext plus a 16 byte tag, only the
+//! last one may be shorter. A chunk's nonce is the base nonce xored with its
+//! index, and the index plus a last chunk marker are authenticated as well
+//! so chunks can't be reordered or the content truncated.
+
+use std::{collections::HashMap, env};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use rand::Rng;
+use tempfile::NamedTempFile;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use super::ByteStream;
+
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const NONCE_SIZE: usize = 12;
+const MAGIC: &[u8; 4] = b"LKE1";
+pub const HEADER_SIZE: u64 = MAGIC.len() as u64 + NONCE_SIZE as u64;
+
+/// A key that encrypts content, never stored anywhere unwrapped
+#[derive(Clone)]
+pub struct DataKey(Aes256Gcm);
+
+/// A data key encrypted with one of the master keys
+#[derive(Clone, Debug)]
+pub struct WrappedKey {
+    pub master_key_id: String,
+    pub wrapped: Vec<u8>,
+}
+
+pub struct MasterKeys {
+    current: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+fn parse_master_key(value: &str) -> anyhow::Result<(String, Aes256Gcm)> {
+    let Some((id, key)) = value.trim().split_once(':') else {
+        anyhow::bail!("master keys have to be in the 'id:base64' form");
+    };
+
+    let key = STANDARD.decode(key)?;
+    if key.len() != 32 {
+        anyhow::bail!("master key '{id}' has to be 32 bytes long");
+    }
+
+    Ok((
+        id.to_owned(),
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+    ))
+}
+
+impl MasterKeys {
+    /// Reads `STORAGE_MASTER_KEY` and the comma separated
+    /// `STORAGE_RETIRED_MASTER_KEYS`, encryption is off when no key is set
+    pub fn from_env() -> anyhow::Result<Option<MasterKeys>> {
+        let current = env::var("STORAGE_MASTER_KEY").unwrap_or_default();
+        if current.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let (current, key) = parse_master_key(&current)?;
+        let mut keys = HashMap::from([(current.clone(), key)]);
+
+        if let Ok(retired) = env::var("STORAGE_RETIRED_MASTER_KEYS") {
+            for value in retired.split(',').filter(|v| !v.trim().is_empty()) {
+                let (id, key) = parse_master_key(value)?;
+                keys.entry(id).or_insert(key);
+            }
+        }
+
+        tracing::info!("storage encryption enabled with master key '{current}'");
+        Ok(Some(MasterKeys { current, keys }))
+    }
+
+    pub fn current_id(&self) -> &str {
+        &self.current
+    }
+
+    /// Creates a new data key, returned both usable and wrapped for storing
+    pub fn generate(&self) -> anyhow::Result<(DataKey, WrappedKey)> {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill(&mut key);
+
+        let data_key = DataKey(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+        let wrapped = self.wrap(&key)?;
+        Ok((data_key, wrapped))
+    }
+
+    fn wrap(&self, key: &[u8]) -> anyhow::Result<WrappedKey> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill(&mut nonce);
+
+        let master = &self.keys[&self.current];
+        let ciphertext = master
+            .encrypt(Nonce::from_slice(&nonce), key)
+            .map_err(|_| anyhow::anyhow!("failed to wrap data key"))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(WrappedKey {
+            master_key_id: self.current.clone(),
+            wrapped,
+        })
+    }
+
+    fn unwrap_raw(&self, wrapped: &WrappedKey) -> anyhow::Result<Vec<u8>> {
+        let Some(master) = self.keys.get(&wrapped.master_key_id) else {
+            anyhow::bail!("unknown master key '{}'", wrapped.master_key_id);
+        };
+        if wrapped.wrapped.len() <= NONCE_SIZE {
+            anyhow::bail!("wrapped data key is too short");
+        }
+
+        let (nonce, ciphertext) = wrapped.wrapped.split_at(NONCE_SIZE);
+        master
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("failed to unwrap data key"))
+    }
+
+    pub fn unwrap(&self, wrapped: &WrappedKey) -> anyhow::Result<DataKey> {
+        let key = self.unwrap_raw(wrapped)?;
+        Ok(DataKey(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+    }
+
+    /// Wraps a data key with the current master key, `None` if it already is
+    pub fn rewrap(&self, wrapped: &WrappedKey) -> anyhow::Result<Option<WrappedKey>> {
+        if wrapped.master_key_id == self.current {
+            return Ok(None);
+        }
+
+        let key = self.unwrap_raw(wrapped)?;
+        Ok(Some(self.wrap(&key)?))
+    }
+}
+
+fn chunk_nonce(base: &[u8; NONCE_SIZE], index: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = *base;
+    for (byte, idx) in nonce[4..].iter_mut().zip(index.to_be_bytes()) {
+        *byte ^= idx;
+    }
+    nonce
+}
+
+fn chunk_aad(index: u64, is_last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&index.to_be_bytes());
+    aad[8] = is_last as u8;
+    aad
+}
+
+fn chunk_count(plain_size: u64) -> u64 {
+    plain_size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+/// Size of the stored content for `plain_size` bytes of plaintext
+pub fn encrypted_size(plain_size: u64) -> u64 {
+    HEADER_SIZE + plain_size + chunk_count(plain_size) * TAG_SIZE
+}
+
+/// Where the chunks holding the plaintext range `start..=end` are stored
+pub fn ciphertext_range(start: u64, end: u64, plain_size: u64) -> (u64, u64) {
+    let first = start / CHUNK_SIZE;
+    let last = end / CHUNK_SIZE;
+    let c_start = HEADER_SIZE + first * (CHUNK_SIZE + TAG_SIZE);
+    let c_end =
+        (HEADER_SIZE + (last + 1) * (CHUNK_SIZE + TAG_SIZE)).min(encrypted_size(plain_size));
+    (c_start, c_end - 1)
+}
+
+/// Reads the base nonce out of the header of encrypted content
+pub fn parse_header(header: &[u8]) -> anyhow::Result<[u8; NONCE_SIZE]> {
+    if header.len() < HEADER_SIZE as usize || &header[..MAGIC.len()] != MAGIC {
+        anyhow::bail!("content is not encrypted");
+    }
+
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce.copy_from_slice(&header[MAGIC.len()..HEADER_SIZE as usize]);
+    Ok(nonce)
+}
+
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+impl DataKey {
+    fn seal(
+        &self,
+        base: &[u8; NONCE_SIZE],
+        index: u64,
+        is_last: bool,
+        plain: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.0
+            .encrypt(
+                Nonce::from_slice(&chunk_nonce(base, index)),
+                Payload {
+                    msg: plain,
+                    aad: &chunk_aad(index, is_last),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("failed to encrypt chunk {index}"))
+    }
+
+    fn open(
+        &self,
+        base: &[u8; NONCE_SIZE],
+        index: u64,
+        is_last: bool,
+        sealed: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.0
+            .decrypt(
+                Nonce::from_slice(&chunk_nonce(base, index)),
+                Payload {
+                    msg: sealed,
+                    aad: &chunk_aad(index, is_last),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("failed to decrypt chunk {index}"))
+    }
+
+    fn new_header() -> ([u8; NONCE_SIZE], Vec<u8>) {
+        let mut base = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill(&mut base);
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&base);
+        (base, header)
+    }
+
+    pub fn encrypt_buffer(&self, plain: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (base, mut out) = Self::new_header();
+        let count = chunk_count(plain.len() as u64);
+
+        for index in 0..count {
+            let start = (index * CHUNK_SIZE) as usize;
+            let end = (start + CHUNK_SIZE as usize).min(plain.len());
+            out.extend(self.seal(&base, index, index + 1 == count, &plain[start..end])?);
+        }
+        Ok(out)
+    }
+
+    pub fn decrypt_buffer(&self, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let base = parse_header(content)?;
+        let sealed = &content[HEADER_SIZE as usize..];
+        let sealed_chunk = (CHUNK_SIZE + TAG_SIZE) as usize;
+        let count = sealed.len().div_ceil(sealed_chunk).max(1);
+
+        let mut out = Vec::with_capacity(sealed.len());
+        for (index, chunk) in sealed.chunks(sealed_chunk).enumerate() {
+            out.extend(self.open(&base, index as u64, index + 1 == count, chunk)?);
+        }
+        Ok(out)
+    }
+
+    /// Encrypts a file of `plain_size` bytes into a temporary file
+    pub async fn encrypt_file(
+        &self,
+        input: &mut File,
+        plain_size: u64,
+    ) -> anyhow::Result<NamedTempFile> {
+        let temp = NamedTempFile::new()?;
+        let mut out = File::from_std(temp.reopen()?);
+
+        let (base, header) = Self::new_header();
+        out.write_all(&header).await?;
+
+        let count = chunk_count(plain_size);
+        let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+        for index in 0..count {
+            let len = (plain_size - index * CHUNK_SIZE).min(CHUNK_SIZE) as usize;
+            input.read_exact(&mut buffer[..len]).await?;
+            out.write_all(&self.seal(&base, index, index + 1 == count, &buffer[..len])?)
+                .await?;
+        }
+        out.flush().await?;
+
+        Ok(temp)
+    }
+
+    /// Decrypts the stored chunks starting at chunk `first` and yields the
+    /// plaintext, minus `skip` leading bytes and at most `take` bytes.
+    /// `last` is the index of the final chunk of the whole content.
+    pub fn decrypt_stream(
+        &self,
+        base: [u8; NONCE_SIZE],
+        sealed: ByteStream,
+        first: u64,
+        last: u64,
+        skip: u64,
+        take: u64,
+    ) -> ByteStream {
+        struct State {
+            key: DataKey,
+            sealed: ByteStream,
+            buffer: Vec<u8>,
+            index: u64,
+            skip: u64,
+            remaining: u64,
+        }
+
+        let state = State {
+            key: self.clone(),
+            sealed,
+            buffer: Vec::new(),
+            index: first,
+            skip,
+            remaining: take,
+        };
+        let sealed_chunk = (CHUNK_SIZE + TAG_SIZE) as usize;
+
+        let stream = stream::try_unfold(state, move |mut state| async move {
+            if state.remaining == 0 {
+                return Ok(None);
+            }
+
+            let mut ended = false;
+            while state.buffer.len() < sealed_chunk && !ended {
+                match state.sealed.next().await {
+                    Some(chunk) => state.buffer.extend_from_slice(&chunk?),
+                    None => ended = true,
+                }
+            }
+            if state.buffer.is_empty() {
+                anyhow::bail!("encrypted content ended early");
+            }
+
+            let len = state.buffer.len().min(sealed_chunk);
+            let sealed = state.buffer.drain(..len).collect::<Vec<u8>>();
+            let plain = state
+                .key
+                .open(&base, state.index, state.index == last, &sealed)?;
+            state.index += 1;
+
+            let start = (state.skip as usize).min(plain.len());
+            let end = (start as u64 + state.remaining).min(plain.len() as u64) as usize;
+            state.skip = 0;
+            state.remaining -= (end - start) as u64;
+
+            Ok(Some((Bytes::copy_from_slice(&plain[start..end]), state)))
+        });
+
+        Box::pin(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        blob_key, memory::MemoryBackend, tests::collect, StorageClient, DEFAULT_POOL,
+    };
+
+    fn master_keys() -> MasterKeys {
+        let key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[7; 32]));
+        MasterKeys {
+            current: "test".to_owned(),
+            keys: HashMap::from([("test".to_owned(), key)]),
+        }
+    }
+
+    #[test]
+    fn wrapped_keys_unwrap_to_the_same_key() {
+        let keys = master_keys();
+        let (data_key, wrapped) = keys.generate().unwrap();
+        let sealed = data_key.encrypt_buffer(b"secret").unwrap();
+
+        let unwrapped = keys.unwrap(&wrapped).unwrap();
+        assert_eq!(unwrapped.decrypt_buffer(&sealed).unwrap(), b"secret");
+        assert!(keys.rewrap(&wrapped).unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_content_is_rejected() {
+        let (data_key, _) = master_keys().generate().unwrap();
+        let mut sealed = data_key.encrypt_buffer(b"secret").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(data_key.decrypt_buffer(&sealed).is_err());
+    }
+
+    #[tokio::test]
+    async fn ranges_decrypt_across_chunks() {
+        let keys = master_keys();
+        let (data_key, _) = keys.generate().unwrap();
+        let storage = StorageClient::with_backend(Box::new(MemoryBackend::default()), Some(keys));
+
+        let plain = (0..3 * CHUNK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let file = NamedTempFile::new().unwrap();
+        tokio::fs::write(file.path(), &plain).await.unwrap();
+        let mut content = File::open(file.path()).await.unwrap();
+        let hash = "ab".repeat(32);
+        storage
+            .upload_blob(
+                DEFAULT_POOL,
+                &hash,
+                &mut content,
+                "application/octet-stream",
+                Some(&data_key),
+            )
+            .await
+            .unwrap();
+
+        let key = blob_key(&hash);
+        let size = plain.len() as u64;
+        let stored = storage.get_object(DEFAULT_POOL, &key).await.unwrap();
+        assert_eq!(stored.len() as u64, encrypted_size(size));
+
+        for (start, end) in [
+            (0, size - 1),
+            (10, 20),
+            (CHUNK_SIZE - 5, 2 * CHUNK_SIZE + 5),
+            (size - 3, size - 1),
+        ] {
+            let stream = storage
+                .open_content(
+                    DEFAULT_POOL,
+                    &key,
+                    Some((start, end)),
+                    size,
+                    Some(&data_key),
+                )
+                .await
+                .unwrap();
+            assert_eq!(collect(stream).await, &plain[start as usize..=end as usize]);
+        }
+    }
+}
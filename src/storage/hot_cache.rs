@@ -0,0 +1,434 @@
+//! Keeps popular public objects close, so a link that goes viral doesn't turn
+//! every download into a postgres query and a storage read. Content is cached
+//! in memory and, when `HOT_CACHE_DISK_PATH` is set, on local disk. Entries are
+//! keyed by object id and content hash, so a new version of an object is never
+//! answered with the old content.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::db::CdnObject;
+
+/// Slug lookups kept at most, the map is pruned when it grows past this
+const MAX_CACHED_SLUGS: usize = 10_000;
+
+type CacheKey = (Uuid, String);
+
+/// Least recently used index bounded by the size of its entries
+struct Lru<V> {
+    /// Value, size and the tick it was last used at
+    entries: HashMap<CacheKey, (V, u64, u64)>,
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    used: u64,
+    capacity: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: u64) -> Lru<V> {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            used: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<V> {
+        let (value, _, tick) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    /// Inserts an entry, returns the entries evicted to make room for it
+    fn insert(&mut self, key: CacheKey, value: V, size: u64) -> Vec<CacheKey> {
+        self.remove(&key);
+
+        let mut evicted = Vec::new();
+        while self.used + size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&oldest) {
+                self.used -= size;
+            }
+            evicted.push(oldest);
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        self.used += size;
+        evicted
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<V> {
+        let (value, size, tick) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        self.used -= size;
+        Some(value)
+    }
+
+    /// Removes every entry of an object, returns their keys
+    fn remove_object(&mut self, id: Uuid) -> Vec<CacheKey> {
+        let keys = self
+            .entries
+            .keys()
+            .filter(|key| key.0 == id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys
+    }
+}
+
+/// Content cached as files named `{id}.{hash}`
+struct DiskTier {
+    path: PathBuf,
+    index: Mutex<Lru<()>>,
+}
+
+impl DiskTier {
+    async fn open(path: PathBuf, capacity: u64) -> anyhow::Result<DiskTier> {
+        tokio::fs::create_dir_all(&path).await?;
+
+        // files of a previous run aren't indexed, so they would never be evicted
+        let mut dir = tokio::fs::read_dir(&path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name();
+            let is_ours = name
+                .to_str()
+                .and_then(|name| name.split('.').next())
+                .is_some_and(|id| id.parse::<Uuid>().is_ok());
+            if is_ours {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(DiskTier {
+            path,
+            index: Mutex::new(Lru::new(capacity)),
+        })
+    }
+
+    fn file_path(&self, key: &CacheKey) -> PathBuf {
+        self.path.join(format!("{}.{}", key.0, key.1))
+    }
+
+    async fn read(&self, key: &CacheKey) -> Option<Bytes> {
+        self.index.lock().unwrap().get(key)?;
+
+        match tokio::fs::read(self.file_path(key)).await {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(error) => {
+                tracing::warn!(error = ?error, "hot cache file of object {} is unreadable", key.0);
+                self.index.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    /// Writes `data` and returns how many files were evicted for it
+    async fn write(&self, key: &CacheKey, data: &[u8]) -> anyhow::Result<u64> {
+        let path = self.file_path(key);
+        let partial = self.path.join(format!("{}.{}.partial", key.0, key.1));
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, &path).await?;
+
+        let evicted = self
+            .index
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (), data.len() as u64);
+        self.remove_files(&evicted).await;
+        Ok(evicted.len() as u64)
+    }
+
+    async fn remove_files(&self, keys: &[CacheKey]) {
+        for key in keys {
+            if let Err(error) = tokio::fs::remove_file(self.file_path(key)).await {
+                tracing::warn!(error = ?error, "failed to remove hot cache file of object {}", key.0);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    slug_hits: AtomicU64,
+    slug_misses: AtomicU64,
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    /// Misses that waited for a fetch another request already started
+    coalesced: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct HotCacheStats {
+    pub slug_hits: u64,
+    pub slug_misses: u64,
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub coalesced: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Share of content requests that didn't fetch from storage
+    pub hit_rate: f64,
+    pub memory_bytes: u64,
+    pub memory_entries: usize,
+    pub disk_bytes: u64,
+    pub disk_entries: usize,
+}
+
+pub struct HotCache {
+    memory: Mutex<Lru<Bytes>>,
+    disk: Option<DiskTier>,
+    /// Objects looked up by slug and when they were looked up
+    slugs: Mutex<HashMap<String, (CdnObject, Instant)>>,
+    /// How long a slug lookup is trusted, this bounds how long another
+    /// instance keeps serving an object deleted through this one
+    slug_ttl: Duration,
+    /// Objects above this size are always streamed from storage
+    max_object_size: u64,
+    /// Fetches in progress, concurrent misses for the same content wait for
+    /// the first one instead of reading it from storage again
+    inflight: Mutex<HashMap<CacheKey, Arc<OnceCell<Bytes>>>>,
+    counters: Counters,
+}
+
+impl HotCache {
+    /// Reads the cache size from `HOT_CACHE_*`. Content is cached decrypted,
+    /// so the disk tier stays off while content is encrypted at rest.
+    pub async fn from_env(encrypted: bool) -> anyhow::Result<HotCache> {
+        let var = |name: &str, default: u64| -> anyhow::Result<u64> {
+            match env::var(name) {
+                Ok(v) if !v.is_empty() => Ok(v.parse()?),
+                _ => Ok(default),
+            }
+        };
+
+        let memory_bytes = var("HOT_CACHE_MEMORY_BYTES", 256 * 1024 * 1024)?;
+        let max_object_size = var("HOT_CACHE_MAX_OBJECT_BYTES", 32 * 1024 * 1024)?;
+        let slug_ttl = Duration::from_secs(var("HOT_CACHE_SLUG_TTL_SECS", 30)?);
+
+        let disk = match env::var("HOT_CACHE_DISK_PATH") {
+            Ok(path) if !path.is_empty() && encrypted => {
+                tracing::warn!("not caching objects on disk, they are encrypted at rest");
+                None
+            }
+            Ok(path) if !path.is_empty() => {
+                let capacity = var("HOT_CACHE_DISK_BYTES", 4 * 1024 * 1024 * 1024)?;
+                Some(DiskTier::open(path.into(), capacity).await?)
+            }
+            _ => None,
+        };
+
+        Ok(HotCache {
+            memory: Mutex::new(Lru::new(memory_bytes)),
+            disk,
+            slugs: Mutex::new(HashMap::new()),
+            slug_ttl,
+            max_object_size,
+            inflight: Mutex::new(HashMap::new()),
+            counters: Counters::default(),
+        })
+    }
+
+    /// Whether content of `size` bytes is served through the cache
+    pub fn admits(&self, size: u64) -> bool {
+        let capacity = self.memory.lock().unwrap().capacity;
+        size <= self.max_object_size && (size <= capacity || self.disk.is_some())
+    }
+
+    /// The object a slug pointed at, if it was looked up recently
+    pub fn lookup_slug(&self, slug: &str) -> Option<CdnObject> {
+        let slugs = self.slugs.lock().unwrap();
+        let cached = slugs
+            .get(slug)
+            .filter(|(_, cached_at)| cached_at.elapsed() < self.slug_ttl)
+            .map(|(obj, _)| obj.clone());
+
+        let counter = match cached {
+            Some(_) => &self.counters.slug_hits,
+            None => &self.counters.slug_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    pub fn remember_slug(&self, slug: &str, obj: &CdnObject) {
+        if self.slug_ttl.is_zero() {
+            return;
+        }
+
+        let mut slugs = self.slugs.lock().unwrap();
+        if slugs.len() >= MAX_CACHED_SLUGS {
+            slugs.retain(|_, (_, cached_at)| cached_at.elapsed() < self.slug_ttl);
+            if slugs.len() >= MAX_CACHED_SLUGS {
+                slugs.clear();
+            }
+        }
+        slugs.insert(slug.to_owned(), (obj.clone(), Instant::now()));
+    }
+
+    /// The content of `obj`, fetched with `fetch` when it isn't cached
+    pub async fn get_or_fetch<F, Fut>(&self, obj: &CdnObject, fetch: F) -> anyhow::Result<Bytes>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+    {
+        let key = (obj.id, obj.sha256_hash.clone());
+        if let Some(data) = self.memory.lock().unwrap().get(&key) {
+            self.counters.memory_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let mut loaded = false;
+        let result = cell
+            .get_or_try_init(|| {
+                loaded = true;
+                self.load(&key, fetch)
+            })
+            .await
+            .cloned();
+
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(&key).is_some_and(|v| Arc::ptr_eq(v, &cell)) {
+            inflight.remove(&key);
+        }
+        if !loaded && result.is_ok() {
+            self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn load<F, Fut>(&self, key: &CacheKey, fetch: F) -> anyhow::Result<Bytes>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+    {
+        if let Some(disk) = &self.disk {
+            if let Some(data) = disk.read(key).await {
+                self.counters.disk_hits.fetch_add(1, Ordering::Relaxed);
+                self.insert_memory(key, &data);
+                return Ok(data);
+            }
+        }
+
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let data = Bytes::from(fetch().await?);
+        self.insert_memory(key, &data);
+
+        if let Some(disk) = &self.disk {
+            match disk.write(key, &data).await {
+                Ok(evicted) => {
+                    self.counters
+                        .evictions
+                        .fetch_add(evicted, Ordering::Relaxed);
+                }
+                Err(error) => {
+                    tracing::warn!(error = ?error, "failed to cache object {} on disk", key.0);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn insert_memory(&self, key: &CacheKey, data: &Bytes) {
+        let mut memory = self.memory.lock().unwrap();
+        if data.len() as u64 > memory.capacity {
+            return;
+        }
+
+        let evicted = memory.insert(key.clone(), data.clone(), data.len() as u64);
+        self.counters
+            .evictions
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Forgets everything cached about an object, called when it's deleted,
+    /// unpublished or gets new content
+    pub async fn invalidate(&self, id: Uuid) {
+        self.slugs
+            .lock()
+            .unwrap()
+            .retain(|_, (obj, _)| obj.id != id);
+        self.memory.lock().unwrap().remove_object(id);
+
+        if let Some(disk) = &self.disk {
+            let removed = disk.index.lock().unwrap().remove_object(id);
+            disk.remove_files(&removed).await;
+        }
+    }
+
+    pub fn stats(&self) -> HotCacheStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let memory_hits = load(&self.counters.memory_hits);
+        let disk_hits = load(&self.counters.disk_hits);
+        let coalesced = load(&self.counters.coalesced);
+        let misses = load(&self.counters.misses);
+
+        let hits = memory_hits + disk_hits + coalesced;
+        let hit_rate = match hits + misses {
+            0 => 0.0,
+            total => hits as f64 / total as f64,
+        };
+
+        let (memory_bytes, memory_entries) = {
+            let memory = self.memory.lock().unwrap();
+            (memory.used, memory.entries.len())
+        };
+        let (disk_bytes, disk_entries) = match &self.disk {
+            Some(disk) => {
+                let index = disk.index.lock().unwrap();
+                (index.used, index.entries.len())
+            }
+            None => (0, 0),
+        };
+
+        HotCacheStats {
+            slug_hits: load(&self.counters.slug_hits),
+            slug_misses: load(&self.counters.slug_misses),
+            memory_hits,
+            disk_hits,
+            coalesced,
+            misses,
+            evictions: load(&self.counters.evictions),
+            hit_rate,
+            memory_bytes,
+            memory_entries,
+            disk_bytes,
+            disk_entries,
+        }
+    }
+}
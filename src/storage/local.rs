@@ -0,0 +1,243 @@
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use futures::StreamExt;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use super::backend::{ByteStream, ObjectMeta, StorageBackend, UploadPart};
+
+/// Stores objects as plain files in a local directory, keys map 1:1 to paths
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub async fn new(root: PathBuf) -> anyhow::Result<LocalBackend> {
+        tokio::fs::create_dir_all(&root).await?;
+
+        tracing::info!("using local storage at {}", root.display());
+        Ok(LocalBackend { root })
+    }
+
+    fn path_of(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let relative = Path::new(key.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("invalid storage key {key}");
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    /// Parts of unfinished multipart uploads live outside of the key space
+    fn multipart_dir(&self, upload_id: &str) -> anyhow::Result<PathBuf> {
+        let upload_id: Uuid = upload_id.parse()?;
+        Ok(self.root.join(".multipart").join(upload_id.to_string()))
+    }
+
+    fn key_of(&self, path: &Path) -> anyhow::Result<String> {
+        let relative = path.strip_prefix(&self.root)?;
+        Ok(format!("/{}", relative.to_string_lossy()))
+    }
+
+    /// Writes into a temporary sibling first so readers never see a partial file
+    async fn temp_path_for(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let Some(parent) = path.parent() else {
+            anyhow::bail!("storage path {} has no parent", path.display());
+        };
+        tokio::fs::create_dir_all(parent).await?;
+        Ok(parent.join(format!(".tmp-{}", Uuid::new_v4())))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put_file(
+        &self,
+        key: &str,
+        content: &mut File,
+        _content_type: &str,
+    ) -> anyhow::Result<usize> {
+        let path = self.path_of(key)?;
+        let temp_path = self.temp_path_for(&path).await?;
+
+        let mut out = File::create(&temp_path).await?;
+        let written = tokio::io::copy(content, &mut out).await?;
+        out.flush().await?;
+        drop(out);
+
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(written.try_into()?)
+    }
+
+    async fn put(&self, key: &str, content: &[u8], _content_type: &str) -> anyhow::Result<()> {
+        let path = self.path_of(key)?;
+        let temp_path = self.temp_path_for(&path).await?;
+
+        tokio::fs::write(&temp_path, content).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self.path_of(key)?;
+        Ok(tokio::fs::read(path).await?)
+    }
+
+    async fn get_stream(&self, key: &str, range: Option<(u64, u64)>) -> anyhow::Result<ByteStream> {
+        let path = self.path_of(key)?;
+        let mut file = File::open(path).await?;
+
+        let Some((start, end)) = range else {
+            return Ok(Box::pin(
+                ReaderStream::new(file).map(|chunk| chunk.map_err(anyhow::Error::from)),
+            ));
+        };
+
+        file.seek(SeekFrom::Start(start)).await?;
+        let reader = file.take(end - start + 1);
+        Ok(Box::pin(
+            ReaderStream::new(reader).map(|chunk| chunk.map_err(anyhow::Error::from)),
+        ))
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let path = self.path_of(key)?;
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => Ok(()),
+            // s3 doesn't complain about deleting missing keys either
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let from = self.path_of(from)?;
+        let to = self.path_of(to)?;
+        let temp_path = self.temp_path_for(&to).await?;
+
+        tokio::fs::copy(&from, &temp_path).await?;
+        tokio::fs::rename(&temp_path, &to).await?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> anyhow::Result<Option<ObjectMeta>> {
+        let path = self.path_of(key)?;
+        match tokio::fs::metadata(&path).await {
+            Ok(meta) if meta.is_file() => Ok(Some(ObjectMeta {
+                key: key.to_owned(),
+                size: meta.len(),
+                content_type: None,
+                last_modified: meta.modified().ok().map(DateTime::from),
+            })),
+            Ok(_) => Ok(None),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectMeta>> {
+        // walk from the deepest directory fully contained in the prefix
+        let dir_prefix = match prefix.rfind('/') {
+            Some(idx) => &prefix[..idx],
+            None => "",
+        };
+        let start = self.path_of(dir_prefix)?;
+
+        let mut objects = Vec::new();
+        let mut pending = vec![start];
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(v) => v,
+                Err(error) if error.kind() == ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let path = entry.path();
+                let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if file_type.is_dir() && !is_hidden {
+                    pending.push(path);
+                    continue;
+                }
+
+                let key = self.key_of(&path)?;
+                if !file_type.is_file() || is_hidden || !key.starts_with(prefix) {
+                    continue;
+                }
+
+                let meta = entry.metadata().await?;
+                objects.push(ObjectMeta {
+                    key,
+                    size: meta.len(),
+                    content_type: None,
+                    last_modified: meta.modified().ok().map(DateTime::from),
+                });
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn create_multipart(&self, _key: &str, _content_type: &str) -> anyhow::Result<String> {
+        let upload_id = Uuid::new_v4().to_string();
+        tokio::fs::create_dir_all(self.multipart_dir(&upload_id)?).await?;
+        Ok(upload_id)
+    }
+
+    async fn put_part(
+        &self,
+        _key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Vec<u8>,
+        _content_type: &str,
+    ) -> anyhow::Result<String> {
+        let path = self.multipart_dir(upload_id)?.join(number.to_string());
+        tokio::fs::write(path, content).await?;
+        Ok(number.to_string())
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadPart],
+    ) -> anyhow::Result<()> {
+        let dir = self.multipart_dir(upload_id)?;
+        let path = self.path_of(key)?;
+        let temp_path = self.temp_path_for(&path).await?;
+
+        let mut out = File::create(&temp_path).await?;
+        for part in parts {
+            let mut part_file = File::open(dir.join(part.number.to_string())).await?;
+            tokio::io::copy(&mut part_file, &mut out).await?;
+        }
+        out.flush().await?;
+        drop(out);
+
+        tokio::fs::rename(&temp_path, &path).await?;
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, _key: &str, upload_id: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_dir_all(self.multipart_dir(upload_id)?).await {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
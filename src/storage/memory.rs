@@ -0,0 +1,164 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use tokio::{fs::File, io::AsyncReadExt};
+use uuid::Uuid;
+
+use super::backend::{ByteStream, ObjectMeta, StorageBackend, UploadPart};
+
+/// Keeps objects in a map, for tests that shouldn't need s3 or a directory
+#[derive(Default)]
+pub struct MemoryBackend {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+    /// Parts of unfinished multipart uploads by upload id
+    uploads: Mutex<HashMap<String, HashMap<u32, Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+    fn read(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        match self.objects.lock().unwrap().get(key) {
+            Some(content) => Ok(content.clone()),
+            None => anyhow::bail!("no such key {key}"),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn put_file(
+        &self,
+        key: &str,
+        content: &mut File,
+        _content_type: &str,
+    ) -> anyhow::Result<usize> {
+        let mut buf = Vec::new();
+        content.read_to_end(&mut buf).await?;
+        let written = buf.len();
+        self.objects.lock().unwrap().insert(key.to_owned(), buf);
+        Ok(written)
+    }
+
+    async fn put(&self, key: &str, content: &[u8], _content_type: &str) -> anyhow::Result<()> {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), content.to_vec());
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.read(key)
+    }
+
+    async fn get_stream(&self, key: &str, range: Option<(u64, u64)>) -> anyhow::Result<ByteStream> {
+        let content = self.read(key)?;
+        let content = match range {
+            Some((start, end)) => {
+                let end = (end + 1).min(content.len() as u64);
+                content[start as usize..end as usize].to_vec()
+            }
+            None => content,
+        };
+
+        // small chunks so readers have to stitch them together like with s3
+        let chunks = content
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        Ok(Box::pin(futures::stream::iter(chunks)))
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let content = self.read(from)?;
+        self.objects.lock().unwrap().insert(to.to_owned(), content);
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> anyhow::Result<Option<ObjectMeta>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|content| ObjectMeta {
+                key: key.to_owned(),
+                size: content.len() as u64,
+                content_type: None,
+                last_modified: Some(Utc::now()),
+            }))
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectMeta>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, content)| ObjectMeta {
+                key: key.clone(),
+                size: content.len() as u64,
+                content_type: None,
+                last_modified: Some(Utc::now()),
+            })
+            .collect())
+    }
+
+    async fn create_multipart(&self, _key: &str, _content_type: &str) -> anyhow::Result<String> {
+        let upload_id = Uuid::new_v4().to_string();
+        self.uploads
+            .lock()
+            .unwrap()
+            .insert(upload_id.clone(), HashMap::new());
+        Ok(upload_id)
+    }
+
+    async fn put_part(
+        &self,
+        _key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Vec<u8>,
+        _content_type: &str,
+    ) -> anyhow::Result<String> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let Some(parts) = uploads.get_mut(upload_id) else {
+            anyhow::bail!("no such upload {upload_id}");
+        };
+        parts.insert(number, content);
+        Ok(number.to_string())
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadPart],
+    ) -> anyhow::Result<()> {
+        let Some(mut stored) = self.uploads.lock().unwrap().remove(upload_id) else {
+            anyhow::bail!("no such upload {upload_id}");
+        };
+
+        let mut content = Vec::new();
+        for part in parts {
+            let Some(part) = stored.remove(&part.number) else {
+                anyhow::bail!("part {} of upload {upload_id} is missing", part.number);
+            };
+            content.extend_from_slice(&part);
+        }
+        self.objects.lock().unwrap().insert(key.to_owned(), content);
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, _key: &str, upload_id: &str) -> anyhow::Result<()> {
+        self.uploads.lock().unwrap().remove(upload_id);
+        Ok(())
+    }
+}
@@ -1,129 +1,381 @@
-use std::env;
+use std::{collections::HashMap, env};
 
-use s3::{creds::Credentials, Bucket, Region};
-use tokio::fs::File;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::{fs::File, io::AsyncWriteExt};
 use uuid::Uuid;
 
+mod backend;
+pub mod crypto;
+mod hot_cache;
+mod local;
+#[cfg(test)]
+mod memory;
+mod pool;
+mod replica;
+mod s3;
+
+pub use self::s3::{S3Backend, S3Config};
+pub use backend::{transfer, ByteStream, ObjectMeta, StorageBackend, UploadPart};
+use crypto::{DataKey, MasterKeys, WrappedKey};
+pub use hot_cache::{HotCache, HotCacheStats};
+pub use local::LocalBackend;
+pub use pool::{StoragePool, DEFAULT_POOL};
+pub use replica::{Replication, ReplicationStats};
+
 pub struct StorageClient {
-    pub inner: Box<Bucket>,
+    pools: HashMap<String, StoragePool>,
+    /// The pool new uploads are stored in
+    pub default_pool: String,
+    /// Set when content is encrypted at rest
+    pub keys: Option<MasterKeys>,
+}
+
+pub fn object_key(user_id: Uuid, object_id: Uuid) -> String {
+    format!("/vaults/{user_id}/objects/{object_id}")
+}
+
+/// Where thumbnails were kept before they were rendered as variants, only
+/// still used to delete them
+pub fn thumb_key(user_id: Uuid, object_id: Uuid) -> String {
+    format!("/vaults/{user_id}/thumbnails/{object_id}")
+}
+
+/// Where the resized and re-encoded images of an object are kept
+pub fn variants_prefix(user_id: Uuid, object_id: Uuid) -> String {
+    format!("/vaults/{user_id}/variants/{object_id}/")
+}
+
+pub fn variant_key(user_id: Uuid, object_id: Uuid, name: &str) -> String {
+    format!("{}{name}", variants_prefix(user_id, object_id))
 }
 
-pub struct UploadedObject {
-    pub id: Uuid,
-    pub size: usize,
+/// Content-addressed location of a blob, shared by every object with this hash
+pub fn blob_key(hash: &str) -> String {
+    format!("/blobs/{}/{hash}", &hash[..2])
 }
 
-pub struct UploadedThumb {
-    pub size: usize,
-    pub buffer: Vec<u8>,
+/// Holds what the server keeps of an upload in progress, the not yet flushed
+/// tail of a resumable upload or the copy of a presigned one being verified
+pub fn upload_key(user_id: Uuid, upload_id: Uuid) -> String {
+    format!("/vaults/{user_id}/uploads/{upload_id}")
 }
 
 impl StorageClient {
+    /// Connects every configured pool, new uploads go to `STORAGE_DEFAULT_POOL`
     pub async fn new() -> anyhow::Result<StorageClient> {
-        let access_key =
-            env::var("S3_ACCESS_KEY").expect("environment variable 'S3_ACCESS_KEY' is not set");
-        let secret_key =
-            env::var("S3_SECRET_KEY").expect("environment variable 'S3_SECRET_KEY' is not set");
-        let endpoint_uri =
-            env::var("S3_ENDPOINT").expect("environment variable 'S3_ENDPOINT' is not set");
-
-        let mut client = Bucket::new(
-            "linkersh",
-            Region::Custom {
-                region: "eu-central-1".to_owned(),
-                endpoint: endpoint_uri,
-            },
-            Credentials {
-                access_key: Some(access_key),
-                secret_key: Some(secret_key),
-                expiration: None,
-                security_token: None,
-                session_token: None,
-            },
-        )?;
-        client.set_path_style();
-        client.set_listobjects_v2();
-
-        if let Err(error) = client.head_object("/").await {
-            panic!("{}", error);
+        let pools = pool::load_pools().await?;
+        let default_pool = match env::var("STORAGE_DEFAULT_POOL") {
+            Ok(v) if !v.is_empty() => v,
+            _ => DEFAULT_POOL.to_owned(),
+        };
+        if !pools.contains_key(&default_pool) {
+            anyhow::bail!("the default storage pool '{default_pool}' isn't configured");
         }
 
-        tracing::info!("connected to s3 bucket");
-        Ok(StorageClient { inner: client })
+        let keys = MasterKeys::from_env()?;
+        Ok(StorageClient {
+            pools,
+            default_pool,
+            keys,
+        })
+    }
+
+    /// A client with `backend` as its only pool, for tests
+    #[cfg(test)]
+    pub fn with_backend(
+        backend: Box<dyn StorageBackend>,
+        keys: Option<MasterKeys>,
+    ) -> StorageClient {
+        let pool = StoragePool {
+            backend,
+            replication: None,
+        };
+        StorageClient {
+            pools: HashMap::from([(DEFAULT_POOL.to_owned(), pool)]),
+            default_pool: DEFAULT_POOL.to_owned(),
+            keys,
+        }
+    }
+
+    pub fn pool(&self, name: &str) -> anyhow::Result<&StoragePool> {
+        match self.pools.get(name) {
+            Some(pool) => Ok(pool),
+            None => anyhow::bail!("storage pool '{name}' isn't configured"),
+        }
     }
 
-    pub async fn upload_user_object(
+    /// The backend of a pool, objects name theirs in `storage_pool`
+    pub fn backend(&self, pool: &str) -> anyhow::Result<&dyn StorageBackend> {
+        Ok(self.pool(pool)?.backend.as_ref())
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = (&String, &StoragePool)> {
+        self.pools.iter()
+    }
+
+    /// A data key for a new blob, `None` when encryption is off
+    pub fn generate_blob_key(&self) -> anyhow::Result<Option<(DataKey, WrappedKey)>> {
+        self.keys.as_ref().map(|keys| keys.generate()).transpose()
+    }
+
+    /// Stores the content of a file as the blob of `hash`, encrypted with
+    /// `data_key` if there is one
+    pub async fn upload_blob(
         &self,
-        user_id: Uuid,
+        pool: &str,
+        hash: &str,
         content: &mut File,
         content_type: &str,
-    ) -> anyhow::Result<UploadedObject> {
-        let id = Uuid::new_v4();
-        let response = self
-            .inner
-            .put_object_stream_with_content_type(
-                content,
-                format!("/vaults/{user_id}/objects/{id}"),
-                &content_type,
+        data_key: Option<&DataKey>,
+    ) -> anyhow::Result<()> {
+        let key = blob_key(hash);
+        let backend = self.backend(pool)?;
+        let Some(data_key) = data_key else {
+            backend.put_file(&key, content, content_type).await?;
+            return Ok(());
+        };
+
+        let plain_size = content.metadata().await?.len();
+        let encrypted = data_key.encrypt_file(content, plain_size).await?;
+
+        let mut encrypted_file = File::open(encrypted.path()).await?;
+        backend
+            .put_file(&key, &mut encrypted_file, content_type)
+            .await?;
+        Ok(())
+    }
+
+    /// Moves an object that was uploaded to a staging key into its blob in
+    /// the same pool, encrypted with `data_key` if there is one
+    pub async fn adopt_blob(
+        &self,
+        pool: &str,
+        staging_key: &str,
+        hash: &str,
+        data_key: Option<&DataKey>,
+    ) -> anyhow::Result<()> {
+        let backend = self.backend(pool)?;
+        if data_key.is_none() {
+            backend.copy(staging_key, &blob_key(hash)).await?;
+        } else {
+            // staged uploads arrive in plaintext, so they have to be re-encrypted
+            let staged = NamedTempFile::new()?;
+            let mut out = File::from_std(staged.reopen()?);
+            let mut stream = backend.get_stream(staging_key, None).await?;
+            while let Some(chunk) = stream.next().await {
+                out.write_all(&chunk?).await?;
+            }
+            out.flush().await?;
+
+            let mut staged_file = File::open(staged.path()).await?;
+            self.upload_blob(
+                pool,
+                hash,
+                &mut staged_file,
+                "application/octet-stream",
+                data_key,
             )
             .await?;
+        }
 
-        Ok(UploadedObject {
-            id,
-            size: response.uploaded_bytes(),
-        })
+        backend.delete(staging_key).await
     }
 
-    pub async fn upload_object_thumb(
+    /// Stores an image variant or thumbnail rendered from an object, encrypted
+    /// with the vault's key if there is one
+    pub async fn put_derived(
         &self,
-        user_id: Uuid,
-        object_id: Uuid,
-        content: Vec<u8>,
+        pool: &str,
+        key: &str,
+        content: &[u8],
         content_type: &str,
-    ) -> anyhow::Result<UploadedThumb> {
-        self.inner
-            .put_object_with_content_type(
-                format!("/vaults/{user_id}/thumbnails/{object_id}"),
-                &content,
-                content_type,
-            )
-            .await?;
+        vault_key: Option<&DataKey>,
+    ) -> anyhow::Result<()> {
+        let backend = self.backend(pool)?;
+        match vault_key {
+            Some(vault_key) => {
+                let encrypted = vault_key.encrypt_buffer(content)?;
+                backend.put(key, &encrypted, content_type).await
+            }
+            None => backend.put(key, content, content_type).await,
+        }
+    }
 
-        Ok(UploadedThumb {
-            size: content.len(),
-            buffer: content,
-        })
+    pub async fn get_derived(
+        &self,
+        pool: &str,
+        key: &str,
+        vault_key: Option<&DataKey>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let content = self.backend(pool)?.get(key).await?;
+
+        // thumbnails written before encryption was turned on are plaintext
+        match vault_key {
+            Some(vault_key) if crypto::is_encrypted(&content) => vault_key.decrypt_buffer(&content),
+            _ => Ok(content),
+        }
     }
 
-    pub async fn delete_user_object(&self, user_id: Uuid, object_id: Uuid) -> anyhow::Result<()> {
-        self.inner
-            .delete_object(format!("/vaults/{user_id}/objects/{object_id}"))
-            .await?;
+    /// Streams the plaintext of an object of `plain_size` bytes, decrypting
+    /// it with `data_key` if it is encrypted. `range` is inclusive.
+    pub async fn open_content(
+        &self,
+        pool: &str,
+        key: &str,
+        range: Option<(u64, u64)>,
+        plain_size: u64,
+        data_key: Option<&DataKey>,
+    ) -> anyhow::Result<ByteStream> {
+        let Some(data_key) = data_key else {
+            return self.stream_object(pool, key, range).await;
+        };
+        if plain_size == 0 {
+            return Ok(Box::pin(futures::stream::empty()));
+        }
 
-        self.inner
-            .delete_object(format!("/vaults/{user_id}/thumbnails/{object_id}"))
+        let mut header = Vec::new();
+        let mut header_stream = self
+            .stream_object(pool, key, Some((0, crypto::HEADER_SIZE - 1)))
             .await?;
+        while let Some(chunk) = header_stream.next().await {
+            header.extend_from_slice(&chunk?);
+        }
+        let base_nonce = crypto::parse_header(&header)?;
 
-        Ok(())
+        let (start, end) = range.unwrap_or((0, plain_size - 1));
+        let sealed_range = crypto::ciphertext_range(start, end, plain_size);
+        let sealed = self.stream_object(pool, key, Some(sealed_range)).await?;
+
+        let first = start / crypto::CHUNK_SIZE;
+        let last = (plain_size - 1) / crypto::CHUNK_SIZE;
+        Ok(data_key.decrypt_stream(
+            base_nonce,
+            sealed,
+            first,
+            last,
+            start - first * crypto::CHUNK_SIZE,
+            end - start + 1,
+        ))
     }
 
-    pub async fn get_object_thumb(
+    /// Reads the whole plaintext of an object into memory, only meant for small objects
+    pub async fn read_content(
         &self,
-        user_id: Uuid,
-        object_id: Uuid,
+        pool: &str,
+        key: &str,
+        plain_size: u64,
+        data_key: Option<&DataKey>,
     ) -> anyhow::Result<Vec<u8>> {
-        let content = self
-            .inner
-            .get_object(format!("/vaults/{user_id}/thumbnails/{object_id}"))
-            .await?;
-        Ok(content.to_vec())
+        let Some(data_key) = data_key else {
+            return self.get_object(pool, key).await;
+        };
+        if plain_size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let content = self.get_object(pool, key).await?;
+        data_key.decrypt_buffer(&content)
     }
 
-    pub async fn get_user_object(&self, user_id: Uuid, object_id: Uuid) -> anyhow::Result<Vec<u8>> {
-        let content = self
-            .inner
-            .get_object(format!("/vaults/{user_id}/objects/{object_id}"))
-            .await?;
-        Ok(content.to_vec())
+    /// Reads a whole object into memory, only meant for small objects
+    pub async fn get_object(&self, pool: &str, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.backend(pool)?.get(key).await
+    }
+
+    /// Streams an object instead of loading it into memory, `range` is inclusive
+    pub async fn stream_object(
+        &self,
+        pool: &str,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> anyhow::Result<ByteStream> {
+        self.backend(pool)?.get_stream(key, range).await
+    }
+
+    /// Computes the SHA-256 of a stored object without loading it into memory
+    pub async fn hash_object(&self, pool: &str, key: &str) -> anyhow::Result<String> {
+        let mut stream = self.stream_object(pool, key, None).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use memory::MemoryBackend;
+
+    const HASH: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    pub async fn collect(mut stream: ByteStream) -> Vec<u8> {
+        let mut content = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk.unwrap());
+        }
+        content
+    }
+
+    fn temp_file(content: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn stores_and_reads_plaintext_blobs() {
+        let storage = StorageClient::with_backend(Box::new(MemoryBackend::default()), None);
+        let file = temp_file(b"hello world");
+        let mut content = File::open(file.path()).await.unwrap();
+        storage
+            .upload_blob(DEFAULT_POOL, HASH, &mut content, "text/plain", None)
+            .await
+            .unwrap();
+
+        let key = blob_key(HASH);
+        let read = storage
+            .read_content(DEFAULT_POOL, &key, 11, None)
+            .await
+            .unwrap();
+        assert_eq!(read, b"hello world");
+        assert_eq!(storage.hash_object(DEFAULT_POOL, &key).await.unwrap(), HASH);
+
+        let range = storage
+            .open_content(DEFAULT_POOL, &key, Some((6, 10)), 11, None)
+            .await
+            .unwrap();
+        assert_eq!(collect(range).await, b"world");
+    }
+
+    #[tokio::test]
+    async fn adopting_a_staged_upload_moves_it_into_its_blob() {
+        let storage = StorageClient::with_backend(Box::new(MemoryBackend::default()), None);
+        let backend = storage.backend(DEFAULT_POOL).unwrap();
+        let staging_key = upload_key(Uuid::nil(), Uuid::nil());
+        backend
+            .put(&staging_key, b"hello world", "text/plain")
+            .await
+            .unwrap();
+
+        storage
+            .adopt_blob(DEFAULT_POOL, &staging_key, HASH, None)
+            .await
+            .unwrap();
+        assert!(backend.head(&staging_key).await.unwrap().is_none());
+        assert_eq!(backend.get(&blob_key(HASH)).await.unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn unknown_pools_are_an_error() {
+        let storage = StorageClient::with_backend(Box::new(MemoryBackend::default()), None);
+        assert!(storage.backend(DEFAULT_POOL).is_ok());
+        assert!(storage.backend("archive").is_err());
     }
 }
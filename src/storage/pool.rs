@@ -0,0 +1,85 @@
+//! Named places content is stored in. Every object records the pool that
+//! holds it, so new uploads can go to a new pool while older objects are
+//! still read from wherever they were stored.
+
+use std::{collections::HashMap, env, path::PathBuf, sync::Arc};
+
+use super::{
+    replica::ReplicatedBackend, LocalBackend, Replication, S3Backend, S3Config, StorageBackend,
+};
+
+/// The pool objects stored before pools existed belong to
+pub const DEFAULT_POOL: &str = "default";
+
+pub struct StoragePool {
+    pub backend: Box<dyn StorageBackend>,
+    /// Set when writes are mirrored to a replica bucket
+    pub replication: Option<Arc<Replication>>,
+}
+
+impl StoragePool {
+    /// Connects a pool of `kind` (`s3` or `local`), s3 pools read their bucket
+    /// from `{prefix}_*` and an optional replica from `{prefix}_REPLICA_*`
+    async fn connect(
+        name: &str,
+        kind: &str,
+        prefix: &str,
+        local_path: PathBuf,
+    ) -> anyhow::Result<StoragePool> {
+        let backend: Box<dyn StorageBackend> = match kind {
+            "s3" => {
+                let Some(config) = S3Config::from_env(prefix)? else {
+                    anyhow::bail!("storage pool '{name}' needs {prefix}_ENDPOINT");
+                };
+                Box::new(S3Backend::connect(config).await?)
+            }
+            "local" => Box::new(LocalBackend::new(local_path).await?),
+            other => anyhow::bail!("unknown storage backend '{other}' for pool '{name}'"),
+        };
+
+        let Some(replica) = S3Config::from_env(&format!("{prefix}_REPLICA"))? else {
+            return Ok(StoragePool {
+                backend,
+                replication: None,
+            });
+        };
+
+        tracing::info!("replicating storage pool '{name}'");
+        let replica = S3Backend::connect(replica).await?;
+        let replication = Replication::start(backend, Box::new(replica));
+        Ok(StoragePool {
+            backend: Box::new(ReplicatedBackend::new(replication.clone())),
+            replication: Some(replication),
+        })
+    }
+}
+
+/// Reads the pools listed in `STORAGE_POOLS`, each configured through
+/// `STORAGE_POOL_{NAME}_*`. Without it there is a single `default` pool
+/// configured through `STORAGE_BACKEND`, `STORAGE_LOCAL_PATH` and `S3_*`.
+pub async fn load_pools() -> anyhow::Result<HashMap<String, StoragePool>> {
+    let mut pools = HashMap::new();
+
+    let names = env::var("STORAGE_POOLS").unwrap_or_default();
+    if names.trim().is_empty() {
+        let kind = env::var("STORAGE_BACKEND").unwrap_or(String::from("s3"));
+        let local_path = env::var("STORAGE_LOCAL_PATH").unwrap_or(String::from("./storage"));
+        let pool = StoragePool::connect(DEFAULT_POOL, &kind, "S3", local_path.into()).await?;
+        pools.insert(DEFAULT_POOL.to_owned(), pool);
+        return Ok(pools);
+    }
+
+    for name in names.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+        let prefix = format!("STORAGE_POOL_{}", name.to_uppercase().replace('-', "_"));
+        let kind = env::var(format!("{prefix}_BACKEND")).unwrap_or(String::from("s3"));
+        let local_path =
+            env::var(format!("{prefix}_LOCAL_PATH")).unwrap_or(format!("./storage/{name}"));
+
+        let pool = StoragePool::connect(name, &kind, &prefix, local_path.into()).await?;
+        if pools.insert(name.to_owned(), pool).is_some() {
+            anyhow::bail!("storage pool '{name}' is listed twice");
+        }
+    }
+
+    Ok(pools)
+}
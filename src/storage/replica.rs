@@ -0,0 +1,347 @@
+//! Mirrors every write to the primary backend onto a secondary one in the
+//! background. Reads go to the primary and only fall back to the replica
+//! when the primary errors, so the replica may lag but never serves stale
+//! content while the primary is healthy.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::{fs::File, sync::mpsc};
+
+use super::backend::{transfer, ByteStream, ObjectMeta, StorageBackend, UploadPart};
+
+/// How many keys are replicated at once, writes to the same key always
+/// land on the same worker so they are applied in order
+const WORKERS: usize = 4;
+
+/// A failed job is retried after 1, 2, 4... seconds this many times
+const MAX_ATTEMPTS: u32 = 6;
+
+enum JobKind {
+    Copy,
+    Delete,
+}
+
+struct Job {
+    kind: JobKind,
+    key: String,
+    queued_at: Instant,
+}
+
+/// Counters exposed as the replication metrics
+#[derive(Debug, Default, Serialize)]
+pub struct ReplicationStats {
+    /// Jobs waiting for or being processed by a worker
+    pub pending: u64,
+    pub replicated: u64,
+    /// Jobs that gave up, the next backfill picks their keys up
+    pub failed: u64,
+    /// Age of the oldest job that hasn't been replicated yet
+    pub lag_secs: f64,
+}
+
+pub struct Replication {
+    primary: Arc<dyn StorageBackend>,
+    replica: Arc<dyn StorageBackend>,
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+    started_at: Instant,
+    /// Per worker, when the job it's working on was queued, in milliseconds
+    /// since `started_at` plus one so that zero means idle
+    oldest: Vec<AtomicU64>,
+    pending: AtomicU64,
+    replicated: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Keys that are rewritten until an upload finishes and then dropped,
+/// there's nothing to fall back to for them
+fn is_transient(key: &str) -> bool {
+    key.contains("/uploads/")
+}
+
+impl Replication {
+    /// Starts the workers, must be called from within the runtime
+    pub fn start(
+        primary: Box<dyn StorageBackend>,
+        replica: Box<dyn StorageBackend>,
+    ) -> Arc<Replication> {
+        let mut senders = Vec::with_capacity(WORKERS);
+        let mut receivers = Vec::with_capacity(WORKERS);
+        for _ in 0..WORKERS {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            senders.push(sender);
+            receivers.push(receiver);
+        }
+
+        let replication = Arc::new(Replication {
+            primary: primary.into(),
+            replica: replica.into(),
+            workers: senders,
+            started_at: Instant::now(),
+            oldest: (0..WORKERS).map(|_| AtomicU64::new(0)).collect(),
+            pending: AtomicU64::new(0),
+            replicated: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        });
+
+        for (worker, receiver) in receivers.into_iter().enumerate() {
+            tokio::spawn(run_worker(replication.clone(), worker, receiver));
+        }
+
+        replication
+    }
+
+    pub fn primary(&self) -> &dyn StorageBackend {
+        self.primary.as_ref()
+    }
+
+    pub fn replica(&self) -> &dyn StorageBackend {
+        self.replica.as_ref()
+    }
+
+    pub fn stats(&self) -> ReplicationStats {
+        let now = self.started_at.elapsed().as_millis() as u64;
+        let oldest = self
+            .oldest
+            .iter()
+            .map(|queued_at| queued_at.load(Ordering::Relaxed))
+            .filter(|queued_at| *queued_at > 0)
+            .min();
+
+        ReplicationStats {
+            pending: self.pending.load(Ordering::Relaxed),
+            replicated: self.replicated.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            lag_secs: oldest
+                .map(|queued_at| now.saturating_sub(queued_at - 1) as f64 / 1000.0)
+                .unwrap_or(0.0),
+        }
+    }
+
+    fn enqueue(&self, kind: JobKind, key: &str) {
+        if is_transient(key) {
+            return;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let worker = hasher.finish() as usize % self.workers.len();
+
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let job = Job {
+            kind,
+            key: key.to_owned(),
+            queued_at: Instant::now(),
+        };
+        if self.workers[worker].send(job).is_err() {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            tracing::error!("replication worker {worker} is gone, {key} won't be replicated");
+        }
+    }
+
+    /// Copies the current content of `key` from the primary to the replica,
+    /// or removes it from the replica if the primary no longer has it
+    pub async fn sync_key(&self, key: &str) -> anyhow::Result<()> {
+        if transfer(self.primary(), self.replica(), key)
+            .await?
+            .is_none()
+        {
+            self.replica.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn process(&self, job: &Job) -> anyhow::Result<()> {
+        match job.kind {
+            JobKind::Copy => self.sync_key(&job.key).await,
+            JobKind::Delete => self.replica.delete(&job.key).await,
+        }
+    }
+}
+
+async fn run_worker(
+    replication: Arc<Replication>,
+    worker: usize,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+) {
+    while let Some(job) = jobs.recv().await {
+        let queued_at = job.queued_at.duration_since(replication.started_at);
+        replication.oldest[worker].store(queued_at.as_millis() as u64 + 1, Ordering::Relaxed);
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match replication.process(&job).await {
+                Ok(()) => {
+                    replication.replicated.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Err(error) if attempt < MAX_ATTEMPTS => {
+                    tracing::warn!(
+                        error = ?error,
+                        "failed to replicate {} (attempt {attempt})",
+                        job.key
+                    );
+                    tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+                }
+                Err(error) => {
+                    tracing::error!(error = ?error, "giving up on replicating {}", job.key);
+                    replication.failed.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+
+        replication.oldest[worker].store(0, Ordering::Relaxed);
+        replication.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A backend that writes to the primary and queues the write for the replica
+pub struct ReplicatedBackend {
+    replication: Arc<Replication>,
+}
+
+impl ReplicatedBackend {
+    pub fn new(replication: Arc<Replication>) -> ReplicatedBackend {
+        ReplicatedBackend { replication }
+    }
+
+    fn primary(&self) -> &dyn StorageBackend {
+        self.replication.primary()
+    }
+
+    fn replica(&self) -> &dyn StorageBackend {
+        self.replication.replica()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ReplicatedBackend {
+    async fn put_file(
+        &self,
+        key: &str,
+        content: &mut File,
+        content_type: &str,
+    ) -> anyhow::Result<usize> {
+        let written = self.primary().put_file(key, content, content_type).await?;
+        self.replication.enqueue(JobKind::Copy, key);
+        Ok(written)
+    }
+
+    async fn put(&self, key: &str, content: &[u8], content_type: &str) -> anyhow::Result<()> {
+        self.primary().put(key, content, content_type).await?;
+        self.replication.enqueue(JobKind::Copy, key);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        match self.primary().get(key).await {
+            Ok(content) => Ok(content),
+            Err(error) => {
+                tracing::warn!(error = ?error, "reading {key} from the replica");
+                self.replica().get(key).await.map_err(|_| error)
+            }
+        }
+    }
+
+    async fn get_stream(&self, key: &str, range: Option<(u64, u64)>) -> anyhow::Result<ByteStream> {
+        match self.primary().get_stream(key, range).await {
+            Ok(stream) => Ok(stream),
+            Err(error) => {
+                tracing::warn!(error = ?error, "streaming {key} from the replica");
+                self.replica()
+                    .get_stream(key, range)
+                    .await
+                    .map_err(|_| error)
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.primary().delete(key).await?;
+        self.replication.enqueue(JobKind::Delete, key);
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.primary().copy(from, to).await?;
+        self.replication.enqueue(JobKind::Copy, to);
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> anyhow::Result<Option<ObjectMeta>> {
+        match self.primary().head(key).await {
+            Ok(meta) => Ok(meta),
+            Err(error) => {
+                tracing::warn!(error = ?error, "looking {key} up on the replica");
+                self.replica().head(key).await.map_err(|_| error)
+            }
+        }
+    }
+
+    /// Only lists the primary, a listing is used to find what to delete and
+    /// the replica may still be missing recent writes
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectMeta>> {
+        self.primary().list(prefix).await
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> anyhow::Result<String> {
+        self.primary().create_multipart(key, content_type).await
+    }
+
+    async fn put_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Vec<u8>,
+        content_type: &str,
+    ) -> anyhow::Result<String> {
+        self.primary()
+            .put_part(key, upload_id, number, content, content_type)
+            .await
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadPart],
+    ) -> anyhow::Result<()> {
+        self.primary()
+            .complete_multipart(key, upload_id, parts)
+            .await?;
+        self.replication.enqueue(JobKind::Copy, key);
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> anyhow::Result<()> {
+        self.primary().abort_multipart(key, upload_id).await
+    }
+
+    async fn presign_put(&self, key: &str, expiry_secs: u32) -> anyhow::Result<String> {
+        self.primary().presign_put(key, expiry_secs).await
+    }
+
+    async fn presign_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        expiry_secs: u32,
+    ) -> anyhow::Result<String> {
+        self.primary()
+            .presign_part(key, upload_id, number, expiry_secs)
+            .await
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, env};
+
+use ::s3::{creds::Credentials, error::S3Error, serde_types::Part, Bucket, Region};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{future, stream, StreamExt};
+use tokio::{fs::File, task::JoinHandle};
+use tokio_util::io::ReaderStream;
+
+use super::backend::{ByteStream, ObjectMeta, StorageBackend, UploadPart};
+
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Waits for the first chunk of a stream, so a read that fails right away
+/// fails the call and can still fall back to the replica, instead of cutting
+/// off a response that already went out
+async fn first_chunk(mut body: ByteStream) -> anyhow::Result<ByteStream> {
+    match body.next().await {
+        None => Ok(Box::pin(stream::empty())),
+        Some(Err(error)) => Err(error),
+        Some(Ok(chunk)) => Ok(Box::pin(stream::once(future::ok(chunk)).chain(body))),
+    }
+}
+
+async fn task_error(task: JoinHandle<anyhow::Result<()>>) -> Option<anyhow::Result<Bytes>> {
+    match task.await {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => Some(Err(error)),
+        Err(error) => Some(Err(error.into())),
+    }
+}
+
+pub struct S3Backend {
+    pub inner: Box<Bucket>,
+}
+
+/// Where an s3 bucket lives, read from `{prefix}_*` environment variables
+pub struct S3Config {
+    pub endpoint: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub bucket: String,
+    pub region: String,
+    /// Addresses the bucket as `{endpoint}/{bucket}` instead of `{bucket}.{endpoint}`
+    pub path_style: bool,
+}
+
+impl S3Config {
+    /// `None` when `{prefix}_ENDPOINT` isn't set or empty
+    pub fn from_env(prefix: &str) -> anyhow::Result<Option<S3Config>> {
+        let endpoint = match env::var(format!("{prefix}_ENDPOINT")) {
+            Ok(v) if !v.is_empty() => v,
+            _ => return Ok(None),
+        };
+        let var = |name: &str| -> anyhow::Result<String> {
+            env::var(format!("{prefix}_{name}"))
+                .map_err(|_| anyhow::anyhow!("environment variable '{prefix}_{name}' is not set"))
+        };
+
+        Ok(Some(S3Config {
+            endpoint,
+            access_key: var("ACCESS_KEY")?,
+            secret_key: var("SECRET_KEY")?,
+            bucket: var("BUCKET").unwrap_or("linkersh".to_owned()),
+            region: var("REGION").unwrap_or("eu-central-1".to_owned()),
+            path_style: var("PATH_STYLE").map_or(true, |v| v != "false"),
+        }))
+    }
+}
+
+impl S3Backend {
+    pub async fn connect(config: S3Config) -> anyhow::Result<S3Backend> {
+        let mut client = Bucket::new(
+            &config.bucket,
+            Region::Custom {
+                region: config.region,
+                endpoint: config.endpoint,
+            },
+            Credentials {
+                access_key: Some(config.access_key),
+                secret_key: Some(config.secret_key),
+                expiration: None,
+                security_token: None,
+                session_token: None,
+            },
+        )?;
+        if config.path_style {
+            client.set_path_style();
+        }
+        client.set_listobjects_v2();
+
+        if let Err(error) = client.head_object("/").await {
+            anyhow::bail!(
+                "failed to connect to s3 bucket '{}': {error}",
+                config.bucket
+            );
+        }
+
+        tracing::info!("connected to s3 bucket '{}'", config.bucket);
+        Ok(S3Backend { inner: client })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put_file(
+        &self,
+        key: &str,
+        content: &mut File,
+        content_type: &str,
+    ) -> anyhow::Result<usize> {
+        let response = self
+            .inner
+            .put_object_stream_with_content_type(content, key, content_type)
+            .await?;
+        Ok(response.uploaded_bytes())
+    }
+
+    async fn put(&self, key: &str, content: &[u8], content_type: &str) -> anyhow::Result<()> {
+        self.inner
+            .put_object_with_content_type(key, content, content_type)
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let content = self.inner.get_object(key).await?;
+        Ok(content.to_vec())
+    }
+
+    async fn get_stream(&self, key: &str, range: Option<(u64, u64)>) -> anyhow::Result<ByteStream> {
+        let Some((start, end)) = range else {
+            let response = self.inner.get_object_stream(key).await?;
+            return first_chunk(Box::pin(
+                response
+                    .bytes
+                    .map(|chunk| chunk.map_err(anyhow::Error::from)),
+            ))
+            .await;
+        };
+
+        // rust-s3 has no ranged stream, so pipe the ranged writer through a
+        // bounded in-memory pipe which gives us backpressure for free
+        let (mut writer, reader) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        let bucket = self.inner.clone();
+        let key = key.to_owned();
+        let task = tokio::spawn(async move {
+            let status = bucket
+                .get_object_range_to_writer(&key, start, Some(end), &mut writer)
+                .await?;
+            if !(200..300).contains(&status) {
+                anyhow::bail!("s3 answered {status} for a range of {key}");
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        // the pipe just ends when the task fails, its error has to be passed on
+        // or a truncated body would look complete
+        let stream = ReaderStream::new(reader)
+            .map(|chunk| chunk.map_err(anyhow::Error::from))
+            .chain(stream::once(task_error(task)).filter_map(future::ready));
+        first_chunk(Box::pin(stream)).await
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.inner.delete_object(key).await?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.inner.copy_object_internal(from, to).await?;
+        Ok(())
+    }
+
+    async fn head(&self, key: &str) -> anyhow::Result<Option<ObjectMeta>> {
+        match self.inner.head_object(key).await {
+            Ok((_, 404)) | Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Ok((head, _)) => Ok(Some(ObjectMeta {
+                key: key.to_owned(),
+                size: head.content_length.unwrap_or(0).try_into()?,
+                content_type: head.content_type,
+                last_modified: head
+                    .last_modified
+                    .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                    .map(|date| date.with_timezone(&Utc)),
+            })),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<ObjectMeta>> {
+        // s3 keys don't start with a slash even though we address them with one
+        let pages = self
+            .inner
+            .list(prefix.trim_start_matches('/').to_owned(), None)
+            .await?;
+
+        let objects = pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|obj| ObjectMeta {
+                key: format!("/{}", obj.key),
+                size: obj.size,
+                content_type: None,
+                last_modified: DateTime::parse_from_rfc3339(&obj.last_modified)
+                    .ok()
+                    .map(|date| date.with_timezone(&Utc)),
+            })
+            .collect();
+        Ok(objects)
+    }
+
+    async fn create_multipart(&self, key: &str, content_type: &str) -> anyhow::Result<String> {
+        let response = self
+            .inner
+            .initiate_multipart_upload(key, content_type)
+            .await?;
+        Ok(response.upload_id)
+    }
+
+    async fn put_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        content: Vec<u8>,
+        content_type: &str,
+    ) -> anyhow::Result<String> {
+        let part = self
+            .inner
+            .put_multipart_chunk(content, key, number, upload_id, content_type)
+            .await?;
+        Ok(part.etag)
+    }
+
+    async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadPart],
+    ) -> anyhow::Result<()> {
+        let parts = parts
+            .iter()
+            .map(|part| Part {
+                part_number: part.number,
+                etag: part.etag.clone(),
+            })
+            .collect();
+        self.inner
+            .complete_multipart_upload(key, upload_id, parts)
+            .await?;
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) -> anyhow::Result<()> {
+        self.inner.abort_upload(key, upload_id).await?;
+        Ok(())
+    }
+
+    async fn presign_put(&self, key: &str, expiry_secs: u32) -> anyhow::Result<String> {
+        let url = self.inner.presign_put(key, expiry_secs, None, None).await?;
+        Ok(url)
+    }
+
+    async fn presign_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        expiry_secs: u32,
+    ) -> anyhow::Result<String> {
+        let queries = HashMap::from([
+            ("partNumber".to_owned(), number.to_string()),
+            ("uploadId".to_owned(), upload_id.to_owned()),
+        ]);
+        let url = self
+            .inner
+            .presign_put(key, expiry_secs, None, Some(queries))
+            .await?;
+        Ok(url)
+    }
+}
@@ -0,0 +1,137 @@
+//! Drains the deletion outbox. Deleting an object only marks its row and
+//! queues what it owns, this removes the content and retries with backoff
+//! when storage or meilisearch are unavailable.
+
+use std::{sync::Arc, time::Duration};
+
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        OutboxEntry, OUTBOX_BLOB, OUTBOX_OBJECT, OUTBOX_SEARCH_DOCUMENT, OUTBOX_THUMBNAIL,
+        OUTBOX_VARIANTS,
+    },
+    state::ApiState,
+    storage::blob_key,
+};
+
+/// How many entries are claimed at once
+const BATCH_SIZE: i64 = 64;
+
+/// Failed entries are retried after 1, 2, 4... seconds, up to this
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+/// How long claimed entries are left to the worker that claimed them
+const LEASE_SECS: i64 = 10 * 60;
+
+async fn process_entry(state: &ApiState, entry: &OutboxEntry) -> anyhow::Result<()> {
+    match entry.kind.as_str() {
+        OUTBOX_OBJECT | OUTBOX_THUMBNAIL => {
+            let Some(pool) = &entry.storage_pool else {
+                anyhow::bail!("outbox entry {} doesn't name a storage pool", entry.id);
+            };
+            state.storage.backend(pool)?.delete(&entry.target).await
+        }
+        OUTBOX_VARIANTS => {
+            let Some(pool) = &entry.storage_pool else {
+                anyhow::bail!("outbox entry {} doesn't name a storage pool", entry.id);
+            };
+            let backend = state.storage.backend(pool)?;
+            for meta in backend.list(&entry.target).await? {
+                backend.delete(&meta.key).await?;
+            }
+            Ok(())
+        }
+        OUTBOX_SEARCH_DOCUMENT => {
+            let id: Uuid = entry.target.parse()?;
+            state.meili.delete_object(id).await
+        }
+        OUTBOX_BLOB => {
+            let mut trans = state.pg.inner.begin().await?;
+            let Some(pool) = state
+                .pg
+                .lock_released_blob(&entry.target, &mut trans)
+                .await?
+            else {
+                return Ok(());
+            };
+            if state.pg.blob_has_objects(&entry.target, &mut trans).await? {
+                anyhow::bail!("blob is still referenced by objects waiting for deletion");
+            }
+
+            // the row stays locked until the content is gone, an upload that
+            // wants to reference it waits and then fails instead of pointing
+            // at missing content
+            state
+                .storage
+                .backend(&pool)?
+                .delete(&blob_key(&entry.target))
+                .await?;
+            state.pg.delete_blob(&entry.target, &mut trans).await?;
+            trans.commit().await?;
+            Ok(())
+        }
+        other => anyhow::bail!("unknown outbox entry kind '{other}'"),
+    }
+}
+
+/// Processes one batch of due entries of the given kinds, returns how many were claimed.
+/// No transaction spans the batch, each entry is claimed up front and settled on its own.
+async fn process_batch(state: &ApiState, kinds: &[&str]) -> anyhow::Result<usize> {
+    let entries = state
+        .pg
+        .claim_due_outbox_entries(kinds, BATCH_SIZE, LEASE_SECS)
+        .await?;
+
+    for entry in &entries {
+        match process_entry(state, entry).await {
+            Ok(()) => state.pg.complete_outbox_entry(entry.id).await?,
+            Err(error) => {
+                tracing::warn!(
+                    error = ?error,
+                    "failed to delete {} {} (attempt {})",
+                    entry.kind,
+                    entry.target,
+                    entry.attempts + 1
+                );
+                state
+                    .pg
+                    .fail_outbox_entry(entry.id, &error.to_string(), MAX_BACKOFF_SECS)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(entries.len())
+}
+
+async fn drain_outbox(state: &ApiState) -> anyhow::Result<()> {
+    let owned = [
+        OUTBOX_OBJECT,
+        OUTBOX_THUMBNAIL,
+        OUTBOX_VARIANTS,
+        OUTBOX_SEARCH_DOCUMENT,
+    ];
+    while process_batch(state, &owned).await? > 0 {}
+
+    let purged = state.pg.purge_deleted_objects().await?;
+    if purged > 0 {
+        tracing::debug!("removed {purged} deleted objects");
+    }
+
+    // blobs go last, they can only be removed once no row points at them
+    while process_batch(state, &[OUTBOX_BLOB]).await? > 0 {}
+    Ok(())
+}
+
+pub fn start_worker(state: Arc<ApiState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            if let Err(error) = drain_outbox(&state).await {
+                tracing::error!(error = ?error, "failed to drain the deletion outbox");
+            }
+        }
+    });
+}
@@ -0,0 +1,42 @@
+//! Deletes objects whose public link was published with `delete_when_spent`,
+//! once the link expired or handed out its last download.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::state::ApiState;
+
+/// How long a spent object is kept, so downloads that started before the link
+/// was spent can finish
+const GRACE_SECS: i64 = 6 * 60 * 60;
+
+async fn delete_spent(state: &ApiState) -> anyhow::Result<()> {
+    let objects = state.pg.list_spent_links(GRACE_SECS).await?;
+
+    for obj in &objects {
+        let mut trans = state.pg.inner.begin().await?;
+        let deleted = state
+            .pg
+            .schedule_object_deletion(obj.user_id, &vec![obj.id], &mut trans)
+            .await?;
+        trans.commit().await?;
+
+        if !deleted.is_empty() {
+            state.hot_cache.invalidate(obj.id).await;
+            tracing::info!("deleted object {} since its link is spent", obj.id);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn start_worker(state: Arc<ApiState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            if let Err(error) = delete_spent(&state).await {
+                tracing::error!(error = ?error, "failed to delete objects of spent links");
+            }
+        }
+    });
+}
@@ -5,9 +5,27 @@ use ocrs::ImageSource;
 
 use crate::{
     db::{CdnObject, COF_INDEXED, COF_SEARCHABLE},
+    pdf::{self, UnreadablePdf},
     state::ApiState,
+    text,
 };
 
+mod deletion;
+mod links;
+mod scrub;
+mod thumbnails;
+mod uploads;
+
+/// Pages of a scanned PDF that are read with OCR
+const MAX_OCR_PAGES: u32 = 10;
+
+/// The longer side of scanned pages rendered for OCR
+const OCR_PAGE_SIZE: u32 = 2000;
+
+/// Images and PDFs above this size are indexed without their text, they'd
+/// have to be downloaded whole
+const MAX_EXTRACTED_SIZE: i64 = 64 * 1024 * 1024;
+
 fn image_to_text(state: Arc<ApiState>, buf: Vec<u8>) -> anyhow::Result<String> {
     let img = image::load_from_memory(&buf)?.into_rgb8();
     let img_source = ImageSource::from_bytes(img.as_raw(), img.dimensions())?;
@@ -16,42 +34,119 @@ fn image_to_text(state: Arc<ApiState>, buf: Vec<u8>) -> anyhow::Result<String> {
     Ok(text)
 }
 
-async fn process_objects(objs: Vec<CdnObject>, state: Arc<ApiState>) -> anyhow::Result<()> {
-    for obj in objs {
-        let s3_data = state.storage.get_user_object(obj.user_id, obj.id).await?;
-        let state_clone = Arc::clone(&state);
-        let lines = tokio::task::spawn_blocking(|| -> anyhow::Result<String> {
-            match image_to_text(state_clone, s3_data) {
-                Ok(v) => Ok(v),
-                Err(error) => {
-                    tracing::error!(error = ?error, "failed to OCR an image");
-                    Err(error)
-                }
+async fn ocr_image(state: &Arc<ApiState>, buf: Vec<u8>) -> anyhow::Result<String> {
+    let state_clone = Arc::clone(state);
+    tokio::task::spawn_blocking(|| -> anyhow::Result<String> {
+        match image_to_text(state_clone, buf) {
+            Ok(v) => Ok(v),
+            Err(error) => {
+                tracing::error!(error = ?error, "failed to OCR an image");
+                Err(error)
             }
-        })
-        .await??;
-
-        state.meili.index_object(obj.id, obj.user_id, lines).await?;
-        sqlx::query!(
-            "UPDATE cdn_objects SET flags = flags | $1 WHERE id = $2",
-            COF_INDEXED,
-            obj.id
-        )
-        .execute(&state.pg.inner)
-        .await?;
-        tracing::debug!("object {} has been processed with OCR", obj.id);
+        }
+    })
+    .await?
+}
+
+async fn pdf_to_text(state: &Arc<ApiState>, obj: &CdnObject) -> anyhow::Result<String> {
+    let file = state.download_object(obj).await?;
+    let text = pdf::extract_text(file.path()).await?;
+    if !text.trim().is_empty() {
+        return Ok(text);
+    }
+
+    // scans have no text layer, their first pages are read with OCR instead
+    let pages = pdf::page_count(file.path()).await?.min(MAX_OCR_PAGES);
+    let mut text = String::new();
+    for page in 1..=pages {
+        let image = pdf::render_page(file.path(), page, OCR_PAGE_SIZE).await?;
+        text.push_str(&ocr_image(state, image).await?);
+        text.push('\n');
     }
 
+    Ok(text)
+}
+
+async fn plain_text(state: &Arc<ApiState>, obj: &CdnObject) -> anyhow::Result<String> {
+    let size = u64::try_from(obj.content_size)?;
+    let complete = size <= text::MAX_INDEXED_SIZE;
+    let content = if complete {
+        state.read_object(obj).await?
+    } else {
+        let mut content = Vec::new();
+        let mut stream = state
+            .open_object(obj, Some((0, text::MAX_INDEXED_SIZE - 1)))
+            .await?;
+        while let Some(chunk) = stream.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+        content
+    };
+
+    match text::decode(&content, complete) {
+        Some(text) => Ok(text),
+        None => {
+            tracing::warn!("object {} looks like text but is binary", obj.id);
+            Ok(String::new())
+        }
+    }
+}
+
+/// The text an object is indexed with, read in a way that depends on its type
+async fn object_text(state: &Arc<ApiState>, obj: &CdnObject) -> anyhow::Result<String> {
+    if text::is_text(&obj.content_type, &obj.file_name) {
+        return plain_text(state, obj).await;
+    }
+    if obj.content_size > MAX_EXTRACTED_SIZE {
+        tracing::warn!("object {} is too large to extract its text", obj.id);
+        return Ok(String::new());
+    }
+    if obj.content_type != pdf::CONTENT_TYPE {
+        let s3_data = state.read_object(obj).await?;
+        return ocr_image(state, s3_data).await;
+    }
+
+    match pdf_to_text(state, obj).await {
+        Ok(text) => Ok(text),
+        // indexed without text, retrying won't make it readable
+        Err(error) if error.is::<UnreadablePdf>() => {
+            tracing::warn!(error = ?error, "object {} has no readable text", obj.id);
+            Ok(String::new())
+        }
+        Err(error) => Err(error),
+    }
+}
+
+async fn index_object(state: &Arc<ApiState>, obj: &CdnObject) -> anyhow::Result<()> {
+    let lines = object_text(state, obj).await?;
+    state.meili.index_object(obj.id, obj.user_id, lines).await?;
+    sqlx::query!(
+        "UPDATE cdn_objects SET flags = flags | $1 WHERE id = $2",
+        COF_INDEXED,
+        obj.id
+    )
+    .execute(&state.pg.inner)
+    .await?;
+    tracing::debug!("object {} has been indexed", obj.id);
     Ok(())
 }
 
+/// Indexes objects one after another, one that fails is tried again next
+/// round without holding up the rest
+async fn process_objects(objs: Vec<CdnObject>, state: Arc<ApiState>) {
+    for obj in objs {
+        if let Err(error) = index_object(&state, &obj).await {
+            tracing::error!(error = ?error, "failed to index object {}", obj.id);
+        }
+    }
+}
+
 async fn run_tasks(state: &Arc<ApiState>) -> anyhow::Result<()> {
-    let objects = sqlx::query_as!(
-        CdnObject,
-        "SELECT * FROM cdn_objects WHERE (flags & $1) = $1 AND (flags & $2) = 0",
-        COF_SEARCHABLE,
-        COF_INDEXED
+    let objects: Vec<CdnObject> = sqlx::query_as(
+        "SELECT * FROM cdn_objects WHERE (flags & $1) = $1 AND (flags & $2) = 0 AND deleted_at IS NULL",
     )
+    .bind(COF_SEARCHABLE)
+    .bind(COF_INDEXED)
     .fetch_all(&state.pg.inner)
     .await?;
 
@@ -61,11 +156,11 @@ async fn run_tasks(state: &Arc<ApiState>) -> anyhow::Result<()> {
 
     let threads: usize = std::thread::available_parallelism()?.into();
     tracing::info!(
-        "need to OCR {} objects, using {threads} worker threads",
+        "need to index {} objects, using {threads} worker threads",
         objects.len()
     );
 
-    let chunk_size = (objects.len() + threads - 1) / threads;
+    let chunk_size = objects.len().div_ceil(threads);
     let chunks = objects.chunks(chunk_size);
     let futures = FuturesUnordered::new();
 
@@ -73,11 +168,7 @@ async fn run_tasks(state: &Arc<ApiState>) -> anyhow::Result<()> {
         let objects = ch.to_vec();
         let state_clone = Arc::clone(state);
 
-        futures.push(async move {
-            if let Err(error) = process_objects(objects, state_clone).await {
-                tracing::error!(error = ?error, "failed to process objects");
-            }
-        });
+        futures.push(process_objects(objects, state_clone));
     }
 
     futures.collect::<Vec<()>>().await;
@@ -85,6 +176,12 @@ async fn run_tasks(state: &Arc<ApiState>) -> anyhow::Result<()> {
 }
 
 pub fn start_service(state: Arc<ApiState>) -> anyhow::Result<()> {
+    deletion::start_worker(Arc::clone(&state));
+    links::start_worker(Arc::clone(&state));
+    scrub::start_worker(Arc::clone(&state))?;
+    thumbnails::start_worker(Arc::clone(&state));
+    uploads::start_worker(Arc::clone(&state));
+
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(30));
         loop {
@@ -0,0 +1,149 @@
+//! Re-reads stored content in the background and compares it against the
+//! hash recorded at upload, so silent corruption or lost content surfaces
+//! before somebody tries to download it.
+
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::{db::CdnObject, state::ApiState};
+
+/// How long to wait when everything is verified
+const IDLE_DELAY: Duration = Duration::from_secs(60);
+
+/// How long until an object that couldn't be read at all is tried again
+const RETRY_DELAY: chrono::Duration = chrono::Duration::hours(1);
+
+struct ScrubConfig {
+    bytes_per_sec: u64,
+    interval: chrono::Duration,
+}
+
+impl ScrubConfig {
+    fn from_env() -> anyhow::Result<ScrubConfig> {
+        let bytes_per_sec = match env::var("SCRUB_BYTES_PER_SEC") {
+            Ok(v) if !v.is_empty() => v.parse()?,
+            _ => 8 * 1024 * 1024,
+        };
+        let interval_days = match env::var("SCRUB_INTERVAL_DAYS") {
+            Ok(v) if !v.is_empty() => v.parse()?,
+            _ => 30,
+        };
+
+        Ok(ScrubConfig {
+            bytes_per_sec,
+            interval: chrono::Duration::days(interval_days),
+        })
+    }
+}
+
+/// Reads the content of an object at no more than `bytes_per_sec`, returns
+/// why it doesn't match its hash if it doesn't
+async fn verify_object(
+    state: &ApiState,
+    obj: &CdnObject,
+    bytes_per_sec: u64,
+) -> anyhow::Result<Option<String>> {
+    if state
+        .storage
+        .backend(&obj.storage_pool)?
+        .head(&obj.storage_key())
+        .await?
+        .is_none()
+    {
+        return Ok(Some("missing".to_owned()));
+    }
+
+    let mut stream = state.open_object(obj, None).await?;
+    let mut hasher = Sha256::new();
+    let mut read = 0u64;
+    let start = Instant::now();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(v) => v,
+            // encrypted content that fails to authenticate is corrupt as well
+            Err(error) => return Ok(Some(format!("unreadable: {error}"))),
+        };
+        hasher.update(&chunk);
+        read += chunk.len() as u64;
+
+        let expected = Duration::from_secs_f64(read as f64 / bytes_per_sec as f64);
+        if let Some(ahead) = expected.checked_sub(start.elapsed()) {
+            tokio::time::sleep(ahead).await;
+        }
+    }
+
+    if read != obj.content_size as u64 {
+        return Ok(Some(format!(
+            "size mismatch: expected {} bytes, read {read}",
+            obj.content_size
+        )));
+    }
+
+    let hash = format!("{:x}", hasher.finalize());
+    if hash != obj.sha256_hash {
+        return Ok(Some(format!("hash mismatch: content hashes to {hash}")));
+    }
+
+    Ok(None)
+}
+
+async fn scrub_next(state: &ApiState, config: &ScrubConfig) -> anyhow::Result<bool> {
+    let verified_before = (chrono::Utc::now() - config.interval).naive_utc();
+    let Some(obj) = state.pg.next_object_to_scrub(verified_before).await? else {
+        return Ok(false);
+    };
+
+    let error = match verify_object(state, &obj, config.bytes_per_sec).await {
+        Ok(v) => v,
+        // e.g. storage is unreachable, the object is tried again later and the
+        // ones after it aren't held up meanwhile. Waiting a bit before the
+        // next one keeps an outage from flagging every object.
+        Err(error) => {
+            tracing::warn!(error = ?error, "failed to scrub object {}", obj.id);
+            let retry_at = verified_before + RETRY_DELAY;
+            state
+                .pg
+                .record_scrub_error(obj.id, &format!("unverifiable: {error}"), retry_at)
+                .await?;
+            return Ok(false);
+        }
+    };
+    match &error {
+        Some(error) => tracing::error!("object {} failed verification: {error}", obj.id),
+        None => tracing::debug!("object {} verified", obj.id),
+    }
+
+    state
+        .pg
+        .record_scrub(obj.id, &obj.sha256_hash, error.as_deref())
+        .await?;
+    Ok(true)
+}
+
+pub fn start_worker(state: Arc<ApiState>) -> anyhow::Result<()> {
+    let config = ScrubConfig::from_env()?;
+    if config.bytes_per_sec == 0 {
+        tracing::info!("integrity scrubbing is turned off");
+        return Ok(());
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match scrub_next(&state, &config).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(error) => tracing::error!(error = ?error, "failed to scrub an object"),
+            }
+            tokio::time::sleep(IDLE_DELAY).await;
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Renders the thumbnails of images and PDFs ahead of time, so requests only
+//! ever read them from storage. Objects are picked up by their flags, which are
+//! reset whenever an object gets new content.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    db::CdnObject,
+    imaging::{self, MAX_SOURCE_SIZE},
+    pdf::{self, UnreadablePdf},
+    state::ApiState,
+    storage::variant_key,
+};
+
+/// How many objects are picked up at once
+const BATCH_SIZE: i64 = 16;
+
+/// How long an object is held by the worker that picked it up, objects that
+/// failed to render are skipped until it runs out
+const LEASE_SECS: i64 = 5 * 60;
+
+/// Objects whose thumbnails failed to render this often get none
+const MAX_ATTEMPTS: i32 = 5;
+
+/// The image thumbnails are rendered from, `None` if the object can't have any
+async fn thumbnail_source(state: &ApiState, obj: &CdnObject) -> anyhow::Result<Option<Vec<u8>>> {
+    if obj.content_type == pdf::CONTENT_TYPE {
+        let file = state.download_object(obj).await?;
+        let size = state.thumbnails.sizes.iter().copied().max().unwrap_or(1024);
+        return match pdf::render_page(file.path(), 1, size).await {
+            Ok(page) => Ok(Some(page)),
+            Err(error) if error.is::<UnreadablePdf>() => {
+                tracing::warn!(error = ?error, "object {} gets no thumbnails", obj.id);
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        };
+    }
+
+    if obj.content_size > MAX_SOURCE_SIZE {
+        tracing::debug!("object {} is too large for thumbnails", obj.id);
+        return Ok(None);
+    }
+    Ok(Some(state.read_object(obj).await?))
+}
+
+async fn render_thumbnails(state: &ApiState, obj: &CdnObject) -> anyhow::Result<()> {
+    let Some(content) = thumbnail_source(state, obj).await? else {
+        return state
+            .pg
+            .finish_thumbnails(obj.id, &obj.sha256_hash, false)
+            .await;
+    };
+
+    let transforms = state.thumbnails.transforms();
+    let id = obj.id;
+    // the image is decoded once for every size and format
+    let rendered = tokio::task::spawn_blocking(move || {
+        let image = match imaging::decode(&content) {
+            Ok(v) => v,
+            Err(error) => {
+                tracing::warn!(error = ?error, "object {id} gets no thumbnails");
+                return Ok(None);
+            }
+        };
+        transforms
+            .into_iter()
+            .map(|transform| Ok((transform, imaging::render_image(&image, &transform)?)))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Some)
+    })
+    .await??;
+
+    let Some(rendered) = rendered else {
+        return state
+            .pg
+            .finish_thumbnails(obj.id, &obj.sha256_hash, false)
+            .await;
+    };
+
+    let vault_key = state.vault_key(obj.user_id).await?;
+    for (transform, thumbnail) in &rendered {
+        let key = variant_key(
+            obj.user_id,
+            obj.id,
+            &transform.variant_name(&obj.sha256_hash),
+        );
+        state
+            .storage
+            .put_derived(
+                &obj.storage_pool,
+                &key,
+                thumbnail,
+                transform.format.content_type(),
+                vault_key.as_ref(),
+            )
+            .await?;
+    }
+
+    state
+        .pg
+        .finish_thumbnails(obj.id, &obj.sha256_hash, true)
+        .await?;
+    tracing::debug!(
+        "rendered {} thumbnails of object {}",
+        rendered.len(),
+        obj.id
+    );
+    Ok(())
+}
+
+/// Renders one batch, returns how many objects were picked up
+async fn render_batch(state: &ApiState) -> anyhow::Result<usize> {
+    let objects = state
+        .pg
+        .claim_pending_thumbnails(LEASE_SECS, BATCH_SIZE)
+        .await?;
+
+    for obj in &objects {
+        // requests get told there are no thumbnails instead of waiting forever
+        if obj.thumbnail_attempts > MAX_ATTEMPTS {
+            tracing::warn!("giving up on the thumbnails of object {}", obj.id);
+            state
+                .pg
+                .finish_thumbnails(obj.id, &obj.sha256_hash, false)
+                .await?;
+            continue;
+        }
+
+        if let Err(error) = render_thumbnails(state, obj).await {
+            tracing::error!(error = ?error, "failed to render the thumbnails of object {}", obj.id);
+        }
+    }
+
+    Ok(objects.len())
+}
+
+async fn render_pending(state: &ApiState) -> anyhow::Result<()> {
+    // objects stay leased when they fail, so the next batch skips them and this ends
+    while render_batch(state).await? > 0 {}
+    Ok(())
+}
+
+pub fn start_worker(state: Arc<ApiState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            if let Err(error) = render_pending(&state).await {
+                tracing::error!(error = ?error, "failed to render thumbnails");
+            }
+        }
+    });
+}
@@ -0,0 +1,82 @@
+//! Drops presigned upload reservations that were never finalized and
+//! resumable uploads that expired, together with whatever the client
+//! uploaded for them.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    state::ApiState,
+    storage::{object_key, upload_key},
+};
+
+/// Presigned URLs are valid for an hour, reservations are dropped an hour after
+const RESERVATION_TTL_SECS: i64 = 2 * 60 * 60;
+
+async fn expire_reservations(state: &ApiState) -> anyhow::Result<()> {
+    let reservations = state
+        .pg
+        .take_expired_upload_reservations(RESERVATION_TTL_SECS)
+        .await?;
+
+    // content left behind when this fails is removed by `gc` later
+    for reservation in &reservations {
+        let backend = state.storage.backend(&reservation.storage_pool)?;
+        let key = object_key(reservation.user_id, reservation.id);
+        if let Some(upload_id) = &reservation.multipart_id {
+            if let Err(error) = backend.abort_multipart(&key, upload_id).await {
+                tracing::warn!(error = ?error, "failed to abort presigned upload {}", reservation.id);
+            }
+        }
+        // a finalize that failed halfway may have left a copy behind
+        for key in [key, upload_key(reservation.user_id, reservation.id)] {
+            if let Err(error) = backend.delete(&key).await {
+                tracing::warn!(error = ?error, "failed to delete presigned upload {}", reservation.id);
+            }
+        }
+
+        tracing::info!(
+            "dropped presigned upload {} that was never finalized",
+            reservation.id
+        );
+    }
+
+    Ok(())
+}
+
+async fn expire_sessions(state: &ApiState) -> anyhow::Result<()> {
+    let sessions = state.pg.take_expired_upload_sessions().await?;
+
+    // content left behind when this fails is removed by `gc` later
+    for session in &sessions {
+        let backend = state.storage.backend(&session.storage_pool)?;
+        let key = object_key(session.user_id, session.object_id);
+        if let Err(error) = backend.abort_multipart(&key, &session.multipart_id).await {
+            tracing::warn!(error = ?error, "failed to abort resumable upload {}", session.id);
+        }
+        // a finish that failed halfway may have completed the upload already
+        for key in [key, upload_key(session.user_id, session.id)] {
+            if let Err(error) = backend.delete(&key).await {
+                tracing::warn!(error = ?error, "failed to delete resumable upload {}", session.id);
+            }
+        }
+
+        tracing::info!("dropped resumable upload {} that expired", session.id);
+    }
+
+    Ok(())
+}
+
+pub fn start_worker(state: Arc<ApiState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(error) = expire_reservations(&state).await {
+                tracing::error!(error = ?error, "failed to drop expired upload reservations");
+            }
+            if let Err(error) = expire_sessions(&state).await {
+                tracing::error!(error = ?error, "failed to drop expired resumable uploads");
+            }
+        }
+    });
+}
@@ -0,0 +1,155 @@
+//! Plain text objects, logs, configs and source code, which are indexed as they
+//! are instead of going through OCR
+
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+
+/// Only the start of a text object is indexed, huge logs would bloat the index
+pub const MAX_INDEXED_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Types holding text besides `text/*`, `*+json` and `*+xml`
+const TEXT_TYPES: [&str; 16] = [
+    "application/json",
+    "application/x-ndjson",
+    "application/xml",
+    "application/yaml",
+    "application/x-yaml",
+    "application/toml",
+    "application/javascript",
+    "application/x-javascript",
+    "application/typescript",
+    "application/x-sh",
+    "application/x-shellscript",
+    "application/sql",
+    "application/graphql",
+    "application/x-httpd-php",
+    "application/x-python",
+    "application/x-subrip",
+];
+
+/// Types files arrive with when the client doesn't know better
+const GENERIC_TYPES: [&str; 3] = ["", "application/octet-stream", "binary/octet-stream"];
+
+/// Extensions, or whole names, of text files uploaded with a generic type
+const TEXT_EXTENSIONS: [&str; 72] = [
+    "txt",
+    "log",
+    "out",
+    "md",
+    "markdown",
+    "rst",
+    "adoc",
+    "csv",
+    "tsv",
+    "json",
+    "jsonl",
+    "ndjson",
+    "yaml",
+    "yml",
+    "toml",
+    "ini",
+    "cfg",
+    "conf",
+    "env",
+    "properties",
+    "xml",
+    "html",
+    "htm",
+    "css",
+    "scss",
+    "js",
+    "mjs",
+    "cjs",
+    "ts",
+    "tsx",
+    "jsx",
+    "vue",
+    "svelte",
+    "rs",
+    "go",
+    "py",
+    "rb",
+    "php",
+    "java",
+    "kt",
+    "kts",
+    "scala",
+    "swift",
+    "c",
+    "h",
+    "cc",
+    "cpp",
+    "hpp",
+    "cs",
+    "fs",
+    "dart",
+    "zig",
+    "ex",
+    "exs",
+    "erl",
+    "hs",
+    "lua",
+    "pl",
+    "r",
+    "sh",
+    "bash",
+    "zsh",
+    "fish",
+    "ps1",
+    "bat",
+    "sql",
+    "graphql",
+    "proto",
+    "tf",
+    "nix",
+    "dockerfile",
+    "makefile",
+];
+
+/// Whether an object is text, by its type or, for generic types, its file name
+pub fn is_text(content_type: &str, file_name: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    if essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || TEXT_TYPES.contains(&essence.as_str())
+    {
+        return true;
+    }
+    if !GENERIC_TYPES.contains(&essence.as_str()) {
+        return false;
+    }
+
+    // `Dockerfile` and `Makefile` have no extension
+    let extension = match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension,
+        None => file_name,
+    };
+    TEXT_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+}
+
+/// Decodes text in whatever charset it was saved in, `None` if it's binary
+/// after all. `complete` is false when `content` is only the start of a file.
+pub fn decode(content: &[u8], complete: bool) -> Option<String> {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(content) {
+        let (text, _) = encoding.decode_without_bom_handling(&content[bom_length..]);
+        return Some(text.into_owned());
+    }
+    // text without a UTF-16 BOM never contains NUL
+    if content.contains(&0) {
+        return None;
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(content, complete);
+    let encoding = detector.guess(None, true);
+    // a character cut in half at the end becomes a replacement character
+    let (text, _) = encoding.decode_without_bom_handling(content);
+    Some(text.into_owned())
+}